@@ -0,0 +1,135 @@
+use ansify::{ANSIfier, Texel};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::QueueableCommand;
+use std::ffi::OsString;
+use std::io::Write;
+
+// The 16 console colors in palette index order with their usual RGB values
+const CONSOLE_COLORS: [(Color, [u8; 3]); 16] = [
+    (Color::Black, [0, 0, 0]),
+    (Color::DarkRed, [128, 0, 0]),
+    (Color::DarkGreen, [0, 128, 0]),
+    (Color::DarkYellow, [128, 128, 0]),
+    (Color::DarkBlue, [0, 0, 128]),
+    (Color::DarkMagenta, [128, 0, 128]),
+    (Color::DarkCyan, [0, 128, 128]),
+    (Color::Grey, [192, 192, 192]),
+    (Color::DarkGrey, [128, 128, 128]),
+    (Color::Red, [255, 0, 0]),
+    (Color::Green, [0, 255, 0]),
+    (Color::Yellow, [255, 255, 0]),
+    (Color::Blue, [0, 0, 255]),
+    (Color::Magenta, [255, 0, 255]),
+    (Color::Cyan, [0, 255, 255]),
+    (Color::White, [255, 255, 255]),
+];
+
+fn xterm_rgb(index: u8) -> [u8; 3] {
+    return match index {
+        0..=15 => CONSOLE_COLORS[index as usize].1,
+        16..=231 => {
+            let level = |value: u8| if value == 0 { 0 } else { 55 + value * 40 };
+            let cube = index - 16;
+            [level(cube / 36), level(cube / 6 % 6), level(cube % 6)]
+        }
+        _ => {
+            let gray = 8 + (index - 232) * 10;
+            [gray, gray, gray]
+        }
+    };
+}
+
+// Legacy consoles only have 16 attribute colors, so the rest of the 256 color
+// palette falls back to the closest of those.
+pub fn console_color(index: u8) -> Color {
+    let [r, g, b] = xterm_rgb(index);
+
+    return CONSOLE_COLORS
+        .iter()
+        .min_by_key(|(_, [cr, cg, cb])| {
+            let dr = r as i32 - *cr as i32;
+            let dg = g as i32 - *cg as i32;
+            let db = b as i32 - *cb as i32;
+            dr * dr + dg * dg + db * db
+        })
+        .unwrap()
+        .0;
+}
+
+#[cfg(windows)]
+fn is_legacy_console() -> bool {
+    return !crossterm::ansi_support::supports_ansi();
+}
+
+#[cfg(not(windows))]
+fn is_legacy_console() -> bool {
+    return false;
+}
+
+pub fn color_enabled(no_color: bool) -> bool {
+    return color_enabled_with(no_color, std::env::var_os("NO_COLOR"));
+}
+
+// Follows https://no-color.org: any non-empty NO_COLOR value turns color off
+fn color_enabled_with(no_color: bool, env: Option<OsString>) -> bool {
+    let no_color_env = env.is_some_and(|value| !value.is_empty());
+    return !no_color && !no_color_env;
+}
+
+pub fn print_grid(ansifier: &ANSIfier, grid: &[Vec<Texel>], color: bool) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+
+    if !color {
+        write!(stdout, "{}", ansifier.grid_plain(grid))?;
+        return stdout.flush();
+    }
+
+    if !is_legacy_console() {
+        write!(stdout, "{}", ansifier.grid_text(grid))?;
+        return stdout.flush();
+    }
+
+    // crossterm sets console attributes through the Windows Console API when the
+    // console can't interpret escape sequences
+    for row in grid {
+        for texel in row {
+            stdout
+                .queue(SetForegroundColor(console_color(texel.foreground_color)))?
+                .queue(SetBackgroundColor(console_color(texel.background_color)))?
+                .queue(Print(ansifier.texel_char(texel)))?;
+        }
+        stdout.queue(ResetColor)?.queue(Print('\n'))?;
+    }
+
+    return stdout.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_color_env_disables_color() {
+        assert!(color_enabled_with(false, None));
+        assert!(!color_enabled_with(true, None));
+
+        assert!(!color_enabled_with(false, Some(OsString::from("1"))));
+        assert!(color_enabled_with(false, Some(OsString::new())));
+        assert!(!color_enabled_with(true, Some(OsString::new())));
+    }
+
+    #[test]
+    fn console_color_maps_palette_indices() {
+        assert_eq!(console_color(0), Color::Black);
+        assert_eq!(console_color(1), Color::DarkRed);
+        assert_eq!(console_color(7), Color::Grey);
+        assert_eq!(console_color(9), Color::Red);
+        assert_eq!(console_color(15), Color::White);
+
+        assert_eq!(console_color(16), Color::Black);
+        assert_eq!(console_color(196), Color::Red);
+        assert_eq!(console_color(21), Color::Blue);
+        assert_eq!(console_color(231), Color::White);
+        assert_eq!(console_color(244), Color::DarkGrey);
+    }
+}
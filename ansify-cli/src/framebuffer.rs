@@ -0,0 +1,185 @@
+use image::RgbImage;
+use log::info;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb565,
+    Bgr888,
+    Bgrx8888,
+}
+
+impl PixelFormat {
+    pub fn from_bits_per_pixel(bits_per_pixel: u32) -> Option<PixelFormat> {
+        return match bits_per_pixel {
+            16 => Some(PixelFormat::Rgb565),
+            24 => Some(PixelFormat::Bgr888),
+            32 => Some(PixelFormat::Bgrx8888),
+            _ => None,
+        };
+    }
+
+    pub fn bytes_per_pixel(&self) -> usize {
+        return match self {
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Bgr888 => 3,
+            PixelFormat::Bgrx8888 => 4,
+        };
+    }
+
+    pub fn encode(&self, rgb: [u8; 3], out: &mut [u8]) {
+        let [r, g, b] = rgb;
+        match self {
+            PixelFormat::Rgb565 => {
+                let packed = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+                out[..2].copy_from_slice(&packed.to_le_bytes());
+            }
+            PixelFormat::Bgr888 => out[..3].copy_from_slice(&[b, g, r]),
+            PixelFormat::Bgrx8888 => out[..4].copy_from_slice(&[b, g, r, 0xFF]),
+        }
+    }
+}
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub stride: usize,
+    pub format: PixelFormat,
+}
+
+fn read_sysfs(device: &str, attribute: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let path = format!("/sys/class/graphics/{}/{}", device, attribute);
+    return Ok(fs::read_to_string(path)?.trim().to_string());
+}
+
+impl Framebuffer {
+    pub fn query(path: &Path) -> Result<Framebuffer, Box<dyn std::error::Error>> {
+        info!("Querying framebuffer format");
+
+        let device = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("Invalid framebuffer path")?;
+
+        let size = read_sysfs(device, "virtual_size")?;
+        let (width, height) = size
+            .split_once(',')
+            .ok_or("Malformed framebuffer virtual_size")?;
+        let bits_per_pixel: u32 = read_sysfs(device, "bits_per_pixel")?.parse()?;
+        let format = PixelFormat::from_bits_per_pixel(bits_per_pixel)
+            .ok_or_else(|| format!("Unsupported framebuffer depth: {} bpp", bits_per_pixel))?;
+        let width: u32 = width.parse()?;
+        let stride = match read_sysfs(device, "stride") {
+            Ok(stride) => stride.parse()?,
+            Err(_) => width as usize * format.bytes_per_pixel(),
+        };
+
+        return Ok(Framebuffer {
+            width,
+            height: height.parse()?,
+            stride,
+            format,
+        });
+    }
+
+    pub fn render(&self, img: &RgbImage) -> Vec<u8> {
+        let mut buffer = vec![0u8; self.stride * self.height as usize];
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+
+        // Center the image, cropping whatever does not fit on screen
+        let offset_x = (self.width as i64 - img.width() as i64) / 2;
+        let offset_y = (self.height as i64 - img.height() as i64) / 2;
+
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let screen_x = x as i64 + offset_x;
+            let screen_y = y as i64 + offset_y;
+            if screen_x < 0
+                || screen_y < 0
+                || screen_x >= self.width as i64
+                || screen_y >= self.height as i64
+            {
+                continue;
+            }
+
+            let start = screen_y as usize * self.stride + screen_x as usize * bytes_per_pixel;
+            self.format
+                .encode(pixel.0, &mut buffer[start..start + bytes_per_pixel]);
+        }
+
+        return buffer;
+    }
+
+    pub fn blit(&self, path: &Path, img: &RgbImage) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Writing to framebuffer");
+
+        let mut device = OpenOptions::new().write(true).open(path)?;
+        device.write_all(&self.render(img))?;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn encoded(format: PixelFormat, rgb: [u8; 3]) -> Vec<u8> {
+        let mut out = vec![0u8; format.bytes_per_pixel()];
+        format.encode(rgb, &mut out);
+        return out;
+    }
+
+    #[test]
+    fn rgb565_packs_the_high_bits() {
+        assert_eq!(encoded(PixelFormat::Rgb565, [0xFF, 0, 0]), vec![0x00, 0xF8]);
+        assert_eq!(encoded(PixelFormat::Rgb565, [0, 0xFF, 0]), vec![0xE0, 0x07]);
+        assert_eq!(encoded(PixelFormat::Rgb565, [0, 0, 0xFF]), vec![0x1F, 0x00]);
+        // The low bits of each channel are dropped
+        assert_eq!(
+            encoded(PixelFormat::Rgb565, [0x87, 0x43, 0x27]),
+            encoded(PixelFormat::Rgb565, [0x80, 0x40, 0x20])
+        );
+    }
+
+    #[test]
+    fn bgr_formats_swap_red_and_blue() {
+        assert_eq!(encoded(PixelFormat::Bgr888, [1, 2, 3]), vec![3, 2, 1]);
+        assert_eq!(
+            encoded(PixelFormat::Bgrx8888, [1, 2, 3]),
+            vec![3, 2, 1, 0xFF]
+        );
+    }
+
+    #[test]
+    fn render_centers_and_crops() {
+        // Rows are padded past the visible width
+        let framebuffer = Framebuffer {
+            width: 4,
+            height: 2,
+            stride: 16,
+            format: PixelFormat::Bgr888,
+        };
+
+        // Narrower than the screen, so centered horizontally, and taller, so the
+        // middle rows are kept
+        let img = RgbImage::from_fn(2, 4, |x, y| Rgb([x as u8 + 1, y as u8 + 1, 9]));
+        let buffer = framebuffer.render(&img);
+        assert_eq!(buffer.len(), 32);
+
+        let pixel = |x: usize, y: usize| buffer[y * 16 + x * 3..y * 16 + x * 3 + 3].to_vec();
+        assert_eq!(pixel(0, 0), vec![0, 0, 0]);
+        assert_eq!(pixel(1, 0), vec![9, 2, 1]);
+        assert_eq!(pixel(2, 0), vec![9, 2, 2]);
+        assert_eq!(pixel(3, 0), vec![0, 0, 0]);
+        assert_eq!(pixel(1, 1), vec![9, 3, 1]);
+        assert_eq!(pixel(2, 1), vec![9, 3, 2]);
+
+        // Nothing is written to the padding
+        assert!(buffer[12..16]
+            .iter()
+            .chain(&buffer[28..32])
+            .all(|&byte| byte == 0));
+    }
+}
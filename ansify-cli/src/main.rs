@@ -1,16 +1,30 @@
-use ansify::{ANSIfier, Blocks, Palette};
-use clap::{Parser, Subcommand};
-use core::time::Duration;
+mod console;
+mod framebuffer;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "watch")]
+mod watch;
+#[cfg(feature = "webcam")]
+mod webcam;
+
+use ansify::{
+    composite_over, fit_width_to_bytes, repeat_to, split_alpha, split_tiles, tint, white_balance,
+    write_png_with_text, ANSIfier, ANSIfierOptions, Blocks, DistanceMetric, Dither, Gamma,
+    MircColors, OutOfGamut, Palette, PalettePreset, Precision, RenderOptions, TestPattern, Texel,
+    TextOptions,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use framebuffer::Framebuffer;
 use image::gif::{GifDecoder, GifEncoder, Repeat};
 use image::io::Reader as ImageReader;
-use image::RgbaImage;
-use image::{AnimationDecoder, Delay, DynamicImage, Frame, GenericImageView};
+use image::{AnimationDecoder, DynamicImage, Frame, GenericImageView, ImageResult};
+use image::{GrayImage, RgbImage};
 use log::info;
-use nokhwa::Camera;
 use show_image::create_window;
 use show_image::WindowOptions;
+use std::borrow::Cow;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 #[derive(Parser)]
@@ -19,17 +33,546 @@ struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    #[arg(short, long, value_name = "PALETTE_PATH")]
-    palette: PathBuf,
+    #[arg(
+        short,
+        long,
+        value_name = "PALETTE_PATH",
+        required_unless_present_any = ["palette_from", "palette_preset"]
+    )]
+    palette: Option<PathBuf>,
+
+    #[arg(long, value_name = "IMAGE_PATH", conflicts_with = "palette")]
+    palette_from: Option<PathBuf>,
+
+    #[arg(long, value_enum, conflicts_with_all = ["palette", "palette_from"])]
+    palette_preset: Option<Preset>,
+
+    #[arg(long, value_name = "COLORS", value_parser = parse_palette_size, default_value_t = 16)]
+    palette_size: usize,
+
+    #[arg(long, value_name = "SEED", default_value_t = 0)]
+    seed: u64,
 
-    #[arg(short, long, value_name = "BLOCKS_PATH")]
-    blocks: PathBuf,
+    #[arg(
+        short,
+        long,
+        value_name = "BLOCKS_PATH",
+        required_unless_present_any = ["block_elements", "half_blocks"]
+    )]
+    blocks: Option<PathBuf>,
+
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_cells, conflicts_with = "blocks")]
+    block_elements: Option<(u32, u32)>,
+
+    #[arg(long, conflicts_with_all = ["blocks", "block_elements"])]
+    half_blocks: bool,
 
     #[arg(short, long, value_name = "WIDTH")]
     width: Option<u32>,
 
     #[arg(short = 'H', long, value_name = "HEIGHT")]
     height: Option<u32>,
+
+    #[arg(long, value_name = "COLSxROWS", value_parser = parse_cells, conflicts_with_all = ["width", "height"])]
+    fit_terminal_cells: Option<(u32, u32)>,
+
+    #[arg(long)]
+    output_dimensions: bool,
+
+    #[arg(long, value_name = "CHAR", num_args = 0..=1, default_missing_value = " ")]
+    empty_char: Option<char>,
+
+    #[arg(long, value_name = "FROM=TO", value_parser = parse_substitution, value_delimiter = ',')]
+    substitute: Vec<(char, char)>,
+
+    #[arg(long)]
+    final_reset: bool,
+
+    #[arg(long)]
+    no_trailing_newline: bool,
+
+    #[arg(long, value_name = "PIXELS", default_value_t = 0)]
+    row_gap: u32,
+
+    // Softens glyph edges against transparent cells when compositing with
+    // --background-image
+    #[arg(long)]
+    aa_edges: bool,
+
+    #[arg(long, value_name = "KELVIN")]
+    white_balance: Option<f32>,
+
+    #[arg(long, value_name = "R,G,B", value_delimiter = ',', num_args = 3)]
+    tint: Option<Vec<f32>>,
+
+    #[arg(long, value_enum, default_value_t = MatchMode::Color)]
+    match_mode: MatchMode,
+
+    #[arg(long, value_name = "COLORS")]
+    posterize: Option<usize>,
+
+    #[arg(long, value_name = "PIXELS")]
+    max_output_pixels: Option<u64>,
+
+    #[arg(long)]
+    no_color: bool,
+
+    #[arg(long)]
+    unicode_shading: bool,
+
+    // Palette indices preferred, first to last, when glyphs blend to the same color
+    #[arg(long, value_name = "INDICES", value_delimiter = ',')]
+    color_priority: Vec<u8>,
+
+    #[arg(long, value_name = "RESOLUTION")]
+    lut_cache: Option<u32>,
+
+    // Matches through the same LUT the OBS filter samples, to reproduce its output
+    #[arg(long, conflicts_with = "lut_cache")]
+    use_lut: bool,
+
+    #[arg(long, value_enum, default_value_t = Gamut::Nearest)]
+    out_of_gamut: Gamut,
+
+    #[arg(long, value_name = "DISTANCE", default_value_t = 0.1)]
+    gamut_tolerance: f32,
+
+    #[arg(long, value_enum, default_value_t = DitherMode::None)]
+    dither: DitherMode,
+
+    // How much of each cell's error Floyd-Steinberg dithering carries on
+    #[arg(long, value_name = "STRENGTH", default_value_t = 1.0)]
+    dither_strength: f32,
+
+    // Bits kept per channel when matching, e.g. 5,6,5 for RGB565
+    #[arg(long, value_name = "R,G,B", value_parser = parse_quantize)]
+    quantize: Option<[u8; 3]>,
+
+    #[arg(long, value_enum, default_value_t = TreePrecision::F32)]
+    precision: TreePrecision,
+
+    #[arg(long, value_enum, default_value_t = Metric::Euclidean)]
+    metric: Metric,
+
+    // Either `srgb` or a power-law exponent such as 2.2, or 1.0 to blend the raw values
+    #[arg(long, value_parser = parse_gamma, default_value = "1.0")]
+    gamma: Gamma,
+}
+
+impl Cli {
+    fn ansifier(&self) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        let palette = if let Some(preset) = self.palette_preset {
+            Palette::preset(preset.into())
+        } else if let Some(palette_image_path) = &self.palette_from {
+            info!("Opening palette image");
+            let palette_image = ImageReader::open(palette_image_path)?.decode()?.into_rgb8();
+            Palette::from_image(&palette_image, self.palette_size, self.seed)
+        } else {
+            Palette::from(self.palette.clone().unwrap())?
+        };
+        let blocks = if let Some((width, height)) = self.block_elements {
+            Blocks::unicode_block_elements(width, height)?
+        } else if self.half_blocks {
+            Blocks::half_blocks()
+        } else {
+            Blocks::from(self.blocks.clone().unwrap())?
+        };
+        let mut ansifier = ANSIfier::with_options(
+            palette,
+            blocks,
+            ANSIfierOptions {
+                gamma: self.gamma,
+                precision: self.precision.into(),
+                metric: self.metric.into(),
+            },
+        )?;
+        if self.unicode_shading {
+            ansifier = ansifier.rebuild_with_unicode_shading(true);
+        }
+        if !self.color_priority.is_empty() {
+            ansifier = ansifier.rebuild_with_color_priority(self.color_priority.clone());
+        }
+        ansifier.set_empty_char(self.empty_char);
+        ansifier.set_substitutions(self.substitute.iter().copied().collect());
+        ansifier.set_text_options(TextOptions {
+            final_reset: self.final_reset,
+            trailing_newline: !self.no_trailing_newline,
+        });
+        ansifier.set_render_options(RenderOptions {
+            row_gap: self.row_gap,
+            aa_edges: self.aa_edges,
+        });
+        ansifier.set_out_of_gamut(self.out_of_gamut.into(), self.gamut_tolerance);
+        ansifier.set_dither(self.dither());
+        if let Some(bits) = self.quantize {
+            ansifier.set_quantize(bits);
+        }
+        if let Some(resolution) = self.lut_cache {
+            ansifier = ansifier.with_lut_cache(resolution);
+        }
+        if self.use_lut {
+            ansifier = ansifier.with_shader_lut()?;
+        }
+
+        return Ok(ansifier);
+    }
+
+    fn dither(&self) -> Dither {
+        return match self.dither {
+            DitherMode::None => Dither::None,
+            DitherMode::BlueNoise => Dither::BlueNoise,
+            DitherMode::FloydSteinberg => Dither::FloydSteinberg {
+                strength: self.dither_strength,
+            },
+        };
+    }
+
+    fn desired_dimensions(&self) -> (Option<u32>, Option<u32>) {
+        return match self.fit_terminal_cells {
+            Some((cols, rows)) => (Some(cols), Some(rows)),
+            None => (self.width, self.height),
+        };
+    }
+
+    fn output_metadata(
+        &self,
+        input: &Path,
+        source_dimensions: (u32, u32),
+    ) -> Vec<(String, String)> {
+        let palette = if let Some(preset) = self.palette_preset {
+            format!("preset {}", preset.to_possible_value().unwrap().get_name())
+        } else if let Some(palette_image_path) = &self.palette_from {
+            format!("from image {}", palette_image_path.display())
+        } else {
+            self.palette.as_ref().unwrap().display().to_string()
+        };
+        let blocks = match (&self.blocks, self.block_elements) {
+            (Some(blocks_path), _) => blocks_path.display().to_string(),
+            (None, Some((width, height))) => format!("block elements {}x{}", width, height),
+            (None, None) if self.half_blocks => String::from("half blocks"),
+            (None, None) => String::new(),
+        };
+        let file_name = input
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+
+        return vec![
+            (String::from("Source"), file_name),
+            (
+                String::from("Source Dimensions"),
+                format!("{}x{}", source_dimensions.0, source_dimensions.1),
+            ),
+            (String::from("Palette"), palette),
+            (String::from("Blocks"), blocks),
+            (
+                String::from("Software"),
+                format!("ansify {}", env!("CARGO_PKG_VERSION")),
+            ),
+        ];
+    }
+
+    fn adjust(&self, img: &mut RgbImage) {
+        if let Some(kelvin) = self.white_balance {
+            white_balance(img, kelvin);
+        }
+
+        if let Some(scale) = &self.tint {
+            tint(img, [scale[0], scale[1], scale[2]]);
+        }
+    }
+
+    fn resize(&self, original_image: &DynamicImage, dimensions: (u32, u32)) -> RgbImage {
+        let mut img = original_image
+            .resize_exact(dimensions.0, dimensions.1, image::imageops::Lanczos3)
+            .into_rgb8();
+        self.adjust(&mut img);
+        return img;
+    }
+
+    fn ansify(
+        &self,
+        ansifier: &ANSIfier,
+        original_image: &DynamicImage,
+        dimensions: (u32, u32),
+        importance_mask: Option<&GrayImage>,
+    ) -> Vec<Vec<Texel>> {
+        let mut grid = match (self.match_mode, importance_mask) {
+            (MatchMode::Color, Some(mask)) => {
+                ansifier.match_grid_with_mask(&self.resize(original_image, dimensions), mask)
+            }
+            (MatchMode::Color, None) => {
+                ansifier.match_grid(&self.resize(original_image, dimensions))
+            }
+            (MatchMode::Structural, _) => ansifier.match_grid_structural(&self.resize(
+                original_image,
+                (
+                    dimensions.0 * ansifier.block_width(),
+                    dimensions.1 * ansifier.block_height(),
+                ),
+            )),
+        };
+
+        if let Some(colors) = self.posterize {
+            ansifier.posterize(&mut grid, colors);
+        }
+
+        return grid;
+    }
+
+    fn check_dimensions(
+        &self,
+        ansifier: &ANSIfier,
+        dimensions: (u32, u32),
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(max_output_pixels) = self.max_output_pixels {
+            ansifier.check_output_pixels(dimensions, max_output_pixels)?;
+        }
+
+        return Ok(());
+    }
+
+    fn report_dimensions(&self, ansifier: &ANSIfier, dimensions: (u32, u32)) {
+        if self.output_dimensions {
+            let pixels = ansifier.output_dimensions(dimensions);
+            eprintln!(
+                "Output: {}x{} cells, {}x{} pixels",
+                dimensions.0, dimensions.1, pixels.0, pixels.1,
+            );
+        }
+    }
+}
+
+fn parse_cells(s: &str) -> Result<(u32, u32), String> {
+    let (cols, rows) = s
+        .split_once('x')
+        .ok_or_else(|| format!("expected COLSxROWS, got `{}`", s))?;
+    let cols = cols.parse::<u32>().map_err(|e| e.to_string())?;
+    let rows = rows.parse::<u32>().map_err(|e| e.to_string())?;
+    if cols == 0 || rows == 0 {
+        return Err(String::from("cell grid must be at least 1x1"));
+    }
+    return Ok((cols, rows));
+}
+
+// Metadata is written as PNG text chunks, so it needs a PNG output
+fn save_output(
+    img: &RgbImage,
+    path: &Path,
+    metadata: &Option<Vec<(String, String)>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return Ok(img.save(path)?),
+    };
+
+    let is_png = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+    if !is_png {
+        return Err(format!(
+            "--embed-metadata needs a .png output, got {}",
+            path.display()
+        )
+        .into());
+    }
+
+    let file = std::io::BufWriter::new(File::create(path)?);
+    return write_png_with_text(img, file, metadata);
+}
+
+// The text --max-bytes measures, without escape codes when color is off
+fn budget_text(ansifier: &ANSIfier, grid: &[Vec<Texel>], color: bool) -> String {
+    if color {
+        return ansifier.grid_text_minimal(grid);
+    }
+
+    return ansifier.grid_plain(grid);
+}
+
+// out.png becomes out_r0_c0.png, out_r0_c1.png, and so on
+fn tile_path(output_path: &Path, row: usize, column: usize) -> PathBuf {
+    let stem = output_path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    let mut name = format!("{}_r{}_c{}", stem, row, column);
+    if let Some(extension) = output_path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    return output_path.with_file_name(name);
+}
+
+fn parse_substitution(s: &str) -> Result<(char, char), String> {
+    let (from, to) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected FROM=TO, got `{}`", s))?;
+    let single = |part: &str| {
+        let mut chars = part.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(c),
+            _ => Err(format!("expected a single character, got `{}`", part)),
+        }
+    };
+    return Ok((single(from)?, single(to)?));
+}
+
+fn parse_quantize(s: &str) -> Result<[u8; 3], String> {
+    let bits = s
+        .split(',')
+        .map(|bits| match bits.trim().parse::<u8>() {
+            Ok(bits @ 1..=8) => Ok(bits),
+            _ => Err(format!("`{}` is not a bit count from 1 to 8", bits)),
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    return bits
+        .try_into()
+        .map_err(|_| format!("expected three comma-separated bit counts, got `{}`", s));
+}
+
+fn parse_palette_size(s: &str) -> Result<usize, String> {
+    return match s.parse::<usize>() {
+        Ok(0) => Err(String::from("palette must have at least one color")),
+        Ok(size) => Ok(size),
+        Err(_) => Err(format!("`{}` is not a color count", s)),
+    };
+}
+
+fn parse_fps(s: &str) -> Result<f32, String> {
+    let fps = s
+        .parse::<f32>()
+        .map_err(|_| format!("`{}` is not a number", s))?;
+    if !(fps > 0.0 && fps.is_finite()) {
+        return Err(String::from("frame rate must be a positive number"));
+    }
+    return Ok(fps);
+}
+
+fn parse_gamma(s: &str) -> Result<Gamma, String> {
+    if s.eq_ignore_ascii_case("srgb") {
+        return Ok(Gamma::Srgb);
+    }
+    let gamma = s
+        .parse::<f32>()
+        .map_err(|_| format!("expected `srgb` or a number, got `{}`", s))?;
+    if !(gamma > 0.0 && gamma.is_finite()) {
+        return Err(String::from("gamma must be a positive number"));
+    }
+    return Ok(Gamma::Power(gamma));
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MatchMode {
+    Color,
+    Structural,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum TreePrecision {
+    F32,
+    F64,
+}
+
+impl From<TreePrecision> for Precision {
+    fn from(precision: TreePrecision) -> Precision {
+        return match precision {
+            TreePrecision::F32 => Precision::F32,
+            TreePrecision::F64 => Precision::F64,
+        };
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Metric {
+    Euclidean,
+    Lab,
+    Ciede2000,
+    WeightedRgb,
+}
+
+impl From<Metric> for DistanceMetric {
+    fn from(metric: Metric) -> DistanceMetric {
+        return match metric {
+            Metric::Euclidean => DistanceMetric::Euclidean,
+            Metric::Lab => DistanceMetric::Lab,
+            Metric::Ciede2000 => DistanceMetric::CIEDE2000,
+            Metric::WeightedRgb => DistanceMetric::WeightedRgb,
+        };
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DitherMode {
+    None,
+    BlueNoise,
+    FloydSteinberg,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Gamut {
+    Nearest,
+    Desaturate,
+}
+
+impl From<Gamut> for OutOfGamut {
+    fn from(gamut: Gamut) -> OutOfGamut {
+        return match gamut {
+            Gamut::Nearest => OutOfGamut::Nearest,
+            Gamut::Desaturate => OutOfGamut::Desaturate,
+        };
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Preset {
+    Pico8,
+    Nes,
+    GameBoy,
+    Cga,
+    Vga,
+    Xterm256,
+    C64,
+}
+
+impl From<Preset> for PalettePreset {
+    fn from(preset: Preset) -> PalettePreset {
+        return match preset {
+            Preset::Pico8 => PalettePreset::Pico8,
+            Preset::Nes => PalettePreset::Nes,
+            Preset::GameBoy => PalettePreset::GameBoy,
+            Preset::Cga => PalettePreset::Cga,
+            Preset::Vga => PalettePreset::Vga,
+            Preset::Xterm256 => PalettePreset::Xterm256,
+            Preset::C64 => PalettePreset::C64,
+        };
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Emit {
+    Rust,
+    Html,
+    HtmlPage,
+    Mirc,
+    MircExtended,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Pattern {
+    ColorRamp,
+    GrayscaleRamp,
+    HueWheel,
+    Checkerboard,
+}
+
+impl From<Pattern> for TestPattern {
+    fn from(pattern: Pattern) -> TestPattern {
+        return match pattern {
+            Pattern::ColorRamp => TestPattern::ColorRamp,
+            Pattern::GrayscaleRamp => TestPattern::GrayscaleRamp,
+            Pattern::HueWheel => TestPattern::HueWheel,
+            Pattern::Checkerboard => TestPattern::Checkerboard,
+        };
+    }
 }
 
 #[derive(Subcommand)]
@@ -46,6 +589,70 @@ enum Commands {
 
         #[arg(short, long)]
         show: bool,
+
+        #[arg(long, value_name = "CSV_PATH")]
+        csv: Option<PathBuf>,
+
+        #[arg(long, value_enum, alias = "format")]
+        emit: Option<Emit>,
+
+        #[arg(long, value_name = "DEVICE_PATH")]
+        framebuffer: Option<PathBuf>,
+
+        #[arg(long, value_name = "CONFIDENCE_PATH")]
+        confidence: Option<PathBuf>,
+
+        #[arg(long, value_name = "BACKGROUND_PATH")]
+        background_image: Option<PathBuf>,
+
+        #[arg(long, value_name = "THRESHOLD", requires = "background_image")]
+        alpha_threshold: Option<f32>,
+
+        #[arg(long, value_name = "LOG_PATH")]
+        match_log: Option<PathBuf>,
+
+        #[arg(long, value_name = "INDICES_PATH")]
+        indices: Option<PathBuf>,
+
+        #[arg(long, value_name = "THUMBNAIL_PATH")]
+        thumbnail: Option<PathBuf>,
+
+        #[arg(long, value_name = "COVERAGE_PATH")]
+        palette_coverage: Option<PathBuf>,
+
+        #[arg(long, value_name = "MASK_PATH")]
+        importance_mask: Option<PathBuf>,
+
+        #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_cells, requires = "output")]
+        tile: Option<(u32, u32)>,
+
+        #[arg(long, requires = "output")]
+        embed_metadata: bool,
+
+        #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_cells)]
+        tile_to: Option<(u32, u32)>,
+
+        // Narrows the output until the text, with as few escape codes as possible,
+        // fits in this many bytes
+        #[arg(
+            long,
+            value_name = "BYTES",
+            requires = "text",
+            conflicts_with = "tile_to"
+        )]
+        max_bytes: Option<usize>,
+
+        #[cfg(feature = "watch")]
+        #[arg(long)]
+        watch: bool,
+
+        // Prints sizes, match error, color usage, and timing as one JSON object
+        #[arg(long)]
+        stats_json: bool,
+
+        #[cfg(feature = "tui")]
+        #[arg(long)]
+        interactive: bool,
     },
     Gif {
         #[arg(short, long, value_name = "INPUT_PATH")]
@@ -53,25 +660,122 @@ enum Commands {
 
         #[arg(short, long, value_name = "OUTPUT_PATH")]
         output: PathBuf,
+
+        #[arg(long)]
+        per_frame_palette: bool,
+
+        #[arg(long, value_name = "DISTANCE", requires = "per_frame_palette")]
+        palette_drift: Option<f32>,
+
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        frame_step: usize,
     },
+    Lut {
+        #[arg(long, value_name = "LUT_PATH", required_unless_present = "dry_run")]
+        lut: Option<PathBuf>,
+
+        #[arg(long, value_name = "MAP_PATH", required_unless_present = "dry_run")]
+        map: Option<PathBuf>,
+
+        #[arg(long)]
+        dry_run: bool,
+    },
+    TestPattern {
+        #[arg(short, long, value_enum)]
+        kind: Pattern,
+
+        #[arg(short, long, value_name = "OUTPUT_PATH")]
+        output: Option<PathBuf>,
+
+        #[arg(short, long)]
+        text: bool,
+    },
+    #[cfg(feature = "webcam")]
     Webcam {
         #[arg(short, long)]
         index: usize,
 
         #[arg(short, long, value_name = "OUTPUT_PATH")]
         output: Option<PathBuf>,
+
+        // Lowers the detail while processing can't keep up with this frame rate
+        #[arg(long, value_name = "FPS", value_parser = parse_fps)]
+        target_fps: Option<f32>,
     },
 }
 
+// Frames are pulled, processed, and handed to write one at a time, so only a
+// single frame's buffers are alive at any point regardless of GIF size.
+fn ansify_frames<I, F>(
+    cli: &Cli,
+    mut ansifier: ANSIfier,
+    frames: I,
+    per_frame_palette: bool,
+    palette_drift: Option<f32>,
+    mut write: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    I: Iterator<Item = ImageResult<Frame>>,
+    F: FnMut(Frame) -> ImageResult<()>,
+{
+    let mut reported_dimensions = false;
+    let mut previous_palette: Option<Palette> = None;
+
+    for frame in frames {
+        let frame = frame?;
+        info!("Calculating dimension and resizing");
+        let left = frame.left();
+        let top = frame.top();
+        let delay = frame.delay();
+        let original_image = DynamicImage::ImageRgba8(frame.into_buffer());
+        let original_dimensions = original_image.dimensions();
+
+        if per_frame_palette {
+            info!("Deriving frame palette");
+            let frame = original_image.to_rgb8();
+            let palette = match (palette_drift, &previous_palette) {
+                (Some(max_drift), Some(previous)) => {
+                    Palette::from_image_stabilized(&frame, previous, max_drift)
+                }
+                _ => Palette::from_image(&frame, cli.palette_size, cli.seed),
+            };
+            previous_palette = Some(palette.clone());
+            ansifier = ansifier.rebuild_with_palette(palette)?;
+        }
+
+        let new_dimensions =
+            ansifier.calculate_new_dimensions(original_dimensions, cli.desired_dimensions());
+        if !reported_dimensions {
+            cli.report_dimensions(&ansifier, new_dimensions);
+            reported_dimensions = true;
+        }
+        cli.check_dimensions(&ansifier, new_dimensions)?;
+        let grid = cli.ansify(&ansifier, &original_image, new_dimensions, None);
+        drop(original_image);
+
+        let out = ansifier.render_grid(&grid);
+
+        let left = (left as f32 / original_dimensions.0 as f32 * new_dimensions.0 as f32) as u32;
+        let top = (top as f32 / original_dimensions.1 as f32 * new_dimensions.1 as f32) as u32;
+
+        write(Frame::from_parts(
+            DynamicImage::ImageRgb8(out).into_rgba8(),
+            left,
+            top,
+            delay,
+        ))?;
+    }
+
+    return Ok(());
+}
+
 #[show_image::main]
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let cli = Cli::parse();
 
     env_logger::init();
 
-    let palette = Palette::from(cli.palette)?;
-    let blocks = Blocks::from(cli.blocks)?;
-    let ansifier = ANSIfier::new(palette, blocks);
+    let ansifier = cli.ansifier()?;
 
     match &cli.command {
         Commands::Image {
@@ -79,48 +783,284 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             output,
             text,
             show,
+            csv,
+            emit,
+            framebuffer,
+            confidence,
+            background_image,
+            alpha_threshold,
+            match_log,
+            indices,
+            thumbnail,
+            palette_coverage,
+            importance_mask,
+            tile,
+            embed_metadata,
+            tile_to,
+            max_bytes,
+            #[cfg(feature = "watch")]
+            watch,
+            stats_json,
+            #[cfg(feature = "tui")]
+            interactive,
         } => {
             info!("Opening original image");
             let original_image = ImageReader::open(input)?.decode()?;
 
-            info!("Calculating dimension and resizing");
-            let new_dimensions = ansifier
-                .calculate_new_dimensions(original_image.dimensions(), (cli.width, cli.height));
-            let img = original_image
-                .resize_exact(
-                    new_dimensions.0,
-                    new_dimensions.1,
-                    image::imageops::Lanczos3,
-                )
-                .into_rgb8();
+            #[cfg(feature = "tui")]
+            if *interactive {
+                return tui::run(&cli, ansifier, &original_image);
+            }
 
-            let (out, out_text) = ansifier.process(&img);
+            // Each change to a watched file replaces the ANSIfier
+            #[cfg(feature = "watch")]
+            let mut ansifier = ansifier;
+            #[cfg(feature = "watch")]
+            let mut watcher = if *watch {
+                Some(watch::Watcher::new(&cli)?)
+            } else {
+                None
+            };
+            let mut window = None;
 
-            if *text {
-                print!("{}", out_text);
-            }
+            // Only a watcher sends the loop around again
+            #[cfg_attr(not(feature = "watch"), allow(clippy::never_loop))]
+            loop {
+                info!("Calculating dimension and resizing");
+                let new_dimensions = ansifier.calculate_new_dimensions(
+                    original_image.dimensions(),
+                    cli.desired_dimensions(),
+                );
 
-            if let Some(output_path) = output {
-                info!("Writing output");
+                // Each copy of the source is drawn at the size the source alone
+                // would be, and enough copies are repeated to cover the target
+                let source_dimensions = original_image.dimensions();
+                let (original_image, new_dimensions) = if let Some((columns, rows)) = tile_to {
+                    let scale = |size: u32, cells: u32, copy_cells: u32| {
+                        (size as u64 * cells as u64 / copy_cells.max(1) as u64).max(1) as u32
+                    };
+                    let repeated = repeat_to(
+                        &original_image.to_rgba8(),
+                        scale(source_dimensions.0, *columns, new_dimensions.0),
+                        scale(source_dimensions.1, *rows, new_dimensions.1),
+                    );
+                    (
+                        Cow::Owned(DynamicImage::ImageRgba8(repeated)),
+                        (*columns, *rows),
+                    )
+                } else {
+                    (Cow::Borrowed(&original_image), new_dimensions)
+                };
 
-                out.save(output_path)?;
+                let mask = match importance_mask {
+                    Some(mask_path) => {
+                        info!("Opening importance mask");
+                        Some(ImageReader::open(mask_path)?.decode()?.into_luma8())
+                    }
+                    None => None,
+                };
+
+                let color = console::color_enabled(cli.no_color);
+                let new_dimensions = if let Some(max_bytes) = max_bytes {
+                    info!("Fitting text to {} bytes", max_bytes);
+
+                    let at_width = |width| {
+                        ansifier.calculate_new_dimensions(
+                            original_image.dimensions(),
+                            (Some(width), None),
+                        )
+                    };
+                    let (width, _) = fit_width_to_bytes(new_dimensions.0, *max_bytes, |width| {
+                        let grid =
+                            cli.ansify(&ansifier, &original_image, at_width(width), mask.as_ref());
+                        budget_text(&ansifier, &grid, color)
+                    })
+                    .ok_or_else(|| {
+                        format!("even one column takes more than {} bytes", max_bytes)
+                    })?;
+                    at_width(width)
+                } else {
+                    new_dimensions
+                };
+                cli.report_dimensions(&ansifier, new_dimensions);
+                cli.check_dimensions(&ansifier, new_dimensions)?;
+
+                let start = Instant::now();
+                let grid = cli.ansify(&ansifier, &original_image, new_dimensions, mask.as_ref());
+                let match_duration = start.elapsed();
+                let out = if let Some(background_path) = background_image {
+                    info!("Opening background image");
+                    let background = ImageReader::open(background_path)?.decode()?.into_rgb8();
+
+                    let cells = original_image
+                        .resize_exact(
+                            new_dimensions.0,
+                            new_dimensions.1,
+                            image::imageops::Lanczos3,
+                        )
+                        .into_rgba8();
+                    let (_, alpha) = split_alpha(&cells, *alpha_threshold);
+
+                    composite_over(&ansifier.render_grid_rgba(&grid, &alpha), &background)
+                } else {
+                    ansifier.render_grid(&grid)
+                };
+
+                if *text {
+                    if max_bytes.is_some() {
+                        print!("{}", budget_text(&ansifier, &grid, color));
+                    } else {
+                        console::print_grid(&ansifier, &grid, color)?;
+                    }
+                }
+
+                match emit {
+                    Some(Emit::Rust) => print!("{}", ansifier.grid_rust(&grid, "ANSIFY")),
+                    Some(Emit::Html) => print!("{}", ansifier.grid_html(&grid)),
+                    Some(Emit::HtmlPage) => print!("{}", ansifier.grid_html_page(&grid)),
+                    Some(Emit::Mirc) => {
+                        print!("{}", ansifier.grid_mirc(&grid, MircColors::Standard))
+                    }
+                    Some(Emit::MircExtended) => {
+                        print!("{}", ansifier.grid_mirc(&grid, MircColors::Extended))
+                    }
+                    None => {}
+                }
+
+                if *stats_json {
+                    let stats = ansifier.match_stats(
+                        &cli.resize(&original_image, new_dimensions),
+                        &grid,
+                        match_duration,
+                    );
+                    println!("{}", stats.to_json());
+                }
+
+                if let Some(csv_path) = csv {
+                    info!("Writing CSV");
+
+                    std::fs::write(csv_path, ansifier.grid_csv(&grid))?;
+                }
+
+                if let Some(output_path) = output {
+                    let metadata = if *embed_metadata {
+                        Some(cli.output_metadata(input, source_dimensions))
+                    } else {
+                        None
+                    };
+
+                    if let Some((tile_width, tile_height)) = tile {
+                        info!("Writing output tiles");
+
+                        for (row, tiles) in split_tiles(&out, *tile_width, *tile_height)
+                            .iter()
+                            .enumerate()
+                        {
+                            for (column, tile) in tiles.iter().enumerate() {
+                                save_output(tile, &tile_path(output_path, row, column), &metadata)?;
+                            }
+                        }
+                    } else {
+                        info!("Writing output");
+
+                        save_output(&out, output_path, &metadata)?;
+                    }
+                }
+
+                if let Some(indices_path) = indices {
+                    info!("Writing cell indices");
+
+                    ansifier.grid_indices(&grid).save(indices_path)?;
+                }
+
+                if let Some(thumbnail_path) = thumbnail {
+                    info!("Writing thumbnail");
+
+                    ansifier
+                        .process_thumbnail(&cli.resize(&original_image, new_dimensions))
+                        .save(thumbnail_path)?;
+                }
+
+                if let Some(coverage_path) = palette_coverage {
+                    info!("Writing palette coverage");
+
+                    ansifier
+                        .palette_coverage_image(&cli.resize(&original_image, new_dimensions))
+                        .save(coverage_path)?;
+                }
+
+                if let Some(confidence_path) = confidence {
+                    info!("Writing confidence");
+
+                    ansifier
+                        .match_confidence(&cli.resize(&original_image, new_dimensions))
+                        .save(confidence_path)?;
+                }
+
+                if let Some(match_log_path) = match_log {
+                    info!("Writing match log");
+
+                    let mut log =
+                        String::from("x\ty\tsource\tforeground\tbackground\tblock\tdistance\n");
+                    ansifier.match_grid_with_log(
+                        &cli.resize(&original_image, new_dimensions),
+                        |entry| {
+                            let [r, g, b] = entry.source;
+                            log.push_str(&format!(
+                                "{}\t{}\t#{:02x}{:02x}{:02x}\t{}\t{}\t{}\t{:.6}\n",
+                                entry.x,
+                                entry.y,
+                                r,
+                                g,
+                                b,
+                                entry.texel.foreground_color,
+                                entry.texel.background_color,
+                                entry.texel.block,
+                                entry.distance
+                            ));
+                        },
+                    );
+                    std::fs::write(match_log_path, log)?;
+                }
+
+                if let Some(framebuffer_path) = framebuffer {
+                    Framebuffer::query(framebuffer_path)?.blit(framebuffer_path, &out)?;
+                }
+
+                if *show {
+                    info!("Showing image");
+
+                    // In watch mode the window is reused so each change replaces the image
+                    if window.is_none() {
+                        let (width, height) = ansifier.output_dimensions(new_dimensions);
+                        window = Some(create_window(
+                            "img2ansi",
+                            WindowOptions::new().set_size([width, height]),
+                        )?);
+                    }
+                    window.as_ref().unwrap().set_image("image", out)?;
+                }
+
+                #[cfg(feature = "watch")]
+                if let Some(watcher) = &mut watcher {
+                    ansifier = watcher.wait(&cli, ansifier)?;
+                    continue;
+                }
+
+                break;
             }
 
-            if *show {
-                info!("Showing image");
-
-                let window = create_window(
-                    "img2ansi",
-                    WindowOptions::new().set_size([
-                        new_dimensions.0 * ansifier.block_width(),
-                        new_dimensions.1 * ansifier.block_height(),
-                    ]),
-                )?;
-                window.set_image("image", out)?;
+            if let Some(window) = window {
                 window.wait_until_destroyed()?;
             }
         }
-        Commands::Gif { input, output } => {
+        Commands::Gif {
+            input,
+            output,
+            per_frame_palette,
+            palette_drift,
+            frame_step,
+        } => {
             info!("Opening original image");
             let file_in = File::open(input)?;
             let decoder = GifDecoder::new(file_in)?;
@@ -129,122 +1069,187 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let mut encoder = GifEncoder::new(file_out);
             encoder.set_repeat(Repeat::Infinite)?;
 
-            for frame in decoder.into_frames() {
-                let frame = frame?;
-                info!("Calculating dimension and resizing");
-                let left = frame.left();
-                let top = frame.top();
-                let delay = frame.delay();
-                let original_image = DynamicImage::ImageRgba8(frame.into_buffer());
-
-                let new_dimensions = ansifier
-                    .calculate_new_dimensions(original_image.dimensions(), (cli.width, cli.height));
-                let img = original_image
-                    .resize_exact(
-                        new_dimensions.0,
-                        new_dimensions.1,
-                        image::imageops::Lanczos3,
-                    )
-                    .into_rgb8();
+            ansify_frames(
+                &cli,
+                ansifier,
+                ansify::frame_step(decoder.into_frames(), *frame_step),
+                *per_frame_palette,
+                *palette_drift,
+                |frame| encoder.encode_frame(frame),
+            )?;
+        }
+        Commands::Lut { lut, map, dry_run } => {
+            let report = ansifier.lut_compatibility_report();
+
+            if *dry_run {
+                print!("{}", report);
+            }
+
+            if !report.is_compatible() {
+                return Err(format!(
+                    "Palette and blocks are not compatible with the LUT format: {}",
+                    report.problems.join(", ")
+                )
+                .into());
+            }
+
+            if let (false, Some(lut_path), Some(map_path)) = (*dry_run, lut, map) {
+                ansifier.write_lut_and_map(File::create(lut_path)?, File::create(map_path)?)?;
+            }
+        }
+        Commands::TestPattern { kind, output, text } => {
+            // Without a requested size the pattern fills a 64x16 cell grid
+            let new_dimensions = ansifier.calculate_new_dimensions(
+                (64 * ansifier.block_width(), 16 * ansifier.block_height()),
+                cli.desired_dimensions(),
+            );
+            cli.report_dimensions(&ansifier, new_dimensions);
+            cli.check_dimensions(&ansifier, new_dimensions)?;
+
+            let pattern = TestPattern::from(*kind).generate(new_dimensions.0, new_dimensions.1)?;
+            let grid = ansifier.match_grid(&pattern);
+            let out = ansifier.render_grid(&grid);
 
-                let (out, _) = ansifier.process(&img);
+            if *text {
+                console::print_grid(&ansifier, &grid, console::color_enabled(cli.no_color))?;
+            }
 
-                let left =
-                    (left as f32 / original_image.width() as f32 * new_dimensions.0 as f32) as u32;
-                let top =
-                    (top as f32 / original_image.height() as f32 * new_dimensions.1 as f32) as u32;
+            if let Some(output_path) = output {
+                info!("Writing output");
 
-                encoder.encode_frame(Frame::from_parts(
-                    DynamicImage::ImageRgb8(out).to_rgba8(),
-                    left,
-                    top,
-                    delay,
-                ))?;
+                out.save(output_path)?;
             }
         }
-        Commands::Webcam { index, output } => {
-            info!("Creating webcam");
-            let mut camera = Camera::new(*index, None)?;
-            camera.open_stream()?;
+        #[cfg(feature = "webcam")]
+        Commands::Webcam {
+            index,
+            output,
+            target_fps,
+        } => {
+            let mut ansifier = ansifier;
+            webcam::run(&cli, &mut ansifier, *index, output.as_ref(), *target_fps)?;
+        }
+    }
 
-            info!("Getting webcame image");
-            let original_image = camera.frame()?;
+    info!("Done");
 
-            info!("Calculating dimension and resizing");
+    return Ok(());
+}
 
-            let new_dimensions = ansifier
-                .calculate_new_dimensions(original_image.dimensions(), (cli.width, cli.height));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Delay, Rgba, RgbaImage};
+    use std::cell::Cell;
+    use std::rc::Rc;
 
-            info!("Creating image window");
+    fn cli(args: &[&str]) -> Cli {
+        let palette = concat!(env!("CARGO_MANIFEST_DIR"), "/../res/8.yaml");
+        let blocks = concat!(env!("CARGO_MANIFEST_DIR"), "/../res/tiny.yaml");
+        let mut argv = vec!["ansify", "-p", palette, "-b", blocks];
+        argv.extend(args);
+        return Cli::try_parse_from(argv).unwrap();
+    }
 
-            let window = create_window(
-                "img2ansi",
-                WindowOptions::new().set_size([
-                    new_dimensions.0 * ansifier.block_width(),
-                    new_dimensions.1 * ansifier.block_height(),
-                ]),
-            )?;
+    // Counts the frames handed out that haven't been written yet
+    struct CountingFrames {
+        remaining: u32,
+        in_flight: Rc<Cell<usize>>,
+        peak: Rc<Cell<usize>>,
+    }
 
-            let mut encoder = if let Some(output_file) = output {
-                info!("Gif file");
+    impl Iterator for CountingFrames {
+        type Item = ImageResult<Frame>;
 
-                let file_out = File::create(output_file)?;
-                let mut new_encoder = GifEncoder::new(file_out);
-                new_encoder.set_repeat(Repeat::Infinite)?;
-                Some(new_encoder)
-            } else {
-                None
-            };
+        fn next(&mut self) -> Option<ImageResult<Frame>> {
+            if self.remaining == 0 {
+                return None;
+            }
+            self.remaining -= 1;
 
-            let mut last_frame: Option<(RgbaImage, Instant)> = None;
+            self.in_flight.set(self.in_flight.get() + 1);
+            self.peak.set(self.peak.get().max(self.in_flight.get()));
 
-            loop {
-                let original_image = camera.frame()?;
+            let buffer =
+                RgbaImage::from_pixel(16, 16, Rgba([self.remaining as u8 * 40, 90, 200, 255]));
+            return Some(Ok(Frame::from_parts(
+                buffer,
+                0,
+                0,
+                Delay::from_numer_denom_ms(100, 1),
+            )));
+        }
+    }
 
-                let img = DynamicImage::ImageRgb8(original_image)
-                    .resize_exact(
-                        new_dimensions.0,
-                        new_dimensions.1,
-                        image::imageops::Lanczos3,
-                    )
-                    .into_rgb8();
+    #[test]
+    fn one_frame_in_flight() {
+        let cli = cli(&["-w", "4", "gif", "-i", "in.gif", "-o", "out.gif"]);
+        let ansifier = cli.ansifier().unwrap();
 
-                let (out, _) = (&ansifier).process(&img);
+        let in_flight = Rc::new(Cell::new(0));
+        let peak = Rc::new(Cell::new(0));
+        let frames = CountingFrames {
+            remaining: 5,
+            in_flight: in_flight.clone(),
+            peak: peak.clone(),
+        };
 
-                info!("Showing image");
+        let mut written = 0;
+        ansify_frames(&cli, ansifier, frames, true, None, |_| {
+            in_flight.set(in_flight.get() - 1);
+            written += 1;
+            return Ok(());
+        })
+        .unwrap();
 
-                if let Some(ref mut enc) = encoder {
-                    if let Some((ref real_last_frame, last_time)) = last_frame {
-                        if last_time.elapsed() > Duration::from_millis(10) {
-                            enc.encode_frame(Frame::from_parts(
-                                real_last_frame.clone(),
-                                0,
-                                0,
-                                Delay::from_saturating_duration(last_time.elapsed()),
-                            ))?;
+        assert_eq!(written, 5);
+        assert_eq!(peak.get(), 1);
+    }
 
-                            last_frame = None;
-                        }
-                    }
+    #[test]
+    fn fit_terminal_cells_fills_the_grid() {
+        let cli = cli(&["--fit-terminal-cells", "80x24", "image", "-i", "in.png"]);
+        let ansifier = cli.ansifier().unwrap();
 
-                    if last_frame.is_none() {
-                        last_frame = Some((
-                            DynamicImage::ImageRgb8(out.clone()).to_rgba8(),
-                            Instant::now(),
-                        ));
-                    }
-                }
+        // Much wider than 80x24 cells would be at its own aspect ratio
+        let original = DynamicImage::ImageRgb8(RgbImage::new(640, 120));
+        let dimensions =
+            ansifier.calculate_new_dimensions(original.dimensions(), cli.desired_dimensions());
+        assert_eq!(dimensions, (80, 24));
 
-                if window.set_image("image", out).is_err() {
-                    info!("Closing window");
+        let grid = cli.ansify(&ansifier, &original, dimensions, None);
+        assert_eq!(grid.len(), 24);
+        assert!(grid.iter().all(|row| row.len() == 80));
+    }
 
-                    break;
-                }
-            }
-        }
+    #[test]
+    fn palette_size_must_be_positive() {
+        let argv = [
+            "ansify",
+            "-p",
+            "8.yaml",
+            "-b",
+            "tiny.yaml",
+            "--palette-size",
+            "0",
+        ];
+        assert!(Cli::try_parse_from(argv.iter().chain(&["image", "-i", "in.png"])).is_err());
+
+        assert_eq!(
+            cli(&["--palette-size", "3", "image", "-i", "in.png"]).palette_size,
+            3
+        );
     }
 
-    info!("Done");
+    #[test]
+    fn budget_text_follows_color() {
+        let ansifier = cli(&["image", "-i", "in.png"]).ansifier().unwrap();
+        let grid = ansifier.match_grid(&RgbImage::from_pixel(4, 2, image::Rgb([200, 30, 30])));
 
-    return Ok(());
+        assert!(budget_text(&ansifier, &grid, true).contains('\x1b'));
+        assert_eq!(
+            budget_text(&ansifier, &grid, false),
+            ansifier.grid_plain(&grid)
+        );
+    }
 }
@@ -0,0 +1,399 @@
+use crate::{Cli, MatchMode};
+use ansify::{ANSIfier, DistanceMetric, Dither, Palette, PalettePreset};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{
+    self, disable_raw_mode, enable_raw_mode, Clear, ClearType, EnterAlternateScreen,
+    LeaveAlternateScreen,
+};
+use crossterm::{execute, QueueableCommand};
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView};
+use log::info;
+use std::io::Write;
+
+// Matching against a LUT cache keeps re-rendering fast enough to feel live
+const LUT_CACHE_RESOLUTION: u32 = 64;
+
+const FILTERS: [FilterType; 5] = [
+    FilterType::Nearest,
+    FilterType::Triangle,
+    FilterType::CatmullRom,
+    FilterType::Gaussian,
+    FilterType::Lanczos3,
+];
+
+const POSTERIZE_LEVELS: [Option<usize>; 5] = [None, Some(16), Some(8), Some(4), Some(2)];
+
+// None is the palette the ANSIfier was created with
+const PALETTES: [Option<PalettePreset>; 5] = [
+    None,
+    Some(PalettePreset::Pico8),
+    Some(PalettePreset::Nes),
+    Some(PalettePreset::GameBoy),
+    Some(PalettePreset::Cga),
+];
+
+const METRICS: [DistanceMetric; 4] = [
+    DistanceMetric::Euclidean,
+    DistanceMetric::Lab,
+    DistanceMetric::CIEDE2000,
+    DistanceMetric::WeightedRgb,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Left,
+    Right,
+    Up,
+    Down,
+    Esc,
+    Char(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Update {
+    None,
+    Redraw,
+    Rebuild,
+    Quit,
+}
+
+#[derive(Clone, Copy)]
+struct TuiState {
+    width: u32,
+    filter: usize,
+    match_mode: MatchMode,
+    posterize: usize,
+    palette: usize,
+    metric: usize,
+    dither: Dither,
+    // Kept so cycling back to Floyd-Steinberg restores the strength it was given
+    dither_strength: f32,
+}
+
+impl TuiState {
+    fn new(width: u32, metric: DistanceMetric, dither: Dither) -> TuiState {
+        let dither_strength = match dither {
+            Dither::FloydSteinberg { strength } => strength,
+            _ => 1.0,
+        };
+
+        return TuiState {
+            width: width.max(1),
+            filter: FILTERS.len() - 1,
+            match_mode: MatchMode::Color,
+            posterize: 0,
+            palette: 0,
+            metric: METRICS.iter().position(|m| *m == metric).unwrap_or(0),
+            dither,
+            dither_strength,
+        };
+    }
+
+    fn filter(&self) -> FilterType {
+        return FILTERS[self.filter];
+    }
+
+    fn posterize(&self) -> Option<usize> {
+        return POSTERIZE_LEVELS[self.posterize];
+    }
+
+    fn palette(&self) -> Option<PalettePreset> {
+        return PALETTES[self.palette];
+    }
+
+    fn metric(&self) -> DistanceMetric {
+        return METRICS[self.metric];
+    }
+
+    // Left/Right change the width by one cell and Up/Down by ten, `f` cycles the
+    // resize filter, `m` the match mode, `p` posterization, `d` dithering, and `c`
+    // the palette and `e` the distance metric, which both need a rebuild
+    fn handle_key(&mut self, key: Key) -> Update {
+        match key {
+            Key::Left => self.width = self.width.saturating_sub(1).max(1),
+            Key::Right => self.width = self.width.saturating_add(1),
+            Key::Down => self.width = self.width.saturating_sub(10).max(1),
+            Key::Up => self.width = self.width.saturating_add(10),
+            Key::Char('f') => self.filter = (self.filter + 1) % FILTERS.len(),
+            Key::Char('m') => {
+                self.match_mode = match self.match_mode {
+                    MatchMode::Color => MatchMode::Structural,
+                    MatchMode::Structural => MatchMode::Color,
+                }
+            }
+            Key::Char('p') => self.posterize = (self.posterize + 1) % POSTERIZE_LEVELS.len(),
+            Key::Char('d') => {
+                self.dither = match self.dither {
+                    Dither::None => Dither::BlueNoise,
+                    Dither::BlueNoise => Dither::FloydSteinberg {
+                        strength: self.dither_strength,
+                    },
+                    Dither::FloydSteinberg { .. } => Dither::None,
+                }
+            }
+            Key::Char('c') => {
+                self.palette = (self.palette + 1) % PALETTES.len();
+                return Update::Rebuild;
+            }
+            Key::Char('e') => {
+                self.metric = (self.metric + 1) % METRICS.len();
+                return Update::Rebuild;
+            }
+            Key::Char('q') | Key::Esc => return Update::Quit,
+            Key::Char(_) => return Update::None,
+        }
+
+        return Update::Redraw;
+    }
+
+    fn status(&self) -> String {
+        let match_mode = match self.match_mode {
+            MatchMode::Color => "color",
+            MatchMode::Structural => "structural",
+        };
+        let posterize = match self.posterize() {
+            Some(colors) => colors.to_string(),
+            None => String::from("off"),
+        };
+        let palette = match self.palette() {
+            Some(preset) => format!("{:?}", preset),
+            None => String::from("original"),
+        };
+        let dither = match self.dither {
+            Dither::None => "off",
+            Dither::BlueNoise => "blue noise",
+            Dither::FloydSteinberg { .. } => "floyd-steinberg",
+        };
+
+        return format!(
+            "width {} | filter {:?} | match {} | posterize {} | dither {} | palette {} | metric {:?} | q to quit",
+            self.width,
+            self.filter(),
+            match_mode,
+            posterize,
+            dither,
+            palette,
+            self.metric()
+        );
+    }
+}
+
+fn to_key(code: KeyCode) -> Option<Key> {
+    return match code {
+        KeyCode::Left => Some(Key::Left),
+        KeyCode::Right => Some(Key::Right),
+        KeyCode::Up => Some(Key::Up),
+        KeyCode::Down => Some(Key::Down),
+        KeyCode::Esc => Some(Key::Esc),
+        KeyCode::Char(c) => Some(Key::Char(c)),
+        _ => None,
+    };
+}
+
+fn draw(
+    cli: &Cli,
+    ansifier: &ANSIfier,
+    original_image: &DynamicImage,
+    state: &TuiState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (_, rows) = terminal::size()?;
+
+    // The bottom row is kept for the status line
+    let (width, height) =
+        ansifier.calculate_new_dimensions(original_image.dimensions(), (Some(state.width), None));
+    let height = height.min((rows as u32).saturating_sub(1).max(1));
+
+    let (resize_width, resize_height) = match state.match_mode {
+        MatchMode::Color => (width, height),
+        MatchMode::Structural => (
+            width * ansifier.block_width(),
+            height * ansifier.block_height(),
+        ),
+    };
+    let mut img = original_image
+        .resize_exact(resize_width, resize_height, state.filter())
+        .into_rgb8();
+    cli.adjust(&mut img);
+
+    let mut grid = match state.match_mode {
+        MatchMode::Color => ansifier.match_grid(&img),
+        MatchMode::Structural => ansifier.match_grid_structural(&img),
+    };
+    if let Some(colors) = state.posterize() {
+        ansifier.posterize(&mut grid, colors);
+    }
+
+    let mut stdout = std::io::stdout();
+    stdout.queue(Clear(ClearType::All))?;
+    for (y, row) in grid.iter().enumerate() {
+        stdout.queue(MoveTo(0, y as u16))?;
+        for texel in row {
+            stdout
+                .queue(SetForegroundColor(Color::AnsiValue(texel.foreground_color)))?
+                .queue(SetBackgroundColor(Color::AnsiValue(texel.background_color)))?
+                .queue(Print(ansifier.texel_char(texel)))?;
+        }
+        stdout.queue(ResetColor)?;
+    }
+    stdout
+        .queue(MoveTo(0, rows.saturating_sub(1)))?
+        .queue(Print(state.status()))?;
+
+    stdout.flush()?;
+
+    return Ok(());
+}
+
+fn event_loop(
+    cli: &Cli,
+    mut ansifier: ANSIfier,
+    original_image: &DynamicImage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let original_palette = ansifier.palette().clone();
+    let (columns, _) = terminal::size()?;
+    let mut state = TuiState::new(
+        cli.width.unwrap_or(columns as u32),
+        cli.metric.into(),
+        cli.dither(),
+    );
+    let mut built = state;
+
+    draw(cli, &ansifier, original_image, &state)?;
+
+    loop {
+        let key = match event::read()? {
+            Event::Key(key) if key.kind != KeyEventKind::Release => to_key(key.code),
+            Event::Resize(_, _) => {
+                draw(cli, &ansifier, original_image, &state)?;
+                continue;
+            }
+            _ => None,
+        };
+
+        match key.map_or(Update::None, |key| state.handle_key(key)) {
+            Update::None => {}
+            Update::Redraw => {
+                ansifier.set_dither(state.dither);
+                draw(cli, &ansifier, original_image, &state)?;
+            }
+            Update::Rebuild => {
+                if state.metric != built.metric {
+                    ansifier = ansifier.rebuild_with_metric(state.metric());
+                }
+                if state.palette != built.palette {
+                    let palette = match state.palette() {
+                        Some(preset) => Palette::preset(preset),
+                        None => original_palette.clone(),
+                    };
+                    ansifier = ansifier.rebuild_with_palette(palette)?;
+                }
+                built = state;
+                draw(cli, &ansifier, original_image, &state)?;
+            }
+            Update::Quit => return Ok(()),
+        }
+    }
+}
+
+pub fn run(
+    cli: &Cli,
+    ansifier: ANSIfier,
+    original_image: &DynamicImage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Starting interactive mode");
+
+    let ansifier = if cli.lut_cache.is_some() || cli.use_lut {
+        ansifier
+    } else {
+        ansifier.with_lut_cache(LUT_CACHE_RESOLUTION)
+    };
+
+    enable_raw_mode()?;
+    execute!(std::io::stdout(), EnterAlternateScreen, Hide)?;
+
+    let result = event_loop(cli, ansifier, original_image);
+
+    // The terminal is restored even if rendering failed
+    execute!(std::io::stdout(), ResetColor, Show, LeaveAlternateScreen)?;
+    disable_raw_mode()?;
+
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn width_keys_clamp_at_one() {
+        let mut state = TuiState::new(5, DistanceMetric::Euclidean, Dither::None);
+        assert_eq!(state.handle_key(Key::Right), Update::Redraw);
+        assert_eq!(state.width, 6);
+        assert_eq!(state.handle_key(Key::Up), Update::Redraw);
+        assert_eq!(state.width, 16);
+        state.handle_key(Key::Down);
+        state.handle_key(Key::Down);
+        assert_eq!(state.width, 1);
+        state.handle_key(Key::Left);
+        assert_eq!(state.width, 1);
+    }
+
+    #[test]
+    fn option_keys_cycle() {
+        let mut state = TuiState::new(10, DistanceMetric::Euclidean, Dither::None);
+        assert_eq!(state.filter(), FilterType::Lanczos3);
+        state.handle_key(Key::Char('f'));
+        assert_eq!(state.filter(), FilterType::Nearest);
+
+        state.handle_key(Key::Char('m'));
+        assert!(matches!(state.match_mode, MatchMode::Structural));
+        state.handle_key(Key::Char('m'));
+        assert!(matches!(state.match_mode, MatchMode::Color));
+
+        for _ in 0..POSTERIZE_LEVELS.len() - 1 {
+            state.handle_key(Key::Char('p'));
+        }
+        assert_eq!(state.posterize(), Some(2));
+        state.handle_key(Key::Char('p'));
+        assert_eq!(state.posterize(), None);
+    }
+
+    #[test]
+    fn palette_key_requests_rebuild() {
+        let mut state = TuiState::new(10, DistanceMetric::Euclidean, Dither::None);
+        assert_eq!(state.handle_key(Key::Char('c')), Update::Rebuild);
+        assert_eq!(state.palette(), Some(PalettePreset::Pico8));
+        assert_eq!(state.handle_key(Key::Char('x')), Update::None);
+        assert_eq!(state.handle_key(Key::Esc), Update::Quit);
+        assert_eq!(state.handle_key(Key::Char('q')), Update::Quit);
+    }
+
+    #[test]
+    fn dither_key_cycles_and_keeps_strength() {
+        let mut state = TuiState::new(
+            10,
+            DistanceMetric::Euclidean,
+            Dither::FloydSteinberg { strength: 0.5 },
+        );
+        assert_eq!(state.handle_key(Key::Char('d')), Update::Redraw);
+        assert_eq!(state.dither, Dither::None);
+        state.handle_key(Key::Char('d'));
+        assert_eq!(state.dither, Dither::BlueNoise);
+        state.handle_key(Key::Char('d'));
+        assert_eq!(state.dither, Dither::FloydSteinberg { strength: 0.5 });
+    }
+
+    #[test]
+    fn metric_key_requests_rebuild() {
+        let mut state = TuiState::new(10, DistanceMetric::Lab, Dither::None);
+        assert_eq!(state.metric(), DistanceMetric::Lab);
+        assert_eq!(state.handle_key(Key::Char('e')), Update::Rebuild);
+        assert_eq!(state.metric(), DistanceMetric::CIEDE2000);
+        state.handle_key(Key::Char('e'));
+        state.handle_key(Key::Char('e'));
+        assert_eq!(state.metric(), DistanceMetric::Euclidean);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::Cli;
+use ansify::{ANSIfier, Palette};
+use log::{info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+// Editors often save a file in several writes, so events this close together are
+// handled as a single change
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Reload {
+    None,
+    Palette,
+    All,
+}
+
+struct WatchedFiles {
+    palette: Option<PathBuf>,
+    blocks: Option<PathBuf>,
+}
+
+impl WatchedFiles {
+    fn reload_for(&self, event: &Event) -> Reload {
+        // Reading the files while rebuilding must not trigger another rebuild
+        if let EventKind::Access(_) = event.kind {
+            return Reload::None;
+        }
+
+        let mut reload = Reload::None;
+        for path in &event.paths {
+            if self.blocks.as_ref() == Some(path) {
+                reload = Reload::All;
+            } else if self.palette.as_ref() == Some(path) {
+                reload = reload.max(Reload::Palette);
+            }
+        }
+
+        return reload;
+    }
+}
+
+// Events carry absolute paths, so the watched paths are made absolute the same way
+fn watched_path(path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| format!("`{}` is not a file", path.display()))?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    return Ok(parent.canonicalize()?.join(file_name));
+}
+
+pub struct Watcher {
+    files: WatchedFiles,
+    events: Receiver<notify::Result<Event>>,
+    // Events stop once the watcher is dropped
+    _watcher: RecommendedWatcher,
+}
+
+impl Watcher {
+    pub fn new(cli: &Cli) -> Result<Watcher, Box<dyn std::error::Error>> {
+        let files = WatchedFiles {
+            palette: cli.palette.as_deref().map(watched_path).transpose()?,
+            blocks: cli.blocks.as_deref().map(watched_path).transpose()?,
+        };
+        if files.palette.is_none() && files.blocks.is_none() {
+            return Err("--watch needs a palette or blocks file".into());
+        }
+
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+
+        // The directories are watched rather than the files because many editors
+        // save by replacing the file
+        for path in files.palette.iter().chain(files.blocks.iter()) {
+            watcher.watch(path.parent().unwrap(), RecursiveMode::NonRecursive)?;
+        }
+
+        return Ok(Watcher {
+            files,
+            events,
+            _watcher: watcher,
+        });
+    }
+
+    // Blocks until a watched file changes and returns the ANSIfier rebuilt from it,
+    // a file that fails to load is reported and the previous ANSIfier kept
+    pub fn wait(
+        &mut self,
+        cli: &Cli,
+        ansifier: ANSIfier,
+    ) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        info!("Watching for changes");
+
+        loop {
+            let mut reload = self.files.reload_for(&self.events.recv()??);
+            while let Ok(event) = self.events.recv_timeout(DEBOUNCE) {
+                reload = reload.max(self.files.reload_for(&event?));
+            }
+
+            match reload {
+                Reload::None => {}
+                Reload::Palette => {
+                    info!("Reloading palette");
+
+                    match Palette::from(self.files.palette.clone().unwrap()) {
+                        Ok(palette) => return ansifier.rebuild_with_palette(palette),
+                        Err(error) => warn!("Failed to reload palette: {}", error),
+                    }
+                }
+                Reload::All => {
+                    info!("Reloading palette and blocks");
+
+                    match cli.ansifier() {
+                        Ok(ansifier) => return Ok(ansifier),
+                        Err(error) => warn!("Failed to reload: {}", error),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, DataChange, ModifyKind};
+
+    fn files() -> WatchedFiles {
+        return WatchedFiles {
+            palette: Some(PathBuf::from("/art/palette.yaml")),
+            blocks: Some(PathBuf::from("/art/blocks.yaml")),
+        };
+    }
+
+    fn modified(path: &str) -> Event {
+        return Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+            .add_path(PathBuf::from(path));
+    }
+
+    #[test]
+    fn palette_change_rebuilds_palette_only() {
+        assert_eq!(
+            files().reload_for(&modified("/art/palette.yaml")),
+            Reload::Palette
+        );
+    }
+
+    #[test]
+    fn blocks_change_rebuilds_everything() {
+        assert_eq!(
+            files().reload_for(&modified("/art/blocks.yaml")),
+            Reload::All
+        );
+
+        // A replaced file shows up as a create event
+        let replaced = Event::new(EventKind::Create(CreateKind::File))
+            .add_path(PathBuf::from("/art/blocks.yaml"));
+        assert_eq!(files().reload_for(&replaced), Reload::All);
+
+        // A burst of events is merged into the largest rebuild
+        assert_eq!(
+            Reload::Palette.max(files().reload_for(&modified("/art/blocks.yaml"))),
+            Reload::All
+        );
+    }
+
+    #[test]
+    fn unrelated_events_are_ignored() {
+        assert_eq!(
+            files().reload_for(&modified("/art/notes.txt")),
+            Reload::None
+        );
+
+        let read = Event::new(EventKind::Access(AccessKind::Any))
+            .add_path(PathBuf::from("/art/palette.yaml"));
+        assert_eq!(files().reload_for(&read), Reload::None);
+
+        let without_blocks = WatchedFiles {
+            palette: Some(PathBuf::from("/art/palette.yaml")),
+            blocks: None,
+        };
+        assert_eq!(
+            without_blocks.reload_for(&modified("/art/blocks.yaml")),
+            Reload::None
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use crate::Cli;
+use ansify::{ANSIfier, Dither};
+use core::time::Duration;
+use image::gif::{GifEncoder, Repeat};
+use image::{Delay, DynamicImage, Frame, RgbImage, RgbaImage};
+use log::info;
+use nokhwa::Camera;
+use show_image::create_window;
+use show_image::WindowOptions;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+// A bounded queue between capture and processing. When processing falls behind,
+// the oldest unprocessed frame is dropped so latency stays low.
+pub struct FrameQueue<T> {
+    state: Mutex<(VecDeque<T>, bool)>,
+    available: Condvar,
+    capacity: usize,
+}
+
+impl<T> FrameQueue<T> {
+    pub fn new(capacity: usize) -> FrameQueue<T> {
+        return FrameQueue {
+            state: Mutex::new((VecDeque::with_capacity(capacity), false)),
+            available: Condvar::new(),
+            capacity: capacity.max(1),
+        };
+    }
+
+    // Returns false once the queue has been closed and the frame was discarded
+    pub fn push(&self, frame: T) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.1 {
+            return false;
+        }
+
+        if state.0.len() == self.capacity {
+            info!("Dropping stale frame");
+            state.0.pop_front();
+        }
+        state.0.push_back(frame);
+        self.available.notify_one();
+
+        return true;
+    }
+
+    // Blocks until a frame is available, or returns None once closed and drained
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(frame) = state.0.pop_front() {
+                return Some(frame);
+            }
+            if state.1 {
+                return None;
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.available.notify_all();
+    }
+}
+
+// How much detail frames are processed at, the cell count is scaled on both axes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    pub scale: f32,
+    pub dither: bool,
+}
+
+// Frame times are averaged over roughly this many frames
+const SMOOTHING: f32 = 0.2;
+// Frames to measure after a change before judging it
+const SETTLE_FRAMES: u32 = 5;
+// Quality only comes back when frames take less than this part of the budget, so
+// it doesn't flip back and forth around the target
+const HEADROOM: f32 = 0.6;
+const SCALE_STEP: f32 = 0.8;
+const MIN_SCALE: f32 = 0.25;
+
+// Lowers the quality while frames take longer than the target frame rate allows,
+// dropping dithering first and then cells, and raises it back in reverse order
+// once frames are fast again
+pub struct QualityController {
+    budget: Duration,
+    dither_allowed: bool,
+    quality: Quality,
+    average: Option<f32>,
+    samples: u32,
+}
+
+impl QualityController {
+    pub fn new(target_fps: f32, dither_allowed: bool) -> QualityController {
+        return QualityController {
+            budget: Duration::from_secs_f32(1.0 / target_fps),
+            dither_allowed,
+            quality: Quality {
+                scale: 1.0,
+                dither: dither_allowed,
+            },
+            average: None,
+            samples: 0,
+        };
+    }
+
+    pub fn quality(&self) -> Quality {
+        return self.quality;
+    }
+
+    pub fn record(&mut self, frame_time: Duration) -> Quality {
+        let frame_time = frame_time.as_secs_f32();
+        let average = match self.average {
+            Some(average) => average + (frame_time - average) * SMOOTHING,
+            None => frame_time,
+        };
+        self.average = Some(average);
+        self.samples += 1;
+
+        if self.samples < SETTLE_FRAMES {
+            return self.quality;
+        }
+
+        let budget = self.budget.as_secs_f32();
+        let previous = self.quality;
+        if average > budget {
+            if self.quality.dither {
+                self.quality.dither = false;
+            } else {
+                self.quality.scale = (self.quality.scale * SCALE_STEP).max(MIN_SCALE);
+            }
+        } else if average < budget * HEADROOM {
+            if self.quality.scale < 1.0 {
+                self.quality.scale = (self.quality.scale / SCALE_STEP).min(1.0);
+            } else if self.dither_allowed {
+                self.quality.dither = true;
+            }
+        }
+
+        if self.quality != previous {
+            info!("Adjusting quality to {:?}", self.quality);
+
+            self.average = None;
+            self.samples = 0;
+        }
+
+        return self.quality;
+    }
+}
+
+fn capture_frames(
+    index: usize,
+    queue: &FrameQueue<RgbImage>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Creating webcam");
+    let mut camera = Camera::new(index, None)?;
+    camera.open_stream()?;
+
+    while queue.push(camera.frame()?) {}
+
+    return Ok(());
+}
+
+pub fn run(
+    cli: &Cli,
+    ansifier: &mut ANSIfier,
+    index: usize,
+    output: Option<&PathBuf>,
+    target_fps: Option<f32>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let queue = Arc::new(FrameQueue::new(2));
+
+    // The camera is created on the capture thread since it cannot be sent between threads
+    let capture_queue = Arc::clone(&queue);
+    let capture = thread::spawn(move || -> Result<(), String> {
+        let result = capture_frames(index, &capture_queue).map_err(|e| e.to_string());
+        capture_queue.close();
+        return result;
+    });
+
+    info!("Getting webcame image");
+    let original_image = match queue.pop() {
+        Some(frame) => frame,
+        None => {
+            capture.join().unwrap()?;
+            return Err("Webcam stopped before producing a frame".into());
+        }
+    };
+
+    info!("Calculating dimension and resizing");
+
+    let new_dimensions =
+        ansifier.calculate_new_dimensions(original_image.dimensions(), cli.desired_dimensions());
+    cli.report_dimensions(ansifier, new_dimensions);
+    cli.check_dimensions(ansifier, new_dimensions)?;
+
+    info!("Creating image window");
+
+    let (width, height) = ansifier.output_dimensions(new_dimensions);
+    let window = create_window("img2ansi", WindowOptions::new().set_size([width, height]))?;
+
+    let mut encoder = if let Some(output_file) = output {
+        info!("Gif file");
+
+        let file_out = File::create(output_file)?;
+        let mut new_encoder = GifEncoder::new(file_out);
+        new_encoder.set_repeat(Repeat::Infinite)?;
+        Some(new_encoder)
+    } else {
+        None
+    };
+
+    let mut last_frame: Option<(RgbaImage, Instant)> = None;
+
+    let dither = cli.dither();
+    let mut controller = target_fps.map(|fps| QualityController::new(fps, dither != Dither::None));
+
+    while let Some(original_image) = queue.pop() {
+        let start = Instant::now();
+
+        let dimensions = match controller {
+            Some(ref controller) => {
+                let quality = controller.quality();
+                ansifier.set_dither(if quality.dither { dither } else { Dither::None });
+                (
+                    ((new_dimensions.0 as f32 * quality.scale).round() as u32).max(1),
+                    ((new_dimensions.1 as f32 * quality.scale).round() as u32).max(1),
+                )
+            }
+            None => new_dimensions,
+        };
+
+        let grid = cli.ansify(
+            ansifier,
+            &DynamicImage::ImageRgb8(original_image),
+            dimensions,
+            None,
+        );
+        let mut out = ansifier.render_grid(&grid);
+
+        // Frames keep the full size so the window and gif don't change size
+        if dimensions != new_dimensions {
+            out = image::imageops::resize(&out, width, height, image::imageops::Nearest);
+        }
+
+        if let Some(ref mut controller) = controller {
+            controller.record(start.elapsed());
+        }
+
+        info!("Showing image");
+
+        if let Some(ref mut enc) = encoder {
+            if let Some((ref real_last_frame, last_time)) = last_frame {
+                if last_time.elapsed() > Duration::from_millis(10) {
+                    enc.encode_frame(Frame::from_parts(
+                        real_last_frame.clone(),
+                        0,
+                        0,
+                        Delay::from_saturating_duration(last_time.elapsed()),
+                    ))?;
+
+                    last_frame = None;
+                }
+            }
+
+            if last_frame.is_none() {
+                last_frame = Some((
+                    DynamicImage::ImageRgb8(out.clone()).to_rgba8(),
+                    Instant::now(),
+                ));
+            }
+        }
+
+        if window.set_image("image", out).is_err() {
+            info!("Closing window");
+
+            break;
+        }
+    }
+
+    queue.close();
+    capture.join().unwrap()?;
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_frames(controller: &mut QualityController, milliseconds: u64, frames: u32) -> Quality {
+        for _ in 0..frames {
+            controller.record(Duration::from_millis(milliseconds));
+        }
+        return controller.quality();
+    }
+
+    #[test]
+    fn slow_frames_lower_quality() {
+        // 20 fps leaves 50ms per frame
+        let mut controller = QualityController::new(20.0, true);
+
+        // Dithering goes first
+        let quality = run_frames(&mut controller, 80, SETTLE_FRAMES);
+        assert_eq!(
+            quality,
+            Quality {
+                scale: 1.0,
+                dither: false
+            }
+        );
+
+        let quality = run_frames(&mut controller, 80, SETTLE_FRAMES);
+        assert_eq!(quality.scale, SCALE_STEP);
+
+        // Never below the minimum scale
+        let quality = run_frames(&mut controller, 80, SETTLE_FRAMES * 20);
+        assert_eq!(quality.scale, MIN_SCALE);
+    }
+
+    #[test]
+    fn fast_frames_restore_quality() {
+        let mut controller = QualityController::new(20.0, true);
+        run_frames(&mut controller, 80, SETTLE_FRAMES * 3);
+        assert!(controller.quality().scale < 1.0);
+
+        let quality = run_frames(&mut controller, 10, SETTLE_FRAMES * 10);
+        assert_eq!(
+            quality,
+            Quality {
+                scale: 1.0,
+                dither: true
+            }
+        );
+    }
+
+    #[test]
+    fn frames_near_the_budget_keep_quality() {
+        let mut controller = QualityController::new(20.0, true);
+        run_frames(&mut controller, 80, SETTLE_FRAMES * 2);
+        let lowered = controller.quality();
+
+        // Within budget but without headroom to spare
+        assert_eq!(run_frames(&mut controller, 40, SETTLE_FRAMES * 10), lowered);
+    }
+
+    #[test]
+    fn dithering_is_not_turned_on_unless_requested() {
+        let mut controller = QualityController::new(20.0, false);
+        let quality = run_frames(&mut controller, 10, SETTLE_FRAMES * 10);
+        assert_eq!(
+            quality,
+            Quality {
+                scale: 1.0,
+                dither: false
+            }
+        );
+
+        // Without dithering to drop, cells go first
+        let quality = run_frames(&mut controller, 80, SETTLE_FRAMES);
+        assert_eq!(quality.scale, SCALE_STEP);
+    }
+
+    #[test]
+    fn slow_consumer_gets_the_newest_frames() {
+        let queue = FrameQueue::new(2);
+        for frame in 0..5 {
+            assert!(queue.push(frame));
+        }
+
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), Some(4));
+    }
+
+    #[test]
+    fn close_unblocks_pop() {
+        let queue = Arc::new(FrameQueue::new(2));
+        assert!(queue.push(1));
+
+        let consumer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let mut frames = Vec::new();
+                while let Some(frame) = queue.pop() {
+                    frames.push(frame);
+                }
+                frames
+            })
+        };
+
+        // Give the consumer time to drain the queue and block waiting for more
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert!(!queue.push(2));
+
+        assert_eq!(consumer.join().unwrap(), vec![1]);
+        assert_eq!(queue.pop(), None);
+    }
+}
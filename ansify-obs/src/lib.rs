@@ -70,10 +70,8 @@ impl Sourceable for ANSIfyFilter {
             let blocks_path_setting: Option::<ObsString> = settings.get(obs_string!("blocks_path"));
 
             let ansifier_data = if let (Some(palette_path), Some(blocks_path)) = (palette_path_setting.clone(), blocks_path_setting.clone()) {
-                if let (Ok(palette), Ok(blocks)) = (
-                    Palette::from(PathBuf::from(palette_path.as_str())),
-                    Blocks::from(PathBuf::from(blocks_path.as_str()))) {
-                    let ansifier = ANSIfier::new(palette, blocks);
+                if let Ok(ansifier) = Palette::from(PathBuf::from(palette_path.as_str()))
+                    .and_then(|palette| ANSIfier::new(palette, Blocks::from(PathBuf::from(blocks_path.as_str()))?)) {
             
                     #[cfg(feature = "rayon")]
                     let (lut_image_buffer, map_image_buffer) = ansifier.par_generate_lut_and_map();
@@ -178,10 +176,8 @@ impl UpdateSource for ANSIfyFilter {
 
         if self.palette_path_setting != palette_path_setting || self.blocks_path_setting != blocks_path_setting {
             self.ansifier_data = if let (Some(palette_path), Some(blocks_path)) = (palette_path_setting, blocks_path_setting) {
-                if let (Ok(palette), Ok(blocks)) = (
-                    Palette::from(PathBuf::from(palette_path.as_str())),
-                    Blocks::from(PathBuf::from(blocks_path.as_str()))) {
-                    let ansifier = ANSIfier::new(palette, blocks);
+                if let Ok(ansifier) = Palette::from(PathBuf::from(palette_path.as_str()))
+                    .and_then(|palette| ANSIfier::new(palette, Blocks::from(PathBuf::from(blocks_path.as_str()))?)) {
             
                     #[cfg(feature = "rayon")]
                     let (lut_image_buffer, map_image_buffer) = ansifier.par_generate_lut_and_map();
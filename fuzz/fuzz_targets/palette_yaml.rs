@@ -0,0 +1,9 @@
+#![no_main]
+
+use ansify::Palette;
+use libfuzzer_sys::fuzz_target;
+
+// Any input must come back as an Ok or an Err, never a panic
+fuzz_target!(|text: &str| {
+    let _ = Palette::from_yaml_str(text);
+});
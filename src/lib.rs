@@ -1,236 +1,4407 @@
 use ansi_term::Colour::Fixed;
-use image::{RgbImage, RgbaImage, Rgb, Rgba};
+use image::png::PngEncoder;
+use image::{
+    ColorType, Delay, DynamicImage, Frame, GrayImage, ImageBuffer, ImageResult, Luma, Pixel, Rgb,
+    RgbImage, Rgba, RgbaImage,
+};
 use kd_tree::KdMap;
-use log::info;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 use std::fs::File;
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 
 #[cfg(feature = "rayon")]
 use std::sync::Mutex;
 #[cfg(feature = "rayon")]
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    #[default]
+    Rgb,
+    Lab,
+}
+
+impl ColorSpace {
+    pub fn convert(&self, color: &[u8; 3]) -> [f32; 3] {
+        return match self {
+            ColorSpace::Rgb => normalize_color(color),
+            ColorSpace::Lab => rgb_to_lab(color),
+        };
+    }
+
+    pub fn to_rgb(&self, color: &[f32; 3]) -> [u8; 3] {
+        return match self {
+            ColorSpace::Rgb => denormalize_color(color),
+            ColorSpace::Lab => lab_to_rgb(color),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct GradientStop {
+    at: f32,
+    rgb: [u8; 3],
+}
+
+// Restricts which side of a glyph a palette color may be drawn on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorRole {
+    FgOnly,
+    BgOnly,
+    #[default]
+    Both,
+}
+
+impl ColorRole {
+    pub fn allows_foreground(&self) -> bool {
+        return *self != ColorRole::BgOnly;
+    }
+
+    pub fn allows_background(&self) -> bool {
+        return *self != ColorRole::FgOnly;
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PaletteEntry {
+    Rgb([u8; 3]),
+    WithRole {
+        rgb: [u8; 3],
+        #[serde(default)]
+        role: ColorRole,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PaletteDefinition {
+    Colors {
+        colors: Vec<PaletteEntry>,
+    },
+    Gradient {
+        gradient: Vec<GradientStop>,
+        steps: usize,
+        #[serde(default)]
+        space: ColorSpace,
+    },
+    Split {
+        foreground: Vec<[u8; 3]>,
+        background: Vec<[u8; 3]>,
+    },
+}
+
+impl TryFrom<PaletteDefinition> for Palette {
+    type Error = String;
+
+    fn try_from(definition: PaletteDefinition) -> Result<Palette, String> {
+        return match definition {
+            PaletteDefinition::Colors { colors } => {
+                let (colors, roles) = colors
+                    .into_iter()
+                    .map(|entry| match entry {
+                        PaletteEntry::Rgb(rgb) => (rgb, ColorRole::Both),
+                        PaletteEntry::WithRole { rgb, role } => (rgb, role),
+                    })
+                    .unzip();
+                Ok(Palette { colors, roles })
+            }
+            PaletteDefinition::Gradient {
+                gradient,
+                steps,
+                space,
+            } => Ok(Palette::new(expand_gradient(gradient, steps, space)?)),
+            PaletteDefinition::Split {
+                foreground,
+                background,
+            } => Ok(Palette::split(foreground, background)),
+        };
+    }
+}
+
+// Colors that can be on either side are written as a bare RGB triple, the same
+// as a palette without roles
+impl From<Palette> for PaletteDefinition {
+    fn from(palette: Palette) -> PaletteDefinition {
+        let colors = palette
+            .colors
+            .into_iter()
+            .zip(palette.roles)
+            .map(|(rgb, role)| match role {
+                ColorRole::Both => PaletteEntry::Rgb(rgb),
+                role => PaletteEntry::WithRole { rgb, role },
+            })
+            .collect();
+        return PaletteDefinition::Colors { colors };
+    }
+}
+
+fn parse_gpl(text: &str) -> Result<Vec<[u8; 3]>, String> {
+    let mut lines = text.lines().enumerate();
+
+    match lines.next() {
+        Some((_, header)) if header.trim() == "GIMP Palette" => {}
+        _ => return Err(String::from("GIMP palette must start with `GIMP Palette`")),
+    }
+
+    let mut colors = Vec::new();
+    for (i, line) in lines {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+
+        // Each row is `R G B` followed by an optional name that may contain spaces
+        let mut fields = line.split_whitespace();
+        let mut channel = |name: &str| -> Result<u8, String> {
+            let field = fields
+                .next()
+                .ok_or_else(|| format!("line {}: missing {} value in `{}`", i + 1, name, line))?;
+            return field.parse::<u8>().map_err(|_| {
+                format!(
+                    "line {}: {} value `{}` is not a number from 0 to 255",
+                    i + 1,
+                    name,
+                    field
+                )
+            });
+        };
+        colors.push([channel("red")?, channel("green")?, channel("blue")?]);
+    }
+
+    return Ok(colors);
+}
+
+fn parse_hex_color(hex: &str) -> Option<[u8; 3]> {
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    return Some([channel(0), channel(2), channel(4)]);
+}
+
+fn parse_hex_list(text: &str) -> Result<Vec<[u8; 3]>, String> {
+    let mut colors = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let token = match line.split_whitespace().next() {
+            Some(token) => token,
+            None => continue,
+        };
+        let hex = token.strip_prefix('#').unwrap_or(token);
+
+        match parse_hex_color(hex) {
+            Some(color) => colors.push(color),
+            // A `#` line that isn't a color is a comment
+            None if token.starts_with('#') => continue,
+            None => {
+                return Err(format!(
+                    "line {}: `{}` is not a hex color like #RRGGBB",
+                    i + 1,
+                    token
+                ))
+            }
+        }
+    }
+
+    return Ok(colors);
+}
+
+fn parse_jasc_pal(text: &str) -> Result<Vec<[u8; 3]>, String> {
+    let mut lines = text.lines().map(str::trim).enumerate();
+
+    // The magic has already been checked, next are the version and the color count
+    lines.next();
+    match lines.next() {
+        Some((_, "0100")) => {}
+        Some((_, version)) => return Err(format!("unsupported JASC-PAL version `{}`", version)),
+        None => return Err(String::from("JASC-PAL is missing its version line")),
+    }
+    let count = match lines.next() {
+        Some((_, count)) => count
+            .parse::<usize>()
+            .map_err(|_| format!("JASC-PAL color count `{}` is not a number", count))?,
+        None => return Err(String::from("JASC-PAL is missing its color count line")),
+    };
+
+    let mut colors = Vec::with_capacity(count);
+    for (i, line) in lines.filter(|(_, line)| !line.is_empty()) {
+        let channels: Vec<u8> = line
+            .split_whitespace()
+            .map(|field| field.parse::<u8>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| format!("line {}: `{}` is not an R G B triple", i + 1, line))?;
+        match channels[..] {
+            [r, g, b] => colors.push([r, g, b]),
+            _ => return Err(format!("line {}: `{}` is not an R G B triple", i + 1, line)),
+        }
+    }
+
+    if colors.len() != count {
+        return Err(format!(
+            "JASC-PAL declares {} colors but contains {}",
+            count,
+            colors.len()
+        ));
+    }
+
+    return Ok(colors);
+}
+
+// Adobe Swatch Exchange: `ASEF`, a version, a block count, then group start, group
+// end, and color blocks. Groups are flattened, and CMYK, Lab, and gray swatches
+// are converted to RGB.
+fn parse_ase(bytes: &[u8]) -> Result<Vec<[u8; 3]>, String> {
+    let read = |offset: usize, len: usize| -> Result<&[u8], String> {
+        return bytes
+            .get(offset..offset + len)
+            .ok_or_else(|| format!("ASE file ends early at byte {}", bytes.len()));
+    };
+    let read_u16 = |offset: usize| -> Result<u16, String> {
+        return Ok(u16::from_be_bytes(read(offset, 2)?.try_into().unwrap()));
+    };
+    let read_u32 = |offset: usize| -> Result<u32, String> {
+        return Ok(u32::from_be_bytes(read(offset, 4)?.try_into().unwrap()));
+    };
+    let read_f32 = |offset: usize| -> Result<f32, String> {
+        return Ok(f32::from_be_bytes(read(offset, 4)?.try_into().unwrap()));
+    };
+
+    if !bytes.starts_with(b"ASEF") {
+        return Err(String::from("ASE file must start with `ASEF`"));
+    }
+    let major = read_u16(4)?;
+    if major != 1 {
+        return Err(format!("unsupported ASE version {}", major));
+    }
+    let block_count = read_u32(8)?;
+
+    let mut colors = Vec::new();
+    let mut offset = 12;
+    for _ in 0..block_count {
+        let block_type = read_u16(offset)?;
+        let block_length = read_u32(offset + 2)? as usize;
+        let block_start = offset + 6;
+        read(block_start, block_length)?;
+        offset = block_start + block_length;
+
+        if block_type != 0x0001 {
+            continue;
+        }
+
+        // The name is UTF-16 with its length in code units first
+        let name_length = read_u16(block_start)? as usize;
+        let model_start = block_start + 2 + name_length * 2;
+        let model = read(model_start, 4)?;
+        let value = |i: usize| read_f32(model_start + 4 + i * 4);
+        let channel = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+        let color = match model {
+            b"RGB " => [channel(value(0)?), channel(value(1)?), channel(value(2)?)],
+            b"Gray" => [channel(value(0)?); 3],
+            b"CMYK" => {
+                let k = 1.0 - value(3)?;
+                [
+                    channel((1.0 - value(0)?) * k),
+                    channel((1.0 - value(1)?) * k),
+                    channel((1.0 - value(2)?) * k),
+                ]
+            }
+            // Lightness is stored as 0 to 1 rather than 0 to 100
+            b"LAB " => lab_to_rgb(&[value(0)? * 100.0, value(1)?, value(2)?]),
+            _ => {
+                return Err(format!(
+                    "ASE swatch at byte {} has unknown color model `{}`",
+                    block_start - 6,
+                    String::from_utf8_lossy(model)
+                ))
+            }
+        };
+        colors.push(color);
+    }
+
+    return Ok(colors);
+}
+
+fn expand_gradient(
+    mut stops: Vec<GradientStop>,
+    steps: usize,
+    space: ColorSpace,
+) -> Result<Vec<[u8; 3]>, String> {
+    if stops.is_empty() {
+        return Err(String::from("gradient must have at least one stop"));
+    }
+    if steps == 0 {
+        return Err(String::from("gradient must have at least one step"));
+    }
+    if steps > MAX_PALETTE_COLORS {
+        return Err(format!(
+            "gradient has {} steps, but a palette holds at most {} colors",
+            steps, MAX_PALETTE_COLORS
+        ));
+    }
+    if stops.iter().any(|stop| !stop.at.is_finite()) {
+        return Err(String::from("gradient stop positions must be finite"));
+    }
+
+    stops.sort_by(|a, b| a.at.total_cmp(&b.at));
+
+    let start = stops[0].at;
+    let end = stops[stops.len() - 1].at;
+
+    let mut colors = Vec::with_capacity(steps);
+    for i in 0..steps {
+        let at = if steps == 1 {
+            start
+        } else {
+            start + (end - start) * i as f32 / (steps - 1) as f32
+        };
+
+        let next = stops
+            .iter()
+            .position(|stop| stop.at >= at)
+            .unwrap_or(stops.len() - 1);
+        let previous = next.saturating_sub(1);
+
+        let (from, to) = (&stops[previous], &stops[next]);
+        let ratio = if to.at > from.at {
+            (at - from.at) / (to.at - from.at)
+        } else {
+            1.0
+        };
+
+        colors.push(space.to_rgb(&blend_two_colors(
+            &space.convert(&to.rgb),
+            &space.convert(&from.rgb),
+            ratio,
+        )));
+    }
+
+    return Ok(colors);
+}
+
+const PICO_8_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0], [29, 43, 83], [126, 37, 83], [0, 135, 81],
+    [171, 82, 54], [95, 87, 79], [194, 195, 199], [255, 241, 232],
+    [255, 0, 77], [255, 163, 0], [255, 236, 39], [0, 228, 54],
+    [41, 173, 255], [131, 118, 156], [255, 119, 168], [255, 204, 170],
+];
+
+// The 2C02 PPU palette with its repeated blacks removed
+const NES_COLORS: [[u8; 3]; 55] = [
+    [124, 124, 124], [0, 0, 252], [0, 0, 188], [68, 40, 188],
+    [148, 0, 132], [168, 0, 32], [168, 16, 0], [136, 20, 0],
+    [80, 48, 0], [0, 120, 0], [0, 104, 0], [0, 88, 0],
+    [0, 64, 88], [0, 0, 0], [188, 188, 188], [0, 120, 248],
+    [0, 88, 248], [104, 68, 252], [216, 0, 204], [228, 0, 88],
+    [248, 56, 0], [228, 92, 16], [172, 124, 0], [0, 184, 0],
+    [0, 168, 0], [0, 168, 68], [0, 136, 136], [248, 248, 248],
+    [60, 188, 252], [104, 136, 252], [152, 120, 248], [248, 120, 248],
+    [248, 88, 152], [248, 120, 88], [252, 160, 68], [248, 184, 0],
+    [184, 248, 24], [88, 216, 84], [88, 248, 152], [0, 232, 216],
+    [120, 120, 120], [252, 252, 252], [164, 228, 252], [184, 184, 248],
+    [216, 184, 248], [248, 184, 248], [248, 164, 192], [240, 208, 176],
+    [252, 224, 168], [248, 216, 120], [216, 248, 120], [184, 248, 184],
+    [184, 248, 216], [0, 252, 252], [248, 216, 248],
+];
+
+// Darkest to lightest
+const GAME_BOY_COLORS: [[u8; 3]; 4] = [
+    [15, 56, 15], [48, 98, 48], [139, 172, 15], [155, 188, 15],
+];
+
+const CGA_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0], [0, 0, 170], [0, 170, 0], [0, 170, 170],
+    [170, 0, 0], [170, 0, 170], [170, 85, 0], [170, 170, 170],
+    [85, 85, 85], [85, 85, 255], [85, 255, 85], [85, 255, 255],
+    [255, 85, 85], [255, 85, 255], [255, 255, 85], [255, 255, 255],
+];
+
+// In ANSI order rather than the BIOS order CGA uses, so the indices line up with
+// the terminal's first 16 colors
+const VGA_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0], [170, 0, 0], [0, 170, 0], [170, 85, 0],
+    [0, 0, 170], [170, 0, 170], [0, 170, 170], [170, 170, 170],
+    [85, 85, 85], [255, 85, 85], [85, 255, 85], [255, 255, 85],
+    [85, 85, 255], [255, 85, 255], [85, 255, 255], [255, 255, 255],
+];
+
+// xterm's default system colors
+const XTERM_SYSTEM_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0], [205, 0, 0], [0, 205, 0], [205, 205, 0],
+    [0, 0, 238], [205, 0, 205], [0, 205, 205], [229, 229, 229],
+    [127, 127, 127], [255, 0, 0], [0, 255, 0], [255, 255, 0],
+    [92, 92, 255], [255, 0, 255], [0, 255, 255], [255, 255, 255],
+];
+
+const XTERM_CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// Pepto's measured VIC-II colors
+const C64_COLORS: [[u8; 3]; 16] = [
+    [0, 0, 0], [255, 255, 255], [104, 55, 43], [112, 164, 178],
+    [111, 61, 134], [88, 141, 67], [53, 40, 121], [184, 199, 111],
+    [111, 79, 37], [67, 57, 0], [154, 103, 89], [68, 68, 68],
+    [108, 108, 108], [154, 210, 132], [108, 94, 181], [149, 149, 149],
+];
+
+// mIRC's color codes, the 16 standard colors followed by the extended ones
+const MIRC_COLORS: [[u8; 3]; 99] = [
+    [255, 255, 255], [0, 0, 0], [0, 0, 127], [0, 147, 0],
+    [255, 0, 0], [127, 0, 0], [156, 0, 156], [252, 127, 0],
+    [255, 255, 0], [0, 252, 0], [0, 147, 147], [0, 255, 255],
+    [0, 0, 252], [255, 0, 255], [127, 127, 127], [210, 210, 210],
+    [71, 0, 0], [71, 33, 0], [71, 71, 0], [50, 71, 0],
+    [0, 71, 0], [0, 71, 44], [0, 71, 71], [0, 39, 71],
+    [0, 0, 71], [46, 0, 71], [71, 0, 71], [71, 0, 42],
+    [116, 0, 0], [116, 58, 0], [116, 116, 0], [81, 116, 0],
+    [0, 116, 0], [0, 116, 73], [0, 116, 116], [0, 64, 116],
+    [0, 0, 116], [75, 0, 116], [116, 0, 116], [116, 0, 69],
+    [181, 0, 0], [181, 99, 0], [181, 181, 0], [125, 181, 0],
+    [0, 181, 0], [0, 181, 113], [0, 181, 181], [0, 99, 181],
+    [0, 0, 181], [117, 0, 181], [181, 0, 181], [181, 0, 107],
+    [255, 0, 0], [255, 140, 0], [255, 255, 0], [178, 255, 0],
+    [0, 255, 0], [0, 255, 160], [0, 255, 255], [0, 140, 255],
+    [0, 0, 255], [165, 0, 255], [255, 0, 255], [255, 0, 152],
+    [255, 89, 89], [255, 180, 89], [255, 255, 113], [207, 255, 96],
+    [111, 255, 111], [101, 255, 201], [109, 255, 255], [89, 180, 255],
+    [89, 89, 255], [196, 89, 255], [255, 102, 255], [255, 89, 188],
+    [255, 156, 156], [255, 211, 156], [255, 255, 156], [226, 255, 156],
+    [156, 255, 156], [156, 255, 219], [156, 255, 255], [156, 211, 255],
+    [156, 156, 255], [220, 156, 255], [255, 156, 255], [255, 148, 211],
+    [0, 0, 0], [19, 19, 19], [40, 40, 40], [54, 54, 54],
+    [77, 77, 77], [101, 101, 101], [129, 129, 129], [159, 159, 159],
+    [188, 188, 188], [226, 226, 226], [255, 255, 255],
+];
+
+// Indices match the terminal's 256-color table: the 16 system colors, a 6x6x6
+// color cube, then a 24 step gray ramp
+fn xterm_256_colors() -> Vec<[u8; 3]> {
+    let mut colors = XTERM_SYSTEM_COLORS.to_vec();
+    for r in XTERM_CUBE_LEVELS {
+        for g in XTERM_CUBE_LEVELS {
+            for b in XTERM_CUBE_LEVELS {
+                colors.push([r, g, b]);
+            }
+        }
+    }
+    for i in 0..24 {
+        let gray = 8 + i * 10;
+        colors.push([gray, gray, gray]);
+    }
+
+    return colors;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PalettePreset {
+    Pico8,
+    Nes,
+    GameBoy,
+    Cga,
+    Vga,
+    Xterm256,
+    C64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PaletteBuilder {
+    colors: Vec<[u8; 3]>,
+}
+
+impl PaletteBuilder {
+    pub fn new() -> PaletteBuilder {
+        return PaletteBuilder::default();
+    }
+
+    pub fn push_color(mut self, color: [u8; 3]) -> PaletteBuilder {
+        self.colors.push(color);
+        return self;
+    }
+
+    // `#RRGGBB` or `RRGGBB`
+    pub fn push_hex(self, hex: &str) -> Result<PaletteBuilder, Box<dyn std::error::Error>> {
+        let color = parse_hex_color(hex.strip_prefix('#').unwrap_or(hex))
+            .ok_or_else(|| format!("expected #RRGGBB, got `{}`", hex))?;
+        return Ok(self.push_color(color));
+    }
+
+    pub fn build(self) -> Palette {
+        return Palette::new(self.colors);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "PaletteDefinition", into = "PaletteDefinition")]
 pub struct Palette {
     colors: Vec<[u8; 3]>,
+    roles: Vec<ColorRole>,
+}
+
+impl Palette {
+    pub fn new(colors: Vec<[u8; 3]>) -> Palette {
+        let roles = vec![ColorRole::Both; colors.len()];
+        return Palette { colors, roles };
+    }
+
+    // Separate text and background ramps, like a terminal theme. A color in both
+    // lists can be on either side, the rest only on the side of their list.
+    pub fn split(foreground: Vec<[u8; 3]>, background: Vec<[u8; 3]>) -> Palette {
+        let mut colors = Vec::new();
+        let mut roles = Vec::new();
+        for color in foreground.iter() {
+            if colors.contains(color) {
+                continue;
+            }
+            colors.push(*color);
+            roles.push(if background.contains(color) {
+                ColorRole::Both
+            } else {
+                ColorRole::FgOnly
+            });
+        }
+        for color in background.iter() {
+            if colors.contains(color) {
+                continue;
+            }
+            colors.push(*color);
+            roles.push(ColorRole::BgOnly);
+        }
+
+        return Palette { colors, roles };
+    }
+
+    pub fn preset(preset: PalettePreset) -> Palette {
+        let colors = match preset {
+            PalettePreset::Pico8 => PICO_8_COLORS.to_vec(),
+            PalettePreset::Nes => NES_COLORS.to_vec(),
+            PalettePreset::GameBoy => GAME_BOY_COLORS.to_vec(),
+            PalettePreset::Cga => CGA_COLORS.to_vec(),
+            PalettePreset::Vga => VGA_COLORS.to_vec(),
+            PalettePreset::Xterm256 => xterm_256_colors(),
+            PalettePreset::C64 => C64_COLORS.to_vec(),
+        };
+
+        return Palette::new(colors);
+    }
+
+    pub fn vga_16() -> Palette {
+        return Palette::preset(PalettePreset::Vga);
+    }
+
+    // The `Fixed` codes in the text output are indices into the terminal's own
+    // color table, so this palette makes them show the colors that were matched
+    pub fn xterm_256() -> Palette {
+        return Palette::preset(PalettePreset::Xterm256);
+    }
+
+    pub fn c64() -> Palette {
+        return Palette::preset(PalettePreset::C64);
+    }
+
+    pub fn colors(&self) -> &[[u8; 3]] {
+        return &self.colors;
+    }
+
+    pub fn roles(&self) -> &[ColorRole] {
+        return &self.roles;
+    }
+
+    pub fn foreground_colors(&self) -> Vec<[u8; 3]> {
+        return self
+            .colors
+            .iter()
+            .zip(self.roles.iter())
+            .filter(|(_, role)| role.allows_foreground())
+            .map(|(color, _)| *color)
+            .collect();
+    }
+
+    pub fn background_colors(&self) -> Vec<[u8; 3]> {
+        return self
+            .colors
+            .iter()
+            .zip(self.roles.iter())
+            .filter(|(_, role)| role.allows_background())
+            .map(|(color, _)| *color)
+            .collect();
+    }
+
+    pub fn from(path: PathBuf) -> Result<Palette, Box<dyn std::error::Error>> {
+        info!("Opening and parsing palette");
+
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        let bytes = std::fs::read(&path)?;
+        if bytes.starts_with(b"ASEF") {
+            return Palette::new(parse_ase(&bytes)?).validated();
+        }
+
+        let text = String::from_utf8(bytes)?;
+        if extension.as_deref() == Some("gpl") {
+            return Palette::new(parse_gpl(&text)?).validated();
+        } else if extension.as_deref() == Some("hex") {
+            return Palette::new(parse_hex_list(&text)?).validated();
+        } else if text.starts_with("JASC-PAL") {
+            return Palette::new(parse_jasc_pal(&text)?).validated();
+        }
+
+        return Palette::from_yaml_str(&text);
+    }
+
+    pub fn from_yaml_str(text: &str) -> Result<Palette, Box<dyn std::error::Error>> {
+        let palette: Palette = serde_yaml::from_str(text)?;
+        return palette.validated();
+    }
+
+    // Duplicates are only reported, not removed, since palette indices double as
+    // terminal color codes and a palette like 256.yaml repeats colors on purpose
+    fn validated(self) -> Result<Palette, Box<dyn std::error::Error>> {
+        if self.colors.is_empty() {
+            return Err("Palette has no colors".into());
+        }
+
+        let duplicates: Vec<String> = self
+            .colors
+            .iter()
+            .enumerate()
+            .filter(|(i, color)| self.colors[..*i].contains(color))
+            .map(|(i, color)| format!("{} {:?}", i, color))
+            .collect();
+        if !duplicates.is_empty() {
+            warn!("Palette repeats colors at {}", duplicates.join(", "));
+        }
+
+        return Ok(self);
+    }
+
+    // Removes exact repeats of earlier colors, keeping the first of each in order
+    pub fn dedup(&self) -> Palette {
+        let mut colors = Vec::new();
+        let mut roles = Vec::new();
+        for (color, role) in self.colors.iter().zip(self.roles.iter()) {
+            if colors.contains(color) {
+                continue;
+            }
+            colors.push(*color);
+            roles.push(*role);
+        }
+
+        return Palette { colors, roles };
+    }
+
+    pub fn to_file(&self, path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Writing palette");
+
+        let file = File::create(path)?;
+        serde_yaml::to_writer(&file, self)?;
+
+        return Ok(());
+    }
+
+    // Swatches exported from Adobe tools, in whatever color model they were saved in
+    pub fn from_ase(path: PathBuf) -> Result<Palette, Box<dyn std::error::Error>> {
+        info!("Opening and parsing ASE palette");
+
+        let bytes = std::fs::read(path)?;
+        return Palette::new(parse_ase(&bytes)?).validated();
+    }
+
+    // One `#RRGGBB` or `RRGGBB` per line, optionally followed by a comment
+    pub fn from_hex_list(path: PathBuf) -> Result<Palette, Box<dyn std::error::Error>> {
+        info!("Opening and parsing hex palette");
+
+        let text = std::fs::read_to_string(path)?;
+        return Palette::new(parse_hex_list(&text)?).validated();
+    }
+
+    pub fn from_image(img: &RgbImage, max_colors: usize, seed: u64) -> Palette {
+        info!("Deriving palette from image");
+
+        let samples = sample_colors(img);
+
+        if samples.is_empty() || max_colors == 0 {
+            return Palette::new(Vec::new());
+        }
+
+        let mut rng = Rng::new(seed);
+
+        info!("Seeding clusters");
+
+        // k-means++ seeding
+        let mut centroids = vec![samples[rng.next_below(samples.len())]];
+        while centroids.len() < max_colors {
+            let weights: Vec<f32> = samples
+                .iter()
+                .map(|sample| {
+                    centroids
+                        .iter()
+                        .map(|centroid| color_distance(sample, centroid).powi(2))
+                        .fold(f32::INFINITY, f32::min)
+                })
+                .collect();
+            let total: f32 = weights.iter().sum();
+            if total <= 0.0 {
+                break;
+            }
+
+            let mut target = rng.next_f32() * total;
+            let mut chosen = samples.len() - 1;
+            for (i, weight) in weights.iter().enumerate() {
+                if target < *weight {
+                    chosen = i;
+                    break;
+                }
+                target -= weight;
+            }
+            centroids.push(samples[chosen]);
+        }
+
+        refine_centroids(&samples, &mut centroids);
+
+        let mut colors: Vec<[u8; 3]> = Vec::new();
+        for centroid in centroids.iter() {
+            let color = denormalize_color(centroid);
+            if !colors.contains(&color) {
+                colors.push(color);
+            }
+        }
+
+        return Palette::new(colors);
+    }
+
+    // Derives a palette for the next frame of a video by clustering from the
+    // previous frame's colors instead of fresh seeds. Each color may move at most
+    // max_drift (in normalized RGB) from where it was, so palettes change slowly
+    // and the color count and order stay the same from frame to frame.
+    pub fn from_image_stabilized(img: &RgbImage, previous: &Palette, max_drift: f32) -> Palette {
+        info!("Deriving stabilized palette from image");
+
+        let samples = sample_colors(img);
+        if samples.is_empty() {
+            return previous.clone();
+        }
+
+        let anchors: Vec<[f32; 3]> = previous.colors.iter().map(normalize_color).collect();
+        let mut centroids = anchors.clone();
+        refine_centroids(&samples, &mut centroids);
+
+        let colors = centroids
+            .iter()
+            .zip(anchors.iter())
+            .map(|(centroid, anchor)| {
+                let drift = color_distance(centroid, anchor);
+                if drift <= max_drift {
+                    denormalize_color(centroid)
+                } else {
+                    denormalize_color(&blend_two_colors(centroid, anchor, max_drift / drift))
+                }
+            })
+            .collect();
+
+        return Palette {
+            colors,
+            roles: previous.roles.clone(),
+        };
+    }
+
+    pub fn nearest_index(&self, color: &[u8; 3]) -> usize {
+        let color = normalize_color(color);
+        return self
+            .colors
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                color_distance(&normalize_color(a), &color)
+                    .total_cmp(&color_distance(&normalize_color(b), &color))
+            })
+            .map_or(0, |(i, _)| i);
+    }
+
+    pub fn prune_similar(&self, min_distance: f32, space: ColorSpace) -> Palette {
+        info!("Pruning similar palette colors");
+
+        let mut kept: Vec<([u8; 3], ColorRole, [f32; 3])> = Vec::new();
+        for (color, role) in self.colors.iter().zip(self.roles.iter()) {
+            let converted = space.convert(color);
+            if kept
+                .iter()
+                .all(|(_, _, other)| color_distance(&converted, other) >= min_distance)
+            {
+                kept.push((*color, *role, converted));
+            }
+        }
+
+        info!(
+            "Pruned {} of {} palette colors",
+            self.colors.len() - kept.len(),
+            self.colors.len()
+        );
+
+        return Palette {
+            colors: kept.iter().map(|(color, _, _)| *color).collect(),
+            roles: kept.iter().map(|(_, role, _)| *role).collect(),
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum BitmapDefinition {
+    Bitmap(Vec<Vec<bool>>),
+    Rows(Vec<String>),
+    Mask(u64),
+}
+
+#[derive(Serialize, Deserialize)]
+struct BlocksDefinition {
+    width: u32,
+    height: u32,
+    blocks: BTreeMap<char, BitmapDefinition>,
+}
+
+fn decode_bitmap_row(row: &str) -> Result<Vec<bool>, String> {
+    return row
+        .chars()
+        .map(|pixel| match pixel {
+            '1' | '#' | 'X' | 'x' | '█' | '▓' => Ok(true),
+            '0' | '.' | ' ' | '-' | '░' => Ok(false),
+            other => Err(format!("invalid bitmap pixel `{}` in row `{}`", other, row)),
+        })
+        .collect();
+}
+
+impl TryFrom<BlocksDefinition> for Blocks {
+    type Error = String;
+
+    fn try_from(definition: BlocksDefinition) -> Result<Blocks, String> {
+        let mut blocks = BTreeMap::new();
+        for (character, bitmap) in definition.blocks {
+            let bitmap = match bitmap {
+                BitmapDefinition::Bitmap(bitmap) => bitmap,
+                BitmapDefinition::Rows(rows) => rows
+                    .iter()
+                    .map(|row| decode_bitmap_row(row))
+                    .collect::<Result<_, _>>()?,
+                BitmapDefinition::Mask(mask) => {
+                    // Same bit order as the packed bitmaps in the LUT map
+                    let area = definition.width as u64 * definition.height as u64;
+                    if area > MAX_LUT_BLOCK_AREA as u64 {
+                        return Err(format!(
+                            "bitmask for `{}` cannot hold {}x{} pixels",
+                            character, definition.width, definition.height
+                        ));
+                    }
+                    (0..definition.height)
+                        .map(|y| {
+                            (0..definition.width)
+                                .map(|x| (mask >> (x + y * definition.width)) & 1 == 1)
+                                .collect()
+                        })
+                        .collect()
+                }
+            };
+            blocks.insert(character, bitmap);
+        }
+
+        return Ok(Blocks {
+            width: definition.width,
+            height: definition.height,
+            blocks,
+        });
+    }
 }
 
-impl Palette {
-    pub fn from(path: PathBuf) -> Result<Palette, Box<dyn std::error::Error>> {
-        info!("Opening and parsing palette");
+// Bitmaps are written as rows of `1` and `0`, the most readable of the formats
+// BlocksDefinition reads
+impl From<Blocks> for BlocksDefinition {
+    fn from(blocks: Blocks) -> BlocksDefinition {
+        let bitmaps = blocks
+            .blocks
+            .into_iter()
+            .map(|(character, bitmap)| {
+                let rows = bitmap
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|pixel| if *pixel { '1' } else { '0' })
+                            .collect()
+                    })
+                    .collect();
+                (character, BitmapDefinition::Rows(rows))
+            })
+            .collect();
+        return BlocksDefinition {
+            width: blocks.width,
+            height: blocks.height,
+            blocks: bitmaps,
+        };
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlocksError {
+    EmptyCell {
+        width: u32,
+        height: u32,
+    },
+    NoBlocks,
+    RowCountMismatch {
+        character: char,
+        expected: usize,
+        found: usize,
+    },
+    RowWidthMismatch {
+        character: char,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for BlocksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        return match self {
+            BlocksError::EmptyCell { width, height } => {
+                write!(f, "{}x{} blocks have no pixels", width, height)
+            }
+            BlocksError::NoBlocks => write!(f, "no blocks are defined"),
+            BlocksError::RowCountMismatch {
+                character,
+                expected,
+                found,
+            } => write!(
+                f,
+                "bitmap for `{}` has {} rows, expected {}",
+                character, found, expected
+            ),
+            BlocksError::RowWidthMismatch {
+                character,
+                expected,
+                found,
+            } => write!(
+                f,
+                "bitmap for `{}` has a row {} pixels wide, expected {}",
+                character, found, expected
+            ),
+        };
+    }
+}
+
+impl std::error::Error for BlocksError {}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "BlocksDefinition", into = "BlocksDefinition")]
+pub struct Blocks {
+    width: u32,
+    height: u32,
+    blocks: BTreeMap<char, Vec<Vec<bool>>>,
+}
+
+impl Blocks {
+    pub fn from(path: PathBuf) -> Result<Blocks, Box<dyn std::error::Error>> {
+        info!("Opening blocks");
+
+        return Blocks::from_yaml_str(&std::fs::read_to_string(path)?);
+    }
+
+    pub fn from_yaml_str(text: &str) -> Result<Blocks, Box<dyn std::error::Error>> {
+        info!("Parsing blocks");
+
+        let blocks: Blocks = serde_yaml::from_str(text)?;
+        return Ok(Blocks::new(blocks.width, blocks.height, blocks.blocks)?);
+    }
+
+    // Checked the same way as blocks loaded with from
+    pub fn new(
+        width: u32,
+        height: u32,
+        blocks: BTreeMap<char, Vec<Vec<bool>>>,
+    ) -> Result<Blocks, BlocksError> {
+        let blocks = Blocks {
+            width,
+            height,
+            blocks,
+        };
+        blocks.verify_dimensions()?;
+
+        let redundant: Vec<String> = blocks
+            .redundant_solid_glyphs()
+            .iter()
+            .map(|character| format!("`{}`", character))
+            .collect();
+        if !redundant.is_empty() {
+            warn!(
+                "Blocks repeat a solid glyph, {} can never be chosen",
+                redundant.join(", ")
+            );
+        }
+
+        return Ok(blocks);
+    }
+
+    // Glyphs that are entirely set or entirely clear after the first of each,
+    // only one of each is ever needed to draw a solid color
+    pub fn redundant_solid_glyphs(&self) -> Vec<char> {
+        let mut full = false;
+        let mut empty = false;
+        let mut redundant = Vec::new();
+
+        for (character, bitmap) in self.blocks.iter() {
+            let seen = if bitmap.iter().flatten().all(|pixel| *pixel) {
+                &mut full
+            } else if bitmap.iter().flatten().all(|pixel| !*pixel) {
+                &mut empty
+            } else {
+                continue;
+            };
+
+            if *seen {
+                redundant.push(*character);
+            }
+            *seen = true;
+        }
+
+        return redundant;
+    }
+
+    fn verify_dimensions(&self) -> Result<(), BlocksError> {
+        info!("Verifying block dimensions");
+
+        if self.width == 0 || self.height == 0 {
+            return Err(BlocksError::EmptyCell {
+                width: self.width,
+                height: self.height,
+            });
+        }
+        if self.blocks.is_empty() {
+            return Err(BlocksError::NoBlocks);
+        }
+
+        for (character, bitmap) in self.blocks.iter() {
+            if bitmap.len() != self.height as usize {
+                return Err(BlocksError::RowCountMismatch {
+                    character: *character,
+                    expected: self.height as usize,
+                    found: bitmap.len(),
+                });
+            }
+            for row in bitmap {
+                if row.len() != self.width as usize {
+                    return Err(BlocksError::RowWidthMismatch {
+                        character: *character,
+                        expected: self.width as usize,
+                        found: row.len(),
+                    });
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Each glyph is scaled so its advance fills the cell's width and the font's
+    // ascent to descent fills its height, the way a terminal lays out a
+    // monospace font. A pixel is set when the glyph covers at least half of it.
+    #[cfg(feature = "font")]
+    pub fn from_font(
+        path: PathBuf,
+        chars: &str,
+        width: u32,
+        height: u32,
+    ) -> Result<Blocks, Box<dyn std::error::Error>> {
+        use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+
+        info!("Rasterizing blocks from font");
+
+        if width == 0 || height == 0 {
+            return Err(BlocksError::EmptyCell { width, height }.into());
+        }
+        let font = FontVec::try_from_vec(std::fs::read(path)?)?;
+
+        let mut blocks = BTreeMap::new();
+        for character in chars.chars() {
+            let glyph_id = font.glyph_id(character);
+            if glyph_id.0 == 0 {
+                return Err(format!("font has no glyph for `{}`", character).into());
+            }
+
+            let advance = font.h_advance_unscaled(glyph_id).max(1.0);
+            let scale = PxScale {
+                x: width as f32 * font.height_unscaled() / advance,
+                y: height as f32,
+            };
+            let ascent = font.as_scaled(scale).ascent();
+
+            let mut bitmap = vec![vec![false; width as usize]; height as usize];
+            let glyph = glyph_id.with_scale_and_position(scale, ab_glyph::point(0.0, ascent));
+            if let Some(outline) = font.outline_glyph(glyph) {
+                let bounds = outline.px_bounds();
+                outline.draw(|x, y, coverage| {
+                    let x = bounds.min.x as i32 + x as i32;
+                    let y = bounds.min.y as i32 + y as i32;
+                    if coverage >= 0.5
+                        && (0..width as i32).contains(&x)
+                        && (0..height as i32).contains(&y)
+                    {
+                        bitmap[y as usize][x as usize] = true;
+                    }
+                });
+            }
+            blocks.insert(character, bitmap);
+        }
+
+        let blocks = Blocks {
+            width,
+            height,
+            blocks,
+        };
+        blocks.verify_dimensions()?;
+
+        return Ok(blocks);
+    }
+
+    // Cells are read left to right, top to bottom, one per character in `chars`.
+    // Light pixels are set and dark pixels are clear, matching the white on
+    // black bitmaps in the rendered output.
+    pub fn from_sprite_sheet(
+        path: PathBuf,
+        cell_width: u32,
+        cell_height: u32,
+        chars: &str,
+    ) -> Result<Blocks, Box<dyn std::error::Error>> {
+        info!("Opening and slicing sprite sheet");
+
+        let sheet = image::open(path)?.into_luma8();
+
+        let mut blocks = BTreeMap::new();
+        for (character, (left, top)) in sprite_cells(&sheet, cell_width, cell_height, chars)? {
+            let bitmap = (0..cell_height)
+                .map(|y| {
+                    (0..cell_width)
+                        .map(|x| sheet.get_pixel(left + x, top + y)[0] >= 128)
+                        .collect()
+                })
+                .collect();
+            blocks.insert(character, bitmap);
+        }
+
+        return Ok(Blocks {
+            width: cell_width,
+            height: cell_height,
+            blocks,
+        });
+    }
+
+    pub fn from_trusted(path: PathBuf) -> Result<Blocks, Box<dyn std::error::Error>> {
+        info!("Opening and parsing blocks");
+
+        let file2 = File::open(path)?;
+        return Ok(serde_yaml::from_reader(&file2)?);
+    }
+
+    pub fn to_file(&self, path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Writing blocks");
+
+        let file = File::create(path)?;
+        serde_yaml::to_writer(&file, self)?;
+
+        return Ok(());
+    }
+
+    // Bitmaps for the Unicode Block Elements, whose shapes are defined
+    // geometrically, drawn at the given cell size. Glyphs that come out empty or
+    // identical to an earlier glyph at that size are left out.
+    pub fn unicode_block_elements(width: u32, height: u32) -> Result<Blocks, BlocksError> {
+        info!("Generating block elements");
+
+        if width == 0 || height == 0 {
+            return Err(BlocksError::EmptyCell { width, height });
+        }
+
+        let mut blocks = BTreeMap::new();
+        let mut bitmaps = Vec::new();
+        for (character, fill) in BLOCK_ELEMENTS.iter() {
+            let bitmap: Vec<Vec<bool>> = (0..height)
+                .map(|y| (0..width).map(|x| fill.covers(x, y, width, height)).collect())
+                .collect();
+
+            if count_foreground_pixels(&bitmap) == 0 || bitmaps.contains(&bitmap) {
+                continue;
+            }
+            bitmaps.push(bitmap.clone());
+            blocks.insert(*character, bitmap);
+        }
+
+        return Ok(Blocks {
+            width,
+            height,
+            blocks,
+        });
+    }
+
+    // Each cell is two vertically stacked pixels, the usual two colors per cell
+    // terminal art
+    pub fn half_blocks() -> Blocks {
+        let blocks = BTreeMap::from([
+            (' ', vec![vec![false], vec![false]]),
+            ('▀', vec![vec![true], vec![false]]),
+            ('▄', vec![vec![false], vec![true]]),
+            ('█', vec![vec![true], vec![true]]),
+        ]);
+
+        return Blocks {
+            width: 1,
+            height: 2,
+            blocks,
+        };
+    }
+
+    // Every combination of the four quadrants of a 2x2 cell
+    pub fn quadrants() -> Blocks {
+        // Indexed by the set quadrants, upper left in the lowest bit, then upper
+        // right, lower left, and lower right
+        const QUADRANTS: [char; 16] = [
+            ' ', '▘', '▝', '▀', '▖', '▌', '▞', '▛', '▗', '▚', '▐', '▜', '▄', '▙', '▟', '█',
+        ];
+
+        return Blocks::from_bit_patterns(2, 2, |pattern| QUADRANTS[pattern as usize]);
+    }
+
+    // Every combination of the six sextants of a 2x3 cell
+    pub fn sextants() -> Blocks {
+        // Symbols for Legacy Computing numbers the sextants the same way as the
+        // bits here, and leaves out the four patterns that already exist as the
+        // space, the left and right halves, and the full block
+        return Blocks::from_bit_patterns(2, 3, |pattern| match pattern {
+            0 => ' ',
+            21 => '▌',
+            42 => '▐',
+            63 => '█',
+            _ => {
+                let skipped = (pattern > 21) as u32 + (pattern > 42) as u32;
+                char::from_u32(0x1FB00 + pattern - 1 - skipped).unwrap()
+            }
+        });
+    }
+
+    // Every combination of the eight dots of a 2x4 Braille cell
+    pub fn braille() -> Blocks {
+        return Blocks::from_bit_patterns(2, 4, |pattern| {
+            // Dots 1 to 3 run down the left column and 4 to 6 down the right, with
+            // 7 and 8 added below them later
+            let dots = (0..8)
+                .filter(|bit| pattern & (1 << bit) != 0)
+                .map(|bit| match (bit % 2, bit / 2) {
+                    (0, 3) => 1 << 6,
+                    (1, 3) => 1 << 7,
+                    (0, y) => 1 << y,
+                    (_, y) => 1 << (3 + y),
+                })
+                .sum::<u32>();
+            char::from_u32(0x2800 + dots).unwrap()
+        });
+    }
+
+    // Bit `y * width + x` of a pattern is the pixel at x, y
+    fn from_bit_patterns<F: Fn(u32) -> char>(width: u32, height: u32, character: F) -> Blocks {
+        let blocks = (0..1 << (width * height))
+            .map(|pattern| {
+                let bitmap = (0..height)
+                    .map(|y| {
+                        (0..width)
+                            .map(|x| pattern & (1 << (y * width + x)) != 0)
+                            .collect()
+                    })
+                    .collect();
+                (character(pattern), bitmap)
+            })
+            .collect();
+
+        return Blocks {
+            width,
+            height,
+            blocks,
+        };
+    }
+
+    pub fn bitmap(&self, block: char) -> Option<&Vec<Vec<bool>>> {
+        return self.blocks.get(&block);
+    }
+
+    pub fn blocks(&self) -> &BTreeMap<char, Vec<Vec<bool>>> {
+        return &self.blocks;
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+}
+
+// The top left corner of each character's cell in a sheet read left to right,
+// top to bottom
+fn sprite_cells(
+    sheet: &GrayImage,
+    cell_width: u32,
+    cell_height: u32,
+    chars: &str,
+) -> Result<BTreeMap<char, (u32, u32)>, Box<dyn std::error::Error>> {
+    if cell_width == 0 || cell_height == 0 {
+        return Err("sprite sheet cells must be at least 1x1".into());
+    }
+
+    let (sheet_width, sheet_height) = sheet.dimensions();
+    if sheet_width % cell_width != 0 || sheet_height % cell_height != 0 {
+        return Err(format!(
+            "sprite sheet is {}x{}, which is not a multiple of the {}x{} cell size",
+            sheet_width, sheet_height, cell_width, cell_height
+        )
+        .into());
+    }
+
+    let columns = sheet_width / cell_width;
+    let cells = (columns * (sheet_height / cell_height)) as usize;
+    let characters = chars.chars().count();
+    if characters > cells {
+        return Err(format!(
+            "sprite sheet has {} cells but {} characters were given",
+            cells, characters
+        )
+        .into());
+    }
+
+    return Ok(chars
+        .chars()
+        .enumerate()
+        .map(|(i, character)| {
+            let left = (i as u32 % columns) * cell_width;
+            let top = (i as u32 / columns) * cell_height;
+            (character, (left, top))
+        })
+        .collect());
+}
+
+enum BlockFill {
+    // Left, top, right, and bottom edges as fractions of the cell
+    Rect(f32, f32, f32, f32),
+    Quadrants {
+        upper_left: bool,
+        upper_right: bool,
+        lower_left: bool,
+        lower_right: bool,
+    },
+    // Ordered dither covering a quarter, half, or three quarters of the cell
+    Shade(u32),
+}
+
+impl BlockFill {
+    fn covers(&self, x: u32, y: u32, width: u32, height: u32) -> bool {
+        let u = (x as f32 + 0.5) / width as f32;
+        let v = (y as f32 + 0.5) / height as f32;
+
+        return match self {
+            BlockFill::Rect(left, top, right, bottom) => {
+                u >= *left && u < *right && v >= *top && v < *bottom
+            }
+            BlockFill::Quadrants {
+                upper_left,
+                upper_right,
+                lower_left,
+                lower_right,
+            } => match (u < 0.5, v < 0.5) {
+                (true, true) => *upper_left,
+                (false, true) => *upper_right,
+                (true, false) => *lower_left,
+                (false, false) => *lower_right,
+            },
+            BlockFill::Shade(quarters) => {
+                let rank = match (x % 2, y % 2) {
+                    (0, 0) => 0,
+                    (1, 1) => 1,
+                    (1, 0) => 2,
+                    _ => 3,
+                };
+                rank < *quarters
+            }
+        };
+    }
+}
+
+const fn quadrants(
+    upper_left: bool,
+    upper_right: bool,
+    lower_left: bool,
+    lower_right: bool,
+) -> BlockFill {
+    return BlockFill::Quadrants {
+        upper_left,
+        upper_right,
+        lower_left,
+        lower_right,
+    };
+}
+
+// In order of preference when two glyphs draw the same bitmap at a small size
+const BLOCK_ELEMENTS: [(char, BlockFill); 32] = [
+    ('█', BlockFill::Rect(0.0, 0.0, 1.0, 1.0)),
+    ('▀', BlockFill::Rect(0.0, 0.0, 1.0, 0.5)),
+    ('▄', BlockFill::Rect(0.0, 0.5, 1.0, 1.0)),
+    ('▌', BlockFill::Rect(0.0, 0.0, 0.5, 1.0)),
+    ('▐', BlockFill::Rect(0.5, 0.0, 1.0, 1.0)),
+    ('▘', quadrants(true, false, false, false)),
+    ('▝', quadrants(false, true, false, false)),
+    ('▖', quadrants(false, false, true, false)),
+    ('▗', quadrants(false, false, false, true)),
+    ('▚', quadrants(true, false, false, true)),
+    ('▞', quadrants(false, true, true, false)),
+    ('▛', quadrants(true, true, true, false)),
+    ('▜', quadrants(true, true, false, true)),
+    ('▙', quadrants(true, false, true, true)),
+    ('▟', quadrants(false, true, true, true)),
+    ('▔', BlockFill::Rect(0.0, 0.0, 1.0, 0.125)),
+    ('▁', BlockFill::Rect(0.0, 0.875, 1.0, 1.0)),
+    ('▂', BlockFill::Rect(0.0, 0.75, 1.0, 1.0)),
+    ('▃', BlockFill::Rect(0.0, 0.625, 1.0, 1.0)),
+    ('▅', BlockFill::Rect(0.0, 0.375, 1.0, 1.0)),
+    ('▆', BlockFill::Rect(0.0, 0.25, 1.0, 1.0)),
+    ('▇', BlockFill::Rect(0.0, 0.125, 1.0, 1.0)),
+    ('▏', BlockFill::Rect(0.0, 0.0, 0.125, 1.0)),
+    ('▎', BlockFill::Rect(0.0, 0.0, 0.25, 1.0)),
+    ('▍', BlockFill::Rect(0.0, 0.0, 0.375, 1.0)),
+    ('▋', BlockFill::Rect(0.0, 0.0, 0.625, 1.0)),
+    ('▊', BlockFill::Rect(0.0, 0.0, 0.75, 1.0)),
+    ('▉', BlockFill::Rect(0.0, 0.0, 0.875, 1.0)),
+    ('▕', BlockFill::Rect(0.875, 0.0, 1.0, 1.0)),
+    ('░', BlockFill::Shade(1)),
+    ('▒', BlockFill::Shade(2)),
+    ('▓', BlockFill::Shade(3)),
+];
+
+#[derive(Clone)]
+struct Shade {
+    ratio: f32,
+    block: char,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Texel {
+    pub foreground_color: u8,
+    pub background_color: u8,
+    pub block: char,
+}
+
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        return Rng { state: seed };
+    }
+
+    // SplitMix64
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+
+    pub fn next_f32(&mut self) -> f32 {
+        return (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+    }
+
+    pub fn next_below(&mut self, bound: usize) -> usize {
+        return (self.next_u64() % bound as u64) as usize;
+    }
+}
+
+fn kelvin_to_rgb(kelvin: f32) -> [f32; 3] {
+    // Tanner Helland's approximation of a black-body radiator's color
+    let temperature = kelvin.clamp(1000.0, 40000.0) / 100.0;
+
+    let red = if temperature <= 66.0 {
+        255.0
+    } else {
+        329.69873 * (temperature - 60.0).powf(-0.13320476)
+    };
+    let green = if temperature <= 66.0 {
+        99.4708 * temperature.ln() - 161.11957
+    } else {
+        288.12216 * (temperature - 60.0).powf(-0.075514846)
+    };
+    let blue = if temperature >= 66.0 {
+        255.0
+    } else if temperature <= 19.0 {
+        0.0
+    } else {
+        138.51773 * (temperature - 10.0).ln() - 305.0448
+    };
+
+    return [red, green, blue].map(|channel| channel.clamp(1.0, 255.0) / 255.0);
+}
+
+pub fn white_balance_scale(kelvin: f32) -> [f32; 3] {
+    let source = kelvin_to_rgb(kelvin);
+    let target = kelvin_to_rgb(6500.0);
+    return [
+        target[0] / source[0],
+        target[1] / source[1],
+        target[2] / source[2],
+    ];
+}
+
+pub fn tint(img: &mut RgbImage, scale: [f32; 3]) {
+    info!("Applying tint");
+
+    for pixel in img.pixels_mut() {
+        for (channel, factor) in pixel.0.iter_mut().zip(scale.iter()) {
+            *channel = (*channel as f32 * factor).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+}
+
+pub fn white_balance(img: &mut RgbImage, kelvin: f32) {
+    tint(img, white_balance_scale(kelvin));
+}
+
+pub fn composite_over(foreground: &RgbaImage, background: &RgbImage) -> RgbImage {
+    info!("Compositing over background");
+
+    let background = image::imageops::resize(
+        background,
+        foreground.width(),
+        foreground.height(),
+        image::imageops::Lanczos3,
+    );
+
+    return RgbImage::from_fn(foreground.width(), foreground.height(), |x, y| {
+        let [r, g, b, a] = foreground.get_pixel(x, y).0;
+        let alpha = a as f32 / 255.0;
+        let under = background.get_pixel(x, y).0;
+        let blend = |over: u8, under: u8| {
+            (over as f32 * alpha + under as f32 * (1.0 - alpha)).round() as u8
+        };
+        Rgb([blend(r, under[0]), blend(g, under[1]), blend(b, under[2])])
+    });
+}
+
+// Any color type to 8-bit RGB. Grayscale is expanded, deeper channels are scaled
+// down, and transparency is composited over black.
+pub fn flatten_to_rgb(img: &DynamicImage) -> RgbImage {
+    if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        return composite_over(&rgba, &RgbImage::new(rgba.width(), rgba.height()));
+    }
+
+    return img.to_rgb8();
+}
+
+// With a threshold, alpha snaps to fully transparent below it and fully opaque
+// at or above it instead of being kept for soft compositing.
+pub fn split_alpha(img: &RgbaImage, alpha_threshold: Option<f32>) -> (RgbImage, GrayImage) {
+    let rgb = RgbImage::from_fn(img.width(), img.height(), |x, y| {
+        let [r, g, b, _] = img.get_pixel(x, y).0;
+        Rgb([r, g, b])
+    });
+    let alpha = GrayImage::from_fn(img.width(), img.height(), |x, y| {
+        let a = img.get_pixel(x, y).0[3];
+        match alpha_threshold {
+            Some(threshold) if (a as f32 / 255.0) < threshold => Luma([0]),
+            Some(_) => Luma([255]),
+            None => Luma([a]),
+        }
+    });
+
+    return (rgb, alpha);
+}
+
+// Rows of tiles at most tile_width by tile_height, left to right and top to
+// bottom. Tiles on the right and bottom edges are smaller when the image does
+// not divide evenly.
+pub fn split_tiles(img: &RgbImage, tile_width: u32, tile_height: u32) -> Vec<Vec<RgbImage>> {
+    info!("Splitting into tiles");
+
+    let tile_width = tile_width.max(1);
+    let tile_height = tile_height.max(1);
+
+    return (0..img.height())
+        .step_by(tile_height as usize)
+        .map(|y| {
+            (0..img.width())
+                .step_by(tile_width as usize)
+                .map(|x| {
+                    let width = tile_width.min(img.width() - x);
+                    let height = tile_height.min(img.height() - y);
+                    image::imageops::crop_imm(img, x, y, width, height).to_image()
+                })
+                .collect()
+        })
+        .collect();
+}
+
+// Repeats the image from its top left corner to fill width by height, which is
+// seamless when the image tiles
+pub fn repeat_to<P: Pixel + 'static>(
+    img: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>> {
+    info!("Repeating image");
+
+    return ImageBuffer::from_fn(width, height, |x, y| {
+        *img.get_pixel(x % img.width(), y % img.height())
+    });
+}
+
+// tEXt chunks hold Latin-1, so any other character is written as `?`
+pub fn write_png_with_text<W: Write>(
+    img: &RgbImage,
+    writer: W,
+    text: &[(String, String)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Writing PNG with text chunks");
+
+    let latin1 = |s: &str| -> Vec<u8> {
+        return s
+            .chars()
+            .map(|c| if (c as u32) < 256 { c as u8 } else { b'?' })
+            .collect();
+    };
+
+    let mut encoder = png::Encoder::new(writer, img.width(), img.height());
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    for (keyword, value) in text {
+        let mut data = latin1(keyword);
+        data.push(0);
+        data.extend(latin1(value));
+        writer.write_chunk(*b"tEXt", &data)?;
+    }
+    writer.write_image_data(img.as_raw())?;
+
+    return Ok(());
+}
+
+// Keeps every step-th frame of an animation, each shown for as long as the frames
+// it stands in for combined. Skipped frames are still decoded but never kept.
+pub struct FrameStep<I> {
+    frames: I,
+    step: usize,
+}
+
+impl<I: Iterator<Item = ImageResult<Frame>>> Iterator for FrameStep<I> {
+    type Item = ImageResult<Frame>;
+
+    fn next(&mut self) -> Option<ImageResult<Frame>> {
+        let frame = match self.frames.next()? {
+            Ok(frame) => frame,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let mut delay = Duration::from(frame.delay());
+        for _ in 1..self.step {
+            match self.frames.next() {
+                Some(Ok(skipped)) => delay += Duration::from(skipped.delay()),
+                Some(Err(e)) => return Some(Err(e)),
+                None => break,
+            }
+        }
+
+        let (left, top) = (frame.left(), frame.top());
+        return Some(Ok(Frame::from_parts(
+            frame.into_buffer(),
+            left,
+            top,
+            Delay::from_saturating_duration(delay),
+        )));
+    }
+}
+
+pub fn frame_step<I: Iterator<Item = ImageResult<Frame>>>(frames: I, step: usize) -> FrameStep<I> {
+    return FrameStep {
+        frames,
+        step: step.max(1),
+    };
+}
+
+// The largest width up to `max_width` whose rendered text fits in `max_bytes`.
+// Text only grows with the width, so this binary searches instead of rendering
+// every width. None when even a single column is too large.
+pub fn fit_width_to_bytes<F: FnMut(u32) -> String>(
+    max_width: u32,
+    max_bytes: usize,
+    mut render: F,
+) -> Option<(u32, String)> {
+    let mut best = None;
+    let (mut low, mut high) = (1, max_width);
+    while low <= high {
+        let width = low + (high - low) / 2;
+        let text = render(width);
+        if text.len() <= max_bytes {
+            best = Some((width, text));
+            low = width + 1;
+        } else {
+            high = width - 1;
+        }
+    }
+
+    return best;
+}
+
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [u8; 3] {
+    let hue = hue.rem_euclid(1.0) * 6.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+
+    return denormalize_color(&[r + m, g + m, b + m]);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestPattern {
+    ColorRamp,
+    GrayscaleRamp,
+    HueWheel,
+    Checkerboard,
+}
+
+impl TestPattern {
+    pub fn generate(
+        &self,
+        width: u32,
+        height: u32,
+    ) -> Result<RgbImage, Box<dyn std::error::Error>> {
+        info!("Generating test pattern");
+
+        if width == 0 || height == 0 {
+            return Err(format!("a {}x{} test pattern has no pixels", width, height).into());
+        }
+        // Position of the pixel center along each axis, from 0 to 1
+        let u = |x: u32| (x as f32 + 0.5) / width as f32;
+        let v = |y: u32| (y as f32 + 0.5) / height as f32;
+
+        let pattern = match self {
+            TestPattern::ColorRamp => RgbImage::from_fn(width, height, |x, y| {
+                Rgb(hsv_to_rgb(u(x), 1.0, 1.0 - v(y)))
+            }),
+            TestPattern::GrayscaleRamp => RgbImage::from_fn(width, height, |x, _| {
+                let level = (x as f32 / (width - 1).max(1) as f32 * 255.0).round() as u8;
+                Rgb([level, level, level])
+            }),
+            TestPattern::HueWheel => RgbImage::from_fn(width, height, |x, y| {
+                let dx = u(x) * 2.0 - 1.0;
+                let dy = v(y) * 2.0 - 1.0;
+                let hue = dy.atan2(dx) / std::f32::consts::TAU;
+                Rgb(hsv_to_rgb(hue, (dx * dx + dy * dy).sqrt().min(1.0), 1.0))
+            }),
+            TestPattern::Checkerboard => {
+                let size = (width.min(height) / 8).max(1);
+                RgbImage::from_fn(width, height, |x, y| {
+                    if (x / size + y / size).is_multiple_of(2) {
+                        Rgb([255, 255, 255])
+                    } else {
+                        Rgb([0, 0, 0])
+                    }
+                })
+            }
+        };
+        return Ok(pattern);
+    }
+}
+
+fn texel_index_pixel(char_to_idx: &BTreeMap<char, u8>, texel: &Texel) -> Rgba<u8> {
+    let block_idx = char_to_idx[&texel.block];
+    return Rgba([texel.foreground_color, texel.background_color, block_idx, 255]);
+}
+
+fn cell_luminances(pixels: &[[u8; 3]]) -> Vec<f32> {
+    return pixels
+        .iter()
+        .map(|pixel| 0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32)
+        .collect();
+}
+
+// Subsample large images so clustering stays fast, keeping it deterministic
+fn sample_colors(img: &RgbImage) -> Vec<[f32; 3]> {
+    let step = (img.pixels().len() / 4096).max(1);
+    return img
+        .pixels()
+        .step_by(step)
+        .map(|pixel| normalize_color(&pixel.0))
+        .collect();
+}
+
+// Lloyd iterations until the centroids settle
+fn refine_centroids(samples: &[[f32; 3]], centroids: &mut [[f32; 3]]) {
+    info!("Clustering colors");
+
+    for _ in 0..16 {
+        let mut sums = vec![[0.0f32; 3]; centroids.len()];
+        let mut counts = vec![0u32; centroids.len()];
+        for sample in samples.iter() {
+            let nearest = centroids
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| {
+                    color_distance(sample, a).total_cmp(&color_distance(sample, b))
+                })
+                .unwrap()
+                .0;
+            for c in 0..3 {
+                sums[nearest][c] += sample[c];
+            }
+            counts[nearest] += 1;
+        }
+
+        let mut moved = false;
+        for (i, centroid) in centroids.iter_mut().enumerate() {
+            if counts[i] == 0 {
+                continue;
+            }
+            let mean = sums[i].map(|sum| sum / counts[i] as f32);
+            moved |= color_distance(&mean, centroid) > 1.0 / 512.0;
+            *centroid = mean;
+        }
+
+        if !moved {
+            break;
+        }
+    }
+}
+
+fn count_foreground_pixels(bitmap: &Vec<Vec<bool>>) -> u32 {
+    return bitmap
+        .into_iter()
+        .flat_map(IntoIterator::into_iter)
+        .map(|x| *x as u32)
+        .sum();
+}
+
+fn blend_two_colors(color_a: &[f32; 3], color_b: &[f32; 3], ratio: f32) -> [f32; 3] {
+    return [
+        color_a[0] * ratio + color_b[0] * (1.0 - ratio),
+        color_a[1] * ratio + color_b[1] * (1.0 - ratio),
+        color_a[2] * ratio + color_b[2] * (1.0 - ratio),
+    ];
+}
+
+// Blends in linear light, so a glyph covering half the cell looks as bright as
+// the average of its two colors on screen
+fn blend_with_gamma(color_a: &[f32; 3], color_b: &[f32; 3], ratio: f32, gamma: Gamma) -> [f32; 3] {
+    let decode = |color: &[f32; 3]| color.map(|channel| gamma.decode(channel));
+    return blend_two_colors(&decode(color_a), &decode(color_b), ratio)
+        .map(|channel| gamma.encode(channel));
+}
+
+// Rounds each channel to the nearest of its 2^bits evenly spaced levels, which
+// always include 0 and 255
+fn quantize_color(color: &[u8; 3], bits: [u8; 3]) -> [u8; 3] {
+    return [0, 1, 2].map(|i| {
+        let levels = (1u32 << bits[i]) - 1;
+        let level = (color[i] as u32 * levels + 127) / 255;
+        ((level * 255 + levels / 2) / levels) as u8
+    });
+}
+
+fn normalize_color(color: &[u8; 3]) -> [f32; 3] {
+    return [
+        color[0] as f32 / 255.0,
+        color[1] as f32 / 255.0,
+        color[2] as f32 / 255.0,
+    ];
+}
+
+fn denormalize_color(color: &[f32; 3]) -> [u8; 3] {
+    return color.map(|channel| (channel * 255.0).round().clamp(0.0, 255.0) as u8);
+}
+
+fn color_distance(color_a: &[f32; 3], color_b: &[f32; 3]) -> f32 {
+    return ((color_a[0] - color_b[0]).powi(2)
+        + (color_a[1] - color_b[1]).powi(2)
+        + (color_a[2] - color_b[2]).powi(2))
+    .sqrt();
+}
+
+fn srgb_to_linear(channel: f32) -> f32 {
+    return if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    };
+}
+
+fn srgb_to_linear_f64(channel: f64) -> f64 {
+    return if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    };
+}
+
+fn rgb_to_lab(color: &[u8; 3]) -> [f32; 3] {
+    return normalized_to_lab(&<[f64; 3]>::solid(color)).map(|channel| channel as f32);
+}
+
+fn normalized_to_lab(color: &[f64; 3]) -> [f64; 3] {
+    let [r, g, b] = color.map(srgb_to_linear_f64);
+
+    // D65 reference white
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+
+    return [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)];
+}
+
+fn linear_to_srgb(channel: f32) -> f32 {
+    return if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    };
+}
+
+fn linear_to_srgb_f64(channel: f64) -> f64 {
+    return if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    };
+}
+
+fn lab_to_rgb(color: &[f32; 3]) -> [u8; 3] {
+    let fy = (color[0] + 16.0) / 116.0;
+    let fx = fy + color[1] / 500.0;
+    let fz = fy - color[2] / 200.0;
+
+    let f_inv = |t: f32| {
+        if t.powi(3) > 216.0 / 24389.0 {
+            t.powi(3)
+        } else {
+            (116.0 * t - 16.0) * 27.0 / 24389.0
+        }
+    };
+
+    // D65 reference white
+    let x = f_inv(fx) * 0.95047;
+    let y = f_inv(fy);
+    let z = f_inv(fz) * 1.08883;
+
+    let r = 3.2406 * x - 1.5372 * y - 0.4986 * z;
+    let g = -0.9689 * x + 1.8758 * y + 0.0415 * z;
+    let b = 0.0557 * x - 0.2040 * y + 1.0570 * z;
+
+    return denormalize_color(&[r, g, b].map(|channel| linear_to_srgb(channel.max(0.0)).min(1.0)));
+}
+
+// The CIEDE2000 color difference between two Lab colors, following Sharma, Wu,
+// and Dalal's implementation notes
+fn ciede2000(lab_a: &[f64; 3], lab_b: &[f64; 3]) -> f64 {
+    let [l1, a1, b1] = *lab_a;
+    let [l2, a2, b2] = *lab_b;
+
+    let c_mean = ((a1.hypot(b1) + a2.hypot(b2)) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (c_mean / (c_mean + 25f64.powi(7))).sqrt());
+    let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+
+    let hue = |a: f64, b: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 <= h1 {
+        h2 - h1 + 360.0
+    } else {
+        h2 - h1 - 360.0
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+    let l_mean = (l1 + l2) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (h_mean - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_mean).to_radians().cos()
+        + 0.32 * (3.0 * h_mean + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_mean - 63.0).to_radians().cos();
+    let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_mean - 50.0).powi(2) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_mean;
+    let s_h = 1.0 + 0.015 * c_mean * t;
+    let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    return (l * l + c * c + h * h + r_t * c * h).sqrt();
+}
+
+fn unicode_shading_density(block: char) -> Option<f32> {
+    return match block {
+        '░' => Some(0.25),
+        '▒' => Some(0.5),
+        '▓' => Some(0.75),
+        _ => None,
+    };
+}
+
+fn generate_shades(blocks: &Blocks, unicode_shading: bool) -> Vec<Shade> {
+    info!("Generating shades");
+
+    let mut shades = Vec::new();
+    for (character, bitmap) in blocks.blocks.iter() {
+        let density = if unicode_shading {
+            unicode_shading_density(*character)
+        } else {
+            None
+        };
+        shades.push(Shade {
+            ratio: density.unwrap_or(
+                count_foreground_pixels(bitmap) as f32 / (blocks.width * blocks.height) as f32,
+            ),
+            block: *character,
+        });
+    }
+
+    return shades;
+}
+
+// A texel color at the precision of the kd-tree it goes into
+trait TexelColor: Copy {
+    type Key: Ord;
+
+    fn solid(color: &[u8; 3]) -> Self;
+    fn blend(foreground: &[u8; 3], background: &[u8; 3], ratio: f32, gamma: Gamma) -> Self;
+    fn key(&self) -> Self::Key;
+}
+
+impl TexelColor for [f32; 3] {
+    type Key = [i32; 3];
+
+    fn solid(color: &[u8; 3]) -> [f32; 3] {
+        return normalize_color(color);
+    }
+
+    fn blend(foreground: &[u8; 3], background: &[u8; 3], ratio: f32, gamma: Gamma) -> [f32; 3] {
+        return blend_with_gamma(
+            &normalize_color(foreground),
+            &normalize_color(background),
+            ratio,
+            gamma,
+        );
+    }
+
+    fn key(&self) -> [i32; 3] {
+        return self.map(|channel| (channel * 65536.0).round() as i32);
+    }
+}
+
+impl TexelColor for [f64; 3] {
+    type Key = [u64; 3];
+
+    fn solid(color: &[u8; 3]) -> [f64; 3] {
+        return color.map(|channel| channel as f64 / 255.0);
+    }
+
+    fn blend(foreground: &[u8; 3], background: &[u8; 3], ratio: f32, gamma: Gamma) -> [f64; 3] {
+        let ratio = ratio as f64;
+        let foreground = <[f64; 3]>::solid(foreground).map(|channel| gamma.decode_f64(channel));
+        let background = <[f64; 3]>::solid(background).map(|channel| gamma.decode_f64(channel));
+        return [0, 1, 2]
+            .map(|i| gamma.encode_f64(foreground[i] * ratio + background[i] * (1.0 - ratio)));
+    }
+
+    // Only exact duplicates are merged, telling apart blends closer than f32 can
+    // is what F64 is for
+    fn key(&self) -> [u64; 3] {
+        return self.map(|channel| channel.to_bits());
+    }
+}
+
+fn generate_texels<C: TexelColor>(
+    palette: &Palette,
+    shades: &[Shade],
+    gamma: Gamma,
+    color_priority: &[u8],
+) -> Vec<(C, Texel)> {
+    info!("Generating texels");
+
+    let mut texels = Vec::new();
+
+    // The color on the hidden side of a solid or empty glyph is never seen, but it
+    // is still emitted, so it should be one the palette allows on that side
+    let foreground_placeholder = palette
+        .roles
+        .iter()
+        .position(ColorRole::allows_foreground)
+        .unwrap_or(0);
+    let background_placeholder = palette
+        .roles
+        .iter()
+        .position(ColorRole::allows_background)
+        .unwrap_or(0);
+
+    for shade in shades.iter() {
+        if shade.ratio == 0.0 {
+            for (i, color) in palette.colors.iter().enumerate() {
+                if !palette.roles[i].allows_background() {
+                    continue;
+                }
+                texels.push((
+                    C::solid(color),
+                    Texel {
+                        foreground_color: foreground_placeholder as u8,
+                        background_color: i as u8,
+                        block: shade.block,
+                    },
+                ));
+            }
+        } else if shade.ratio == 1.0 {
+            for (i, color) in palette.colors.iter().enumerate() {
+                if !palette.roles[i].allows_foreground() {
+                    continue;
+                }
+                texels.push((
+                    C::solid(color),
+                    Texel {
+                        foreground_color: i as u8,
+                        background_color: background_placeholder as u8,
+                        block: shade.block,
+                    },
+                ));
+            }
+        } else {
+            for (i, foreground_color) in palette.colors.iter().enumerate() {
+                if !palette.roles[i].allows_foreground() {
+                    continue;
+                }
+                for (j, background_color) in palette.colors.iter().enumerate() {
+                    if foreground_color == background_color || !palette.roles[j].allows_background()
+                    {
+                        continue;
+                    }
+                    let color = C::blend(foreground_color, background_color, shade.ratio, gamma);
+                    texels.push((
+                        color,
+                        Texel {
+                            foreground_color: i as u8,
+                            background_color: j as u8,
+                            block: shade.block,
+                        },
+                    ));
+                }
+            }
+        }
+    }
+
+    // Different glyph and color pairs can blend to the same color, up to float
+    // rounding (e.g. a glyph and its inverse with the colors swapped). Only the
+    // first is kept so the nearest match never depends on how the kd-tree happens
+    // to break the tie. With a color priority, the texel showing the highest
+    // priority color is moved first, the sort is stable so equals keep their order.
+    if !color_priority.is_empty() {
+        let ratios: BTreeMap<char, f32> = shades
+            .iter()
+            .map(|shade| (shade.block, shade.ratio))
+            .collect();
+        let rank = |index: u8| {
+            return color_priority
+                .iter()
+                .position(|&preferred| preferred == index)
+                .unwrap_or(color_priority.len());
+        };
+        texels.sort_by_key(|(_, texel)| {
+            let ratio = ratios[&texel.block];
+            let foreground = if ratio > 0.0 {
+                rank(texel.foreground_color)
+            } else {
+                usize::MAX
+            };
+            let background = if ratio < 1.0 {
+                rank(texel.background_color)
+            } else {
+                usize::MAX
+            };
+            foreground.min(background)
+        });
+    }
+    let mut seen = BTreeSet::new();
+    texels.retain(|(color, _)| seen.insert(color.key()));
+
+    return texels;
+}
+
+// A region of the cell-resolution source image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellChange {
+    pub x: u32,
+    pub y: u32,
+    pub texel: Texel,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MatchLogEntry {
+    pub x: u32,
+    pub y: u32,
+    pub source: [u8; 3],
+    pub texel: Texel,
+    pub distance: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutOfGamut {
+    #[default]
+    Nearest,
+    Desaturate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MircColors {
+    // The 16 colors every IRC client supports
+    Standard,
+    // All 99 colors, which newer clients support
+    Extended,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Dither {
+    #[default]
+    None,
+    BlueNoise,
+    // Error diffusion, with strength scaling how much of each cell's error is
+    // carried, 1.0 for the full amount
+    FloydSteinberg { strength: f32 },
+}
+
+// A 16x16 blue noise threshold tile made with void-and-cluster, each of 0-255
+// appears exactly once
+const BLUE_NOISE: [u8; 256] = [
+    120, 61, 134, 223, 84, 33, 168, 12, 113, 225, 63, 246, 185, 233, 88, 169,
+    23, 206, 181, 17, 109, 214, 58, 140, 201, 24, 161, 93, 34, 133, 14, 221,
+    144, 73, 250, 49, 158, 187, 81, 251, 100, 51, 142, 210, 172, 57, 191, 106,
+    42, 167, 101, 126, 220, 3, 121, 40, 170, 231, 82, 8, 114, 255, 80, 232,
+    212, 11, 195, 31, 72, 239, 152, 196, 16, 127, 188, 222, 45, 157, 26, 128,
+    154, 87, 235, 143, 179, 94, 54, 108, 237, 65, 29, 105, 139, 207, 184, 66,
+    248, 47, 115, 62, 209, 20, 164, 217, 79, 146, 178, 243, 69, 90, 0, 118,
+    30, 190, 173, 6, 131, 254, 41, 136, 10, 204, 43, 159, 22, 229, 162, 218,
+    77, 148, 99, 226, 74, 182, 117, 192, 86, 247, 119, 97, 197, 130, 53, 103,
+    242, 19, 198, 44, 155, 96, 59, 230, 28, 165, 60, 5, 240, 39, 175, 202,
+    137, 64, 122, 238, 25, 211, 1, 149, 104, 224, 135, 183, 151, 71, 112, 9,
+    91, 213, 166, 85, 186, 111, 249, 174, 48, 75, 208, 32, 89, 205, 236, 160,
+    37, 252, 18, 55, 138, 38, 78, 123, 194, 13, 107, 253, 124, 15, 56, 189,
+    76, 145, 110, 228, 203, 163, 219, 21, 241, 141, 171, 50, 156, 227, 102, 129,
+    2, 199, 176, 68, 7, 98, 52, 150, 92, 36, 215, 83, 200, 27, 177, 216,
+    244, 95, 35, 153, 245, 125, 193, 234, 70, 180, 132, 4, 116, 67, 147, 46,
+];
+
+// Transfer function between encoded display values and linear light, used when
+// blending the colors of a glyph. Srgb blends in linear light the way colors mix
+// on screen, the default of Power(1.0) blends the raw values as ansify always has.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gamma {
+    Srgb,
+    Power(f32),
+}
+
+impl Default for Gamma {
+    fn default() -> Gamma {
+        return Gamma::Power(1.0);
+    }
+}
+
+impl Gamma {
+    pub fn decode(&self, channel: f32) -> f32 {
+        return match self {
+            Gamma::Srgb => srgb_to_linear(channel),
+            Gamma::Power(gamma) => channel.powf(*gamma),
+        };
+    }
+
+    pub fn encode(&self, channel: f32) -> f32 {
+        return match self {
+            Gamma::Srgb => linear_to_srgb(channel),
+            Gamma::Power(gamma) => channel.powf(1.0 / gamma),
+        };
+    }
+
+    fn decode_f64(&self, channel: f64) -> f64 {
+        return match self {
+            Gamma::Srgb => srgb_to_linear_f64(channel),
+            Gamma::Power(gamma) => channel.powf(*gamma as f64),
+        };
+    }
+
+    fn encode_f64(&self, channel: f64) -> f64 {
+        return match self {
+            Gamma::Srgb => linear_to_srgb_f64(channel),
+            Gamma::Power(gamma) => channel.powf(1.0 / *gamma as f64),
+        };
+    }
+}
+
+// The coordinate type of the texel kd-tree. F64 costs twice the memory but keeps
+// near-identical texel colors apart in palettes with very fine gradients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Precision {
+    #[default]
+    F32,
+    F64,
+}
+
+// How the kd-tree compares colors. Lab builds the tree over CIELAB, so distances
+// are the CIE76 color difference, which is already much closer to perception
+// than RGB. CIEDE2000 uses the same tree but picks among the nearest texels in
+// it by the CIEDE2000 difference, which also handles saturated colors well.
+// WeightedRgb weights the channels by how much they add to luminance, a cheaper
+// step towards perception that stays in RGB. Each is one of the ColorMetrics
+// below.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DistanceMetric {
+    #[default]
+    Euclidean,
+    Lab,
+    CIEDE2000,
+    WeightedRgb,
+}
+
+impl DistanceMetric {
+    fn color_metric(self) -> Arc<dyn ColorMetric> {
+        return match self {
+            DistanceMetric::Euclidean => Arc::new(SrgbMetric),
+            DistanceMetric::Lab => Arc::new(LabMetric),
+            DistanceMetric::CIEDE2000 => Arc::new(Ciede2000Metric),
+            DistanceMetric::WeightedRgb => Arc::new(WeightedRgbMetric),
+        };
+    }
+}
+
+// Maps normalized RGB colors into the space the kd-tree is built over, where the
+// tree is searched by straight-line distance. A metric with a distance of its
+// own has the tree search for more candidates than needed and ranks them by it.
+pub trait ColorMetric: Send + Sync {
+    fn encode(&self, color: [f64; 3]) -> [f64; 3];
+
+    fn distance(&self, color_a: &[f64; 3], color_b: &[f64; 3]) -> f64 {
+        return [0, 1, 2]
+            .map(|i| (color_a[i] - color_b[i]).powi(2))
+            .iter()
+            .sum::<f64>()
+            .sqrt();
+    }
+
+    // How many of the nearest texels in the tree are ranked by distance
+    fn candidates(&self) -> usize {
+        return 1;
+    }
+}
+
+// Normalized RGB, DistanceMetric::Euclidean
+pub struct SrgbMetric;
+
+impl ColorMetric for SrgbMetric {
+    fn encode(&self, color: [f64; 3]) -> [f64; 3] {
+        return color;
+    }
+}
+
+// Scaled CIELAB, DistanceMetric::Lab
+pub struct LabMetric;
+
+impl ColorMetric for LabMetric {
+    fn encode(&self, color: [f64; 3]) -> [f64; 3] {
+        return normalized_to_lab(&color).map(|channel| channel * LAB_SCALE);
+    }
+}
+
+// Scaled CIELAB ranked by CIEDE2000, DistanceMetric::CIEDE2000
+pub struct Ciede2000Metric;
+
+impl ColorMetric for Ciede2000Metric {
+    fn encode(&self, color: [f64; 3]) -> [f64; 3] {
+        return LabMetric.encode(color);
+    }
+
+    fn distance(&self, color_a: &[f64; 3], color_b: &[f64; 3]) -> f64 {
+        let lab_a = color_a.map(|channel| channel / LAB_SCALE);
+        let lab_b = color_b.map(|channel| channel / LAB_SCALE);
+        return ciede2000(&lab_a, &lab_b) * LAB_SCALE;
+    }
+
+    fn candidates(&self) -> usize {
+        return CIEDE2000_CANDIDATES;
+    }
+}
+
+// Normalized RGB scaled by the RGB weights, DistanceMetric::WeightedRgb
+pub struct WeightedRgbMetric;
+
+impl ColorMetric for WeightedRgbMetric {
+    fn encode(&self, color: [f64; 3]) -> [f64; 3] {
+        return [0, 1, 2].map(|i| color[i] * RGB_WEIGHTS[i].sqrt());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ANSIfierOptions {
+    pub gamma: Gamma,
+    pub precision: Precision,
+    pub metric: DistanceMetric,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextOptions {
+    pub final_reset: bool,
+    pub trailing_newline: bool,
+}
+
+impl Default for TextOptions {
+    fn default() -> TextOptions {
+        TextOptions {
+            final_reset: false,
+            trailing_newline: true,
+        }
+    }
+}
+
+// Rows of background color inserted between cell rows in rendered images, like
+// the line spacing of a terminal. With aa_edges, glyph pixels of RGBA renders
+// bordering transparent cells get partial alpha so overlays composite smoothly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RenderOptions {
+    pub row_gap: u32,
+    pub aa_edges: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LutCompatibilityReport {
+    pub colors: usize,
+    pub blocks: usize,
+    pub block_area: u32,
+    pub problems: Vec<String>,
+    pub estimated_memory_bytes: u64,
+    pub estimated_duration: Duration,
+}
+
+// Errors are distances between each source color and its texel's color, in the
+// units of the distance metric
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchStats {
+    pub palette_size: usize,
+    pub blocks: usize,
+    pub texels: usize,
+    pub columns: u32,
+    pub rows: u32,
+    pub output_width: u32,
+    pub output_height: u32,
+    pub mean_error: f32,
+    pub max_error: f32,
+    // Cells showing each palette color, by index
+    pub color_usage: Vec<u64>,
+    pub match_duration: Duration,
+}
+
+impl MatchStats {
+    pub fn to_json(&self) -> String {
+        let color_usage: Vec<String> = self
+            .color_usage
+            .iter()
+            .map(|count| count.to_string())
+            .collect();
+
+        // Debug formatting always keeps a fractional part, so floats stay floats
+        return format!(
+            "{{\"palette_size\":{},\"blocks\":{},\"texels\":{},\"columns\":{},\"rows\":{},\
+             \"output_width\":{},\"output_height\":{},\"mean_error\":{:?},\"max_error\":{:?},\
+             \"color_usage\":[{}],\"match_seconds\":{:?}}}",
+            self.palette_size,
+            self.blocks,
+            self.texels,
+            self.columns,
+            self.rows,
+            self.output_width,
+            self.output_height,
+            self.mean_error,
+            self.max_error,
+            color_usage.join(","),
+            self.match_duration.as_secs_f64()
+        );
+    }
+}
+
+impl LutCompatibilityReport {
+    pub fn is_compatible(&self) -> bool {
+        return self.problems.is_empty();
+    }
+}
+
+impl fmt::Display for LutCompatibilityReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Colors: {} (max 256)", self.colors)?;
+        writeln!(f, "Blocks: {} (max 256)", self.blocks)?;
+        writeln!(
+            f,
+            "Block area: {} pixels (max {})",
+            self.block_area, MAX_LUT_BLOCK_AREA
+        )?;
+        writeln!(
+            f,
+            "Estimated memory: {:.1} MiB",
+            self.estimated_memory_bytes as f64 / (1024.0 * 1024.0)
+        )?;
+        writeln!(
+            f,
+            "Estimated time: {:.1} s",
+            self.estimated_duration.as_secs_f64()
+        )?;
+
+        if self.is_compatible() {
+            writeln!(f, "Compatible with the LUT format")?;
+        } else {
+            for problem in &self.problems {
+                writeln!(f, "Incompatible: {}", problem)?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// Texels, the text output, and the LUT all refer to palette colors by a u8 index
+const MAX_PALETTE_COLORS: usize = 256;
+
+// Block bitmaps are packed into a u64, split across two rows of the LUT map
+const MAX_LUT_BLOCK_AREA: u32 = 64;
+
+const REACHABILITY_RESOLUTION: u32 = 64;
+
+// Lab is scaled down so distances in the Lab tree are about as large as in the
+// normalized RGB one, which keeps the gamut tolerance meaningful for both
+const LAB_SCALE: f64 = 1.0 / 100.0;
+
+// Weights of the squared channel differences for WeightedRgb, the tree is built
+// over the channels scaled by their square roots so it stays Euclidean
+const RGB_WEIGHTS: [f64; 3] = [0.3, 0.59, 0.11];
+
+// Euclidean distance in Lab only approximates CIEDE2000, so this many of the
+// nearest texels in the tree are compared by CIEDE2000
+const CIEDE2000_CANDIDATES: usize = 8;
+
+struct LutCache {
+    resolution: u32,
+    texels: Vec<Texel>,
+}
+
+// The LUT texture the OBS filter samples, with the block each index stands for
+struct ShaderLut {
+    lut: RgbaImage,
+    blocks: Vec<char>,
+}
+
+impl ShaderLut {
+    // The same lookup as the shader, the low four bits of blue extend the red
+    // coordinate and the high four bits extend the green
+    fn texel(&self, color: &[u8; 3]) -> Texel {
+        let [r, g, b] = color.map(|channel| channel as u32);
+        let x = r | ((b & 0xF) << 8);
+        let y = g | ((b >> 4) << 8);
+        let [foreground_color, background_color, block, _] = self.lut.get_pixel(x, y).0;
+
+        return Texel {
+            foreground_color,
+            background_color,
+            block: self.blocks[block as usize],
+        };
+    }
+}
+
+impl LutCache {
+    fn cell(resolution: u32, channel: u8) -> u32 {
+        return channel as u32 * resolution / 256;
+    }
+
+    // The color at the center of a cell, which is what the whole cell maps to
+    fn center(resolution: u32, cell: u32) -> u8 {
+        return ((cell * 256 + 128) / resolution) as u8;
+    }
+
+    fn index(&self, color: &[u8; 3]) -> usize {
+        let resolution = self.resolution;
+        let r = LutCache::cell(resolution, color[0]);
+        let g = LutCache::cell(resolution, color[1]);
+        let b = LutCache::cell(resolution, color[2]);
+        return ((r * resolution + g) * resolution + b) as usize;
+    }
+}
+
+// Texels keyed by their colors in the metric's space, each kept with its
+// normalized color
+enum TexelPoints {
+    F32(KdMap<[f32; 3], (Texel, [f32; 3])>),
+    F64(KdMap<[f64; 3], (Texel, [f32; 3])>),
+}
+
+struct TexelTree {
+    points: TexelPoints,
+    metric: Arc<dyn ColorMetric>,
+}
+
+impl TexelTree {
+    fn len(&self) -> usize {
+        return match &self.points {
+            TexelPoints::F32(tree) => tree.len(),
+            TexelPoints::F64(tree) => tree.len(),
+        };
+    }
+
+    // Glyphs still blend in light as they do on screen, only the blended colors
+    // are moved into the metric's space
+    fn build(
+        palette: &Palette,
+        shades: &[Shade],
+        options: &ANSIfierOptions,
+        metric: Arc<dyn ColorMetric>,
+        color_priority: &[u8],
+    ) -> TexelTree {
+        let points = match options.precision {
+            Precision::F32 => {
+                let texels: Vec<_> =
+                    generate_texels::<[f32; 3]>(palette, shades, options.gamma, color_priority)
+                        .into_iter()
+                        .map(|(color, texel)| {
+                            let point = metric.encode(color.map(f64::from));
+                            (point.map(|channel| channel as f32), (texel, color))
+                        })
+                        .collect();
+                #[cfg(feature = "rayon")]
+                let tree = KdMap::par_build_by_ordered_float(texels);
+                #[cfg(not(feature = "rayon"))]
+                let tree = KdMap::build_by_ordered_float(texels);
+                TexelPoints::F32(tree)
+            }
+            Precision::F64 => {
+                let texels: Vec<_> =
+                    generate_texels::<[f64; 3]>(palette, shades, options.gamma, color_priority)
+                        .into_iter()
+                        .map(|(color, texel)| {
+                            let normalized = color.map(|channel| channel as f32);
+                            (metric.encode(color), (texel, normalized))
+                        })
+                        .collect();
+                #[cfg(feature = "rayon")]
+                let tree = KdMap::par_build_by_ordered_float(texels);
+                #[cfg(not(feature = "rayon"))]
+                let tree = KdMap::build_by_ordered_float(texels);
+                TexelPoints::F64(tree)
+            }
+        };
+
+        return TexelTree { points, metric };
+    }
+
+    // Colors are normalized at the precision the texel colors were blended at
+    fn normalize(&self, color: &[u8; 3]) -> [f64; 3] {
+        return match self.points {
+            TexelPoints::F32(_) => normalize_color(color).map(f64::from),
+            TexelPoints::F64(_) => <[f64; 3]>::solid(color),
+        };
+    }
+
+    fn nearest(&self, color: &[u8; 3]) -> (&Texel, f32) {
+        return self.nearest_normalized(&self.normalize(color));
+    }
+
+    fn nearest_normalized(&self, color: &[f64; 3]) -> (&Texel, f32) {
+        if self.metric.candidates() > 1 {
+            let (texel, _, distance) = self.nearests_normalized(color, 1)[0];
+            return (texel, distance);
+        }
+
+        let query = self.metric.encode(*color);
+        let (texel, point) = match &self.points {
+            TexelPoints::F32(tree) => {
+                let nearest = tree.nearest(&query.map(|channel| channel as f32)).unwrap();
+                (&nearest.item.1 .0, nearest.item.0.map(f64::from))
+            }
+            TexelPoints::F64(tree) => {
+                let nearest = tree.nearest(&query).unwrap();
+                (&nearest.item.1 .0, nearest.item.0)
+            }
+        };
+        return (texel, self.metric.distance(&query, &point) as f32);
+    }
+
+    // The k nearest texels with their normalized colors, nearest first
+    fn nearests(&self, color: &[u8; 3], k: usize) -> Vec<([f32; 3], Texel)> {
+        return self
+            .nearests_normalized(&self.normalize(color), k)
+            .into_iter()
+            .map(|(texel, color, _)| (color, *texel))
+            .collect();
+    }
+
+    // The k nearest texels by the metric's distance, with their normalized colors
+    // and the distances
+    fn nearests_normalized(&self, color: &[f64; 3], k: usize) -> Vec<(&Texel, [f32; 3], f32)> {
+        let query = self.metric.encode(*color);
+        let count = k.max(self.metric.candidates());
+        let mut candidates: Vec<(&Texel, [f32; 3], f32)> = match &self.points {
+            TexelPoints::F32(tree) => tree
+                .nearests(&query.map(|channel| channel as f32), count)
+                .into_iter()
+                .map(|candidate| {
+                    let (point, (texel, color)) = candidate.item;
+                    let distance = self.metric.distance(&query, &point.map(f64::from));
+                    (texel, *color, distance as f32)
+                })
+                .collect(),
+            TexelPoints::F64(tree) => tree
+                .nearests(&query, count)
+                .into_iter()
+                .map(|candidate| {
+                    let (point, (texel, color)) = candidate.item;
+                    (texel, *color, self.metric.distance(&query, point) as f32)
+                })
+                .collect(),
+        };
+        candidates.sort_by(|(_, _, a), (_, _, b)| a.total_cmp(b));
+        candidates.truncate(k);
+        return candidates;
+    }
+
+    // Distance between two normalized colors in the metric's space
+    fn distance(&self, color_a: &[f32; 3], color_b: &[f32; 3]) -> f32 {
+        let color_a = self.metric.encode(color_a.map(f64::from));
+        let color_b = self.metric.encode(color_b.map(f64::from));
+        return self.metric.distance(&color_a, &color_b) as f32;
+    }
+}
+
+// Nothing can be matched without a color
+fn check_palette(palette: &Palette) -> Result<(), Box<dyn std::error::Error>> {
+    if palette.colors.is_empty() {
+        return Err("Palette must contain at least one color".into());
+    }
+
+    return Ok(());
+}
+
+pub struct ANSIfier {
+    palette: Palette,
+    pub blocks: Blocks,
+    shades: Vec<Shade>,
+    kdtree: TexelTree,
+    empty_char: Option<char>,
+    out_of_gamut: OutOfGamut,
+    gamut_tolerance: f32,
+    dither: Dither,
+    quantize: [u8; 3],
+    lut_cache: Option<LutCache>,
+    shader_lut: Option<ShaderLut>,
+    text_options: TextOptions,
+    render_options: RenderOptions,
+    substitutions: BTreeMap<char, char>,
+    tree_queries: AtomicU64,
+    options: ANSIfierOptions,
+    metric: Option<Arc<dyn ColorMetric>>,
+    color_priority: Vec<u8>,
+    previous_grid: Option<Vec<Vec<Texel>>>,
+}
+
+impl ANSIfier {
+    pub fn new(palette: Palette, blocks: Blocks) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        return ANSIfier::with_options(palette, blocks, ANSIfierOptions::default());
+    }
+
+    pub fn with_options(
+        palette: Palette,
+        blocks: Blocks,
+        options: ANSIfierOptions,
+    ) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        check_palette(&palette)?;
+
+        let shades = generate_shades(&blocks, false);
+        return Ok(ANSIfier::build(
+            palette,
+            blocks,
+            shades,
+            options,
+            None,
+            Vec::new(),
+        ));
+    }
+
+    // Searches for texels in the space the metric encodes colors into instead of
+    // the one options.metric picks
+    pub fn with_metric(
+        palette: Palette,
+        blocks: Blocks,
+        options: ANSIfierOptions,
+        metric: Arc<dyn ColorMetric>,
+    ) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        check_palette(&palette)?;
+
+        let shades = generate_shades(&blocks, false);
+        return Ok(ANSIfier::build(
+            palette,
+            blocks,
+            shades,
+            options,
+            Some(metric),
+            Vec::new(),
+        ));
+    }
+
+    fn build(
+        mut palette: Palette,
+        blocks: Blocks,
+        shades: Vec<Shade>,
+        options: ANSIfierOptions,
+        metric: Option<Arc<dyn ColorMetric>>,
+        color_priority: Vec<u8>,
+    ) -> ANSIfier {
+        if palette.colors.len() > MAX_PALETTE_COLORS {
+            warn!(
+                "Palette has {} colors, only the first {} are used",
+                palette.colors.len(),
+                MAX_PALETTE_COLORS
+            );
+            palette.colors.truncate(MAX_PALETTE_COLORS);
+            palette.roles.truncate(MAX_PALETTE_COLORS);
+        }
+
+        info!("Generate kdtree");
+
+        let kdtree = TexelTree::build(
+            &palette,
+            &shades,
+            &options,
+            metric
+                .clone()
+                .unwrap_or_else(|| options.metric.color_metric()),
+            &color_priority,
+        );
+
+        return ANSIfier {
+            palette,
+            blocks,
+            shades,
+            kdtree,
+            empty_char: None,
+            out_of_gamut: OutOfGamut::default(),
+            gamut_tolerance: 0.1,
+            dither: Dither::default(),
+            quantize: [8, 8, 8],
+            lut_cache: None,
+            shader_lut: None,
+            text_options: TextOptions::default(),
+            render_options: RenderOptions::default(),
+            substitutions: BTreeMap::new(),
+            tree_queries: AtomicU64::new(0),
+            options,
+            metric,
+            color_priority,
+            previous_grid: None,
+        };
+    }
+
+    pub fn palette(&self) -> &Palette {
+        return &self.palette;
+    }
+
+    pub fn set_empty_char(&mut self, empty_char: Option<char>) {
+        self.empty_char = empty_char;
+    }
+
+    pub fn set_render_options(&mut self, render_options: RenderOptions) {
+        self.render_options = render_options;
+    }
+
+    pub fn set_text_options(&mut self, text_options: TextOptions) {
+        self.text_options = text_options;
+    }
+
+    // Replaces glyphs in the text output only, for fonts that can't display some
+    // of the blocks. Matching and rendered images still use the original glyphs.
+    pub fn set_substitutions(&mut self, substitutions: BTreeMap<char, char>) {
+        self.substitutions = substitutions;
+    }
+
+    // Colors further than the tolerance (in normalized RGB) from every texel are
+    // considered out of gamut
+    pub fn set_out_of_gamut(&mut self, out_of_gamut: OutOfGamut, tolerance: f32) {
+        self.out_of_gamut = out_of_gamut;
+        self.gamut_tolerance = tolerance;
+
+        if let Some(resolution) = self.lut_cache.as_ref().map(|cache| cache.resolution) {
+            self.lut_cache = Some(self.build_lut_cache(resolution));
+        }
+    }
+
+    pub fn set_dither(&mut self, dither: Dither) {
+        self.dither = dither;
+    }
+
+    // Bits of precision kept per channel of the colors looked up, e.g. [5, 6, 5]
+    // for RGB565. Coarser colors match faster since more neighboring pixels end up
+    // identical and reuse one match.
+    pub fn set_quantize(&mut self, quantize: [u8; 3]) {
+        self.quantize = quantize.map(|bits| bits.clamp(1, 8));
+    }
+
+    fn quantize_color(&self, color: &[u8; 3]) -> [u8; 3] {
+        return quantize_color(color, self.quantize);
+    }
+
+    // Precomputes the texel for every cell of a resolution^3 grid over the RGB
+    // cube so match_grid is a table lookup instead of a kd-tree query. A
+    // resolution of 256 gives exactly the same matches as the kd-tree.
+    pub fn with_lut_cache(mut self, resolution: u32) -> ANSIfier {
+        self.lut_cache = Some(self.build_lut_cache(resolution.clamp(1, 256)));
+        return self;
+    }
+
+    // Matches through the LUT generate_lut_and_map builds for the OBS filter
+    // instead of the kd-tree, to reproduce the filter's choices exactly
+    pub fn with_shader_lut(mut self) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        let report = self.lut_compatibility_report();
+        if !report.is_compatible() {
+            return Err(format!(
+                "Palette and blocks are not compatible with the LUT format: {}",
+                report.problems.join(", ")
+            )
+            .into());
+        }
+
+        self.build_shader_lut();
+        return Ok(self);
+    }
+
+    fn build_shader_lut(&mut self) {
+        info!("Building shader LUT");
+
+        #[cfg(feature = "rayon")]
+        let (lut, _) = self.par_generate_lut_and_map();
+        #[cfg(not(feature = "rayon"))]
+        let (lut, _) = self.generate_lut_and_map();
+
+        let (_, blocks) = self.lut_block_indices();
+        self.shader_lut = Some(ShaderLut { lut, blocks });
+    }
+
+    fn build_lut_cache(&self, resolution: u32) -> LutCache {
+        info!("Building LUT cache");
+
+        let texel = |index: u32| {
+            let r = LutCache::center(resolution, index / (resolution * resolution));
+            let g = LutCache::center(resolution, index / resolution % resolution);
+            let b = LutCache::center(resolution, index % resolution);
+            *self.nearest_texel(&[r, g, b])
+        };
+
+        let cells = resolution * resolution * resolution;
+        #[cfg(feature = "rayon")]
+        let texels = (0..cells).into_par_iter().map(texel).collect();
+        #[cfg(not(feature = "rayon"))]
+        let texels = (0..cells).map(texel).collect();
+
+        return LutCache { resolution, texels };
+    }
+
+    // Glyphs that win the match for at least one color, sampled at the LUT cache's
+    // cells or at REACHABILITY_RESOLUTION per channel without one. A glyph whose
+    // blends are always tied or beaten by another glyph's never shows up.
+    pub fn reachable_glyphs(&self) -> BTreeSet<char> {
+        info!("Finding reachable glyphs");
+
+        let sampled;
+        let cache = match &self.lut_cache {
+            Some(cache) => cache,
+            None => {
+                sampled = self.build_lut_cache(REACHABILITY_RESOLUTION);
+                &sampled
+            }
+        };
+
+        return cache.texels.iter().map(|texel| texel.block).collect();
+    }
+
+    fn match_texel(&self, color: &[u8; 3]) -> Texel {
+        let color = &self.quantize_color(color);
+
+        if let Some(shader_lut) = &self.shader_lut {
+            return shader_lut.texel(color);
+        }
+
+        return match &self.lut_cache {
+            Some(cache) => cache.texels[cache.index(color)],
+            None => *self.nearest_texel(color),
+        };
+    }
+
+    fn is_empty_block(&self, block: char) -> bool {
+        return count_foreground_pixels(&self.blocks.blocks[&block]) == 0;
+    }
+
+    pub fn rebuild_with_palette(
+        self,
+        palette: Palette,
+    ) -> Result<ANSIfier, Box<dyn std::error::Error>> {
+        check_palette(&palette)?;
+
+        let shades = self.shades.clone();
+        return Ok(self.rebuild(palette, shades));
+    }
+
+    // Terminal fonts draw the Unicode shading glyphs at their nominal density no
+    // matter what the bitmap looks like, so this matches them by that density
+    // instead. The bitmap is still what gets rendered to images.
+    pub fn rebuild_with_unicode_shading(self, enabled: bool) -> ANSIfier {
+        let shades = generate_shades(&self.blocks, enabled);
+        let palette = self.palette.clone();
+        return self.rebuild(palette, shades);
+    }
+
+    // Texels that blend to exactly the same color are otherwise told apart by palette
+    // order, this prefers the ones showing colors earlier in the priority list
+    pub fn rebuild_with_color_priority(mut self, color_priority: Vec<u8>) -> ANSIfier {
+        self.color_priority = color_priority;
+        let (palette, shades) = (self.palette.clone(), self.shades.clone());
+        return self.rebuild(palette, shades);
+    }
+
+    // Replaces any custom ColorMetric the ANSIfier was created with
+    pub fn rebuild_with_metric(mut self, metric: DistanceMetric) -> ANSIfier {
+        self.options.metric = metric;
+        self.metric = None;
+        let (palette, shades) = (self.palette.clone(), self.shades.clone());
+        return self.rebuild(palette, shades);
+    }
+
+    fn rebuild(self, palette: Palette, shades: Vec<Shade>) -> ANSIfier {
+        let mut ansifier = ANSIfier::build(
+            palette,
+            self.blocks,
+            shades,
+            self.options,
+            self.metric,
+            self.color_priority,
+        );
+        ansifier.empty_char = self.empty_char;
+        ansifier.out_of_gamut = self.out_of_gamut;
+        ansifier.gamut_tolerance = self.gamut_tolerance;
+        ansifier.dither = self.dither;
+        ansifier.quantize = self.quantize;
+        ansifier.text_options = self.text_options;
+        ansifier.render_options = self.render_options;
+        ansifier.substitutions = self.substitutions;
+
+        let mut ansifier = match self.lut_cache {
+            Some(cache) => ansifier.with_lut_cache(cache.resolution),
+            None => ansifier,
+        };
+        // The blocks were already checked when the shader LUT was first built
+        if self.shader_lut.is_some() {
+            ansifier.build_shader_lut();
+        }
+        return ansifier;
+    }
+
+    fn nearest(&self, color: &[u8; 3]) -> (&Texel, f32) {
+        self.tree_queries.fetch_add(1, Ordering::Relaxed);
+        let mut best = self.kdtree.nearest(color);
+
+        if self.out_of_gamut == OutOfGamut::Desaturate && best.1 > self.gamut_tolerance {
+            let color = normalize_color(color);
+
+            // Pull the color toward its own luminance a step at a time so brightness
+            // is kept and only as much saturation as needed is given up
+            const STEPS: u32 = 8;
+            let luminance = 0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2];
+            let gray = [luminance, luminance, luminance];
+
+            for step in 1..=STEPS {
+                let desaturated = blend_two_colors(&gray, &color, step as f32 / STEPS as f32);
+                self.tree_queries.fetch_add(1, Ordering::Relaxed);
+                best = self.kdtree.nearest_normalized(&desaturated.map(f64::from));
+                if best.1 <= self.gamut_tolerance {
+                    break;
+                }
+            }
+        }
+
+        return best;
+    }
+
+    fn nearest_texel(&self, color: &[u8; 3]) -> &Texel {
+        return self.nearest(color).0;
+    }
+
+    // Distance between two normalized colors in the units of the kd-tree
+    fn distance(&self, color_a: &[f32; 3], color_b: &[f32; 3]) -> f32 {
+        return self.kdtree.distance(color_a, color_b);
+    }
+
+    pub fn match_confidence(&self, img: &RgbImage) -> GrayImage {
+        info!("Calculating match confidence");
+
+        // Measured in normalized RGB whatever the metric, so the longest possible
+        // distance is the diagonal of the cube
+        let max_distance = 3.0f32.sqrt();
+
+        let grid = self.match_grid(img);
+        return GrayImage::from_fn(img.width(), img.height(), |x, y| {
+            let texel = &grid[y as usize][x as usize];
+            let distance = color_distance(
+                &normalize_color(&img.get_pixel(x, y).0),
+                &normalize_color(&self.texel_color(texel)),
+            );
+            Luma([((1.0 - distance / max_distance).clamp(0.0, 1.0) * 255.0).round() as u8])
+        });
+    }
+
+    pub fn match_grid(&self, img: &RgbImage) -> Vec<Vec<Texel>> {
+        info!("Matching texels");
+
+        if let Dither::FloydSteinberg { strength } = self.dither {
+            return self.match_grid_floyd_steinberg(img, strength);
+        }
+
+        return (0..img.height()).map(|y| self.match_row(img, y)).collect();
+    }
+
+    fn match_texel_at(&self, color: &[u8; 3], x: u32, y: u32) -> Texel {
+        return match self.dither {
+            Dither::None => self.match_texel(color),
+            Dither::BlueNoise => self.match_texel_blue_noise(color, x, y),
+            // The error from neighboring cells is only known when matching a whole
+            // grid in order, a single cell is matched as is
+            Dither::FloydSteinberg { .. } => self.match_texel(color),
+        };
+    }
+
+    // Matches in scan order, spreading the difference between each cell and its
+    // texel's color over the cells not matched yet with the Floyd-Steinberg
+    // weights. The error is measured in light, like glyphs are blended, so a
+    // dithered area looks as bright as the source. Every cell depends on the ones
+    // before it, so rows can't be matched in parallel.
+    fn match_grid_floyd_steinberg(&self, img: &RgbImage, strength: f32) -> Vec<Vec<Texel>> {
+        let width = img.width() as usize;
+        let decode = |color: &[f32; 3]| color.map(|channel| self.options.gamma.decode(channel));
+
+        // Error carried into this row and the next, padded by a cell on each side
+        let mut current = vec![[0.0f32; 3]; width + 2];
+        let mut next = vec![[0.0f32; 3]; width + 2];
+
+        let mut grid = Vec::with_capacity(img.height() as usize);
+        for y in 0..img.height() {
+            let mut row = Vec::with_capacity(width);
+            for x in 0..width {
+                let source = decode(&normalize_color(&img.get_pixel(x as u32, y).0));
+                let target = [0, 1, 2].map(|i| (source[i] + current[x + 1][i]).clamp(0.0, 1.0));
+                let color = target.map(|channel| self.options.gamma.encode(channel));
+                let texel = self.match_texel(&denormalize_color(&color));
+                let matched = decode(&normalize_color(&self.texel_color(&texel)));
+
+                let error = [0, 1, 2].map(|i| (target[i] - matched[i]) * strength);
+                let spread = |cell: &mut [f32; 3], weight: f32| {
+                    for (channel, error) in cell.iter_mut().zip(error) {
+                        *channel += error * weight;
+                    }
+                };
+                spread(&mut current[x + 2], 7.0 / 16.0);
+                spread(&mut next[x], 3.0 / 16.0);
+                spread(&mut next[x + 1], 5.0 / 16.0);
+                spread(&mut next[x + 2], 1.0 / 16.0);
+
+                row.push(texel);
+            }
+            grid.push(row);
+
+            current = std::mem::replace(&mut next, vec![[0.0; 3]; width + 2]);
+        }
+
+        return grid;
+    }
+
+    // Picks between the two nearest texels by how far the color lies along the
+    // line between them, against a threshold from the tiled blue noise. Every
+    // pixel is decided on its own, so the result doesn't depend on the order or
+    // threads pixels are matched in and a still area stays still in video.
+    fn match_texel_blue_noise(&self, color: &[u8; 3], x: u32, y: u32) -> Texel {
+        let target = normalize_color(color);
+        self.tree_queries.fetch_add(1, Ordering::Relaxed);
+        let candidates = self.kdtree.nearests(color, 2);
+        if candidates.len() < 2 {
+            return candidates[0].1;
+        }
+
+        let (a, b) = (&candidates[0].0, &candidates[1].0);
+        let ab = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        let at = [target[0] - a[0], target[1] - a[1], target[2] - a[2]];
+        let length = ab[0] * ab[0] + ab[1] * ab[1] + ab[2] * ab[2];
+        let t = if length > 0.0 {
+            ((at[0] * ab[0] + at[1] * ab[1] + at[2] * ab[2]) / length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        let threshold = (BLUE_NOISE[(y % 16 * 16 + x % 16) as usize] as f32 + 0.5) / 256.0;
+        return if t > threshold {
+            candidates[1].1
+        } else {
+            candidates[0].1
+        };
+    }
+
+    // Matching is deterministic, so a run of identical pixels (e.g. letterboxing)
+    // reuses the texel matched for the first pixel of the run
+    fn match_row(&self, img: &RgbImage, y: u32) -> Vec<Texel> {
+        let mut row: Vec<Texel> = Vec::with_capacity(img.width() as usize);
+        let mut previous: Option<[u8; 3]> = None;
+
+        for x in 0..img.width() {
+            let color = self.quantize_color(&img.get_pixel(x, y).0);
+            let texel = match (previous, row.last()) {
+                (Some(previous), Some(texel))
+                    if previous == color && self.dither == Dither::None =>
+                {
+                    *texel
+                }
+                _ => self.match_texel_at(&color, x, y),
+            };
+            row.push(texel);
+            previous = Some(color);
+        }
+
+        return row;
+    }
+
+    // Re-matches only the cells inside dirty_rect against the grid from the last
+    // call and returns the cells whose texel changed. The first call, or one with
+    // different dimensions, matches everything and reports every cell. With
+    // Floyd-Steinberg dithering the error from the dirty cells carries into the
+    // rest of the image, so every cell is matched again and compared.
+    pub fn process_dirty(&mut self, img: &RgbImage, dirty_rect: Rect) -> Vec<CellChange> {
+        info!("Matching dirty texels");
+
+        let (width, height) = img.dimensions();
+        let mut grid = match self.previous_grid.take() {
+            Some(grid)
+                if grid.len() == height as usize
+                    && grid.iter().all(|row| row.len() == width as usize) =>
+            {
+                grid
+            }
+            _ => {
+                let grid = self.match_grid(img);
+                let changes = grid
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(y, row)| {
+                        row.iter().enumerate().map(move |(x, texel)| CellChange {
+                            x: x as u32,
+                            y: y as u32,
+                            texel: *texel,
+                        })
+                    })
+                    .collect();
+                self.previous_grid = Some(grid);
+                return changes;
+            }
+        };
+
+        if let Dither::FloydSteinberg { .. } = self.dither {
+            let matched = self.match_grid(img);
+            let mut changes = Vec::new();
+            for (y, (row, matched_row)) in grid.iter().zip(matched.iter()).enumerate() {
+                for (x, (texel, matched_texel)) in row.iter().zip(matched_row).enumerate() {
+                    if texel != matched_texel {
+                        changes.push(CellChange {
+                            x: x as u32,
+                            y: y as u32,
+                            texel: *matched_texel,
+                        });
+                    }
+                }
+            }
+            self.previous_grid = Some(matched);
+            return changes;
+        }
+
+        let right = dirty_rect.x.saturating_add(dirty_rect.width).min(width);
+        let bottom = dirty_rect.y.saturating_add(dirty_rect.height).min(height);
+
+        let mut changes = Vec::new();
+        for y in dirty_rect.y.min(bottom)..bottom {
+            for x in dirty_rect.x.min(right)..right {
+                let texel = self.match_texel_at(&img.get_pixel(x, y).0, x, y);
+                let cell = &mut grid[y as usize][x as usize];
+                if *cell != texel {
+                    *cell = texel;
+                    changes.push(CellChange { x, y, texel });
+                }
+            }
+        }
+
+        self.previous_grid = Some(grid);
+
+        return changes;
+    }
+
+    pub fn previous_grid(&self) -> Option<&Vec<Vec<Texel>>> {
+        return self.previous_grid.as_ref();
+    }
+
+    // Logs every cell of the grid match_grid returns, so dithering, quantization,
+    // the LUTs and out-of-gamut handling all apply. The distance is from the source
+    // color to the color of the texel that was picked.
+    pub fn match_grid_with_log<F: FnMut(MatchLogEntry)>(
+        &self,
+        img: &RgbImage,
+        mut log: F,
+    ) -> Vec<Vec<Texel>> {
+        info!("Matching texels with log");
+
+        let grid = self.match_grid(img);
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                let source = img.get_pixel(x as u32, y as u32).0;
+                let distance = self.distance(
+                    &normalize_color(&source),
+                    &normalize_color(&self.texel_color(texel)),
+                );
+                log(MatchLogEntry {
+                    x: x as u32,
+                    y: y as u32,
+                    source,
+                    texel: *texel,
+                    distance,
+                });
+            }
+        }
+
+        return grid;
+    }
+
+    // The mask is an importance value per cell. Cells with more importance are
+    // quantized less, not at all at full importance, and cells with any skip the
+    // LUT cache for the kd-tree, so they are matched against their own color
+    // rather than a coarse stand-in for it. Cells with none match as in match_grid.
+    pub fn match_grid_with_mask(&self, img: &RgbImage, mask: &GrayImage) -> Vec<Vec<Texel>> {
+        info!("Matching texels with importance mask");
+
+        let mask = if mask.dimensions() == img.dimensions() {
+            mask.clone()
+        } else {
+            image::imageops::resize(mask, img.width(), img.height(), image::imageops::Triangle)
+        };
+
+        return img
+            .enumerate_rows()
+            .map(|(_, row)| {
+                row.map(|(x, y, pixel)| {
+                    let importance = mask.get_pixel(x, y).0[0] as f32 / 255.0;
+                    self.match_texel_with_importance(&pixel.0, importance)
+                })
+                .collect()
+            })
+            .collect();
+    }
+
+    fn match_texel_with_importance(&self, color: &[u8; 3], importance: f32) -> Texel {
+        if importance == 0.0 {
+            return self.match_texel(color);
+        }
+
+        let bits = self
+            .quantize
+            .map(|bits| bits + (importance * (8 - bits) as f32).round() as u8);
+        let color = &quantize_color(color, bits);
+
+        return match &self.shader_lut {
+            Some(shader_lut) => shader_lut.texel(color),
+            None => *self.nearest_texel(color),
+        };
+    }
+
+    // Pixels of the constraint map with any alpha require the palette color whose
+    // index is in their red channel as the foreground of that cell. Indices past
+    // the end of the palette, or of colors not allowed in the foreground, are
+    // ignored. The map is scaled to the image with nearest neighbor sampling so
+    // indices are never blended.
+    pub fn match_grid_with_constraints(
+        &self,
+        img: &RgbImage,
+        constraints: &RgbaImage,
+    ) -> Vec<Vec<Texel>> {
+        info!("Matching texels with constraint map");
+
+        let constraints = if constraints.dimensions() == img.dimensions() {
+            constraints.clone()
+        } else {
+            image::imageops::resize(
+                constraints,
+                img.width(),
+                img.height(),
+                image::imageops::Nearest,
+            )
+        };
+
+        return img
+            .enumerate_rows()
+            .map(|(_, row)| {
+                row.map(|(x, y, pixel)| {
+                    let [index, _, _, alpha] = constraints.get_pixel(x, y).0;
+                    let allowed = self
+                        .palette
+                        .roles
+                        .get(index as usize)
+                        .is_some_and(ColorRole::allows_foreground);
+                    if alpha == 0 || !allowed {
+                        return self.match_texel(&pixel.0);
+                    }
+                    self.match_texel_with_foreground(&pixel.0, index)
+                })
+                .collect()
+            })
+            .collect();
+    }
+
+    // Scores texels the same way the kd-tree does, but only those drawing the
+    // foreground color on a glyph where it is visible
+    fn match_texel_with_foreground(&self, color: &[u8; 3], foreground: u8) -> Texel {
+        let target = normalize_color(color);
+        let foreground_color = normalize_color(&self.palette.colors[foreground as usize]);
+
+        let mut best: Option<(f32, Texel)> = None;
+        for shade in self.shades.iter().filter(|shade| shade.ratio > 0.0) {
+            for (i, background_color) in self.palette.colors.iter().enumerate() {
+                if !self.palette.roles[i].allows_background() {
+                    continue;
+                }
+
+                let blended = blend_with_gamma(
+                    &foreground_color,
+                    &normalize_color(background_color),
+                    shade.ratio,
+                    self.options.gamma,
+                );
+                let distance = self.distance(&target, &blended);
+                if best.is_none_or(|(best_distance, _)| distance < best_distance) {
+                    best = Some((
+                        distance,
+                        Texel {
+                            foreground_color: foreground,
+                            background_color: i as u8,
+                            block: shade.block,
+                        },
+                    ));
+                }
+            }
+        }
+
+        return best.map_or_else(|| self.match_texel(color), |(_, texel)| texel);
+    }
+
+    // Running total of kd-tree lookups
+    pub fn tree_queries(&self) -> u64 {
+        return self.tree_queries.load(Ordering::Relaxed);
+    }
+
+    // Row by row, in the same order as the glyph bitmaps
+    fn cell_pixels(&self, img: &RgbImage, cell_x: u32, cell_y: u32) -> Vec<[u8; 3]> {
+        let block_width = self.blocks.width;
+        let block_height = self.blocks.height;
+
+        let mut pixels = Vec::with_capacity((block_width * block_height) as usize);
+        for j in 0..block_height {
+            for i in 0..block_width {
+                pixels.push(
+                    img.get_pixel(cell_x * block_width + i, cell_y * block_height + j)
+                        .0,
+                );
+            }
+        }
+
+        return pixels;
+    }
+
+    // The glyph whose bitmap best agrees with which pixels are brighter than the
+    // cell's mean, and whether the bright side is its background
+    fn fit_glyph(&self, luminances: &[f32]) -> (char, bool) {
+        let cell_area = luminances.len();
+        let mean = luminances.iter().sum::<f32>() / cell_area as f32;
+        let mask: Vec<bool> = luminances.iter().map(|l| *l > mean).collect();
+
+        // A glyph may be drawn with the bright side as either the foreground
+        // or the background, so both polarities are scored.
+        let mut best: Option<(usize, char, bool)> = None;
+        for (character, bitmap) in self.blocks.blocks.iter() {
+            let bits = bitmap.iter().flat_map(|row| row.iter());
+            let agreement = bits.zip(mask.iter()).filter(|(a, b)| a == b).count();
+            for (score, inverted) in [(agreement, false), (cell_area - agreement, true)] {
+                if best.is_none_or(|(best_score, _, _)| score > best_score) {
+                    best = Some((score, *character, inverted));
+                }
+            }
+        }
+        let (_, block, inverted) = best.unwrap();
+
+        return (block, inverted);
+    }
+
+    pub fn match_grid_structural(&self, img: &RgbImage) -> Vec<Vec<Texel>> {
+        info!("Matching texels structurally");
+
+        let mut grid = Vec::new();
+        for cell_y in 0..img.height() / self.blocks.height {
+            let mut row = Vec::new();
+            for cell_x in 0..img.width() / self.blocks.width {
+                let pixels = self.cell_pixels(img, cell_x, cell_y);
+
+                let (block, inverted) = self.fit_glyph(&cell_luminances(&pixels));
+
+                let mut sums = [[0u32; 3]; 2];
+                let mut counts = [0u32; 2];
+                let bits = self.blocks.blocks[&block].iter().flat_map(|row| row.iter());
+                for (pixel, bit) in pixels.iter().zip(bits) {
+                    let side = (*bit != inverted) as usize;
+                    for c in 0..3 {
+                        sums[side][c] += pixel[c] as u32;
+                    }
+                    counts[side] += 1;
+                }
+                let average = |side: usize| -> [u8; 3] {
+                    let (sum, count) = if counts[side] == 0 {
+                        (
+                            [0, 1, 2].map(|c| sums[0][c] + sums[1][c]),
+                            counts[0] + counts[1],
+                        )
+                    } else {
+                        (sums[side], counts[side])
+                    };
+                    return sum.map(|channel| (channel / count.max(1)) as u8);
+                };
+
+                let bright = self.palette.nearest_index(&average(1)) as u8;
+                let dark = self.palette.nearest_index(&average(0)) as u8;
+                let (foreground_color, background_color) = if inverted {
+                    (dark, bright)
+                } else {
+                    (bright, dark)
+                };
+
+                row.push(Texel {
+                    foreground_color,
+                    background_color,
+                    block,
+                });
+            }
+            grid.push(row);
+        }
+
+        return grid;
+    }
+
+    // Colors come from matching the cell averages like match_grid, and glyphs from
+    // fitting the full resolution luminance inside each cell like
+    // match_grid_structural. A cell only takes the detail glyph when its luminance
+    // range is more than 1 - detail_strength of full scale, so 0 keeps the coarse
+    // pass and 1 adds detail to every cell with any contrast.
+    pub fn match_grid_two_scale(
+        &self,
+        full_res_img: &RgbImage,
+        detail_strength: f32,
+    ) -> Vec<Vec<Texel>> {
+        info!("Matching texels at two scales");
+
+        let columns = full_res_img.width() / self.blocks.width;
+        let rows = full_res_img.height() / self.blocks.height;
+
+        let cells: Vec<Vec<Vec<[u8; 3]>>> = (0..rows)
+            .map(|cell_y| {
+                (0..columns)
+                    .map(|cell_x| self.cell_pixels(full_res_img, cell_x, cell_y))
+                    .collect()
+            })
+            .collect();
+
+        let coarse = RgbImage::from_fn(columns, rows, |x, y| {
+            let pixels = &cells[y as usize][x as usize];
+            let mut sum = [0u32; 3];
+            for pixel in pixels.iter() {
+                for c in 0..3 {
+                    sum[c] += pixel[c] as u32;
+                }
+            }
+            Rgb(sum.map(|channel| (channel / pixels.len() as u32) as u8))
+        });
+        let mut grid = self.match_grid(&coarse);
+
+        let threshold = (1.0 - detail_strength) * 255.0;
+        let color_luminance =
+            |index: u8| cell_luminances(&[self.palette.colors[index as usize]])[0];
+
+        for (row, cell_row) in grid.iter_mut().zip(cells.iter()) {
+            for (texel, pixels) in row.iter_mut().zip(cell_row.iter()) {
+                // A solid or empty glyph only shows one real color, so there is no
+                // second color to draw the detail with
+                let ratio = self
+                    .shades
+                    .iter()
+                    .find(|shade| shade.block == texel.block)
+                    .map_or(1.0, |shade| shade.ratio);
+                if ratio == 0.0 || ratio == 1.0 {
+                    continue;
+                }
+
+                let luminances = cell_luminances(pixels);
+                let min = luminances.iter().cloned().fold(f32::INFINITY, f32::min);
+                let max = luminances.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                if max - min <= threshold {
+                    continue;
+                }
+
+                let (block, inverted) = self.fit_glyph(&luminances);
+                let (bright, dark) = if color_luminance(texel.foreground_color)
+                    >= color_luminance(texel.background_color)
+                {
+                    (texel.foreground_color, texel.background_color)
+                } else {
+                    (texel.background_color, texel.foreground_color)
+                };
+                let (foreground_color, background_color) = if inverted {
+                    (dark, bright)
+                } else {
+                    (bright, dark)
+                };
+
+                *texel = Texel {
+                    foreground_color,
+                    background_color,
+                    block,
+                };
+            }
+        }
+
+        return grid;
+    }
+
+    pub fn posterize(&self, grid: &mut [Vec<Texel>], colors: usize) {
+        info!("Posterizing");
+
+        // Only count a color where it is actually visible in the glyph
+        let coverage: BTreeMap<char, u32> = self
+            .blocks
+            .blocks
+            .iter()
+            .map(|(character, bitmap)| (*character, count_foreground_pixels(bitmap)))
+            .collect();
+        let area = self.blocks.width * self.blocks.height;
+
+        let mut usage = BTreeMap::<u8, u32>::new();
+        for texel in grid.iter().flatten() {
+            let foreground_pixels = coverage[&texel.block];
+            if foreground_pixels > 0 {
+                *usage.entry(texel.foreground_color).or_insert(0) += foreground_pixels;
+            }
+            if foreground_pixels < area {
+                *usage.entry(texel.background_color).or_insert(0) += area - foreground_pixels;
+            }
+        }
+
+        if usage.len() <= colors.max(1) {
+            return;
+        }
+
+        // Agglomerative clustering, repeatedly merging the two closest weighted centroids
+        let mut clusters: Vec<(Vec<u8>, [f32; 3], f32)> = usage
+            .iter()
+            .map(|(index, count)| {
+                (
+                    vec![*index],
+                    normalize_color(&self.palette.colors[*index as usize]),
+                    *count as f32,
+                )
+            })
+            .collect();
+
+        while clusters.len() > colors.max(1) {
+            let mut closest = (0, 1, f32::INFINITY);
+            for i in 0..clusters.len() {
+                for j in i + 1..clusters.len() {
+                    let distance = color_distance(&clusters[i].1, &clusters[j].1);
+                    if distance < closest.2 {
+                        closest = (i, j, distance);
+                    }
+                }
+            }
+
+            let (members, centroid, weight) = clusters.swap_remove(closest.1);
+            let cluster = &mut clusters[closest.0];
+            let total = cluster.2 + weight;
+            for (channel, other) in cluster.1.iter_mut().zip(centroid) {
+                *channel = (*channel * cluster.2 + other * weight) / total;
+            }
+            cluster.0.extend(members);
+            cluster.2 = total;
+        }
+
+        let mut remap = BTreeMap::<u8, u8>::new();
+        for (members, _, _) in clusters.iter() {
+            let representative = *members.iter().max_by_key(|index| usage[*index]).unwrap();
+            for member in members {
+                remap.insert(*member, representative);
+            }
+        }
+
+        for texel in grid.iter_mut().flatten() {
+            if let Some(index) = remap.get(&texel.foreground_color) {
+                texel.foreground_color = *index;
+            }
+            if let Some(index) = remap.get(&texel.background_color) {
+                texel.background_color = *index;
+            }
+        }
+    }
+
+    pub fn render_grid(&self, grid: &[Vec<Texel>]) -> RgbImage {
+        info!("Creating output image");
+
+        let grid_width = grid.first().map_or(0, |row| row.len()) as u32;
+        let grid_height = grid.len() as u32;
+
+        let (width, height) = self.output_dimensions((grid_width, grid_height));
+        let mut out = RgbImage::new(width, height);
+
+        info!("Generating output");
+
+        let row_pitch = self.blocks.height + self.render_options.row_gap;
+
+        for (y, row) in grid.iter().enumerate() {
+            // The gap below the last row would be outside the image
+            let gap = if y as u32 + 1 < grid_height {
+                self.render_options.row_gap
+            } else {
+                0
+            };
+            for (x, texel) in row.iter().enumerate() {
+                let foreground_color = self.palette.colors[texel.foreground_color as usize];
+                let background_color = self.palette.colors[texel.background_color as usize];
+                for i in 0..self.blocks.width {
+                    for j in 0..self.blocks.height + gap {
+                        let foreground = j < self.blocks.height
+                            && self.blocks.blocks[&texel.block][j as usize][i as usize];
+                        out.put_pixel(
+                            x as u32 * self.blocks.width + i,
+                            y as u32 * row_pitch + j,
+                            Rgb(if foreground {
+                                foreground_color
+                            } else {
+                                background_color
+                            }),
+                        );
+                    }
+                }
+            }
+        }
+
+        return out;
+    }
+
+    pub fn render_grid_rgba(&self, grid: &[Vec<Texel>], alpha: &GrayImage) -> RgbaImage {
+        let out = self.render_grid(grid);
+
+        let row_pitch = self.blocks.height + self.render_options.row_gap;
+        let cell_alpha =
+            |x: u32, y: u32| alpha.get_pixel(x / self.blocks.width, y / row_pitch).0[0];
+
+        return RgbaImage::from_fn(out.width(), out.height(), |x, y| {
+            let [r, g, b] = out.get_pixel(x, y).0;
+            let a = if self.render_options.aa_edges && self.is_glyph_pixel(grid, x, y) {
+                // The mean alpha of the 3x3 neighborhood, so a glyph edge against a
+                // transparent cell fades out instead of stepping
+                let mut sum = 0u32;
+                let mut count = 0u32;
+                for ny in y.saturating_sub(1)..(y + 2).min(out.height()) {
+                    for nx in x.saturating_sub(1)..(x + 2).min(out.width()) {
+                        sum += cell_alpha(nx, ny) as u32;
+                        count += 1;
+                    }
+                }
+                (sum as f32 / count as f32).round() as u8
+            } else {
+                cell_alpha(x, y)
+            };
+            Rgba([r, g, b, a])
+        });
+    }
+
+    // Whether a pixel of the rendered image is drawn in its cell's foreground color
+    fn is_glyph_pixel(&self, grid: &[Vec<Texel>], x: u32, y: u32) -> bool {
+        let row_pitch = self.blocks.height + self.render_options.row_gap;
+        let (i, j) = (x % self.blocks.width, y % row_pitch);
+        if j >= self.blocks.height {
+            return false;
+        }
+
+        let texel = &grid[(y / row_pitch) as usize][(x / self.blocks.width) as usize];
+        return self.blocks.blocks[&texel.block][j as usize][i as usize];
+    }
+
+    // Renders each cell from a grayscale atlas of the glyphs as a terminal draws
+    // them, laid out like a sprite sheet with one cell per character in `chars`.
+    // The atlas brightness blends from the background to the foreground color so
+    // antialiased edges come out as they do on screen.
+    pub fn render_with_atlas(
+        &self,
+        grid: &[Vec<Texel>],
+        atlas: &GrayImage,
+        cell_width: u32,
+        cell_height: u32,
+        chars: &str,
+    ) -> Result<RgbImage, Box<dyn std::error::Error>> {
+        info!("Creating output image from atlas");
+
+        let cells = sprite_cells(atlas, cell_width, cell_height, chars)?;
+
+        let grid_width = grid.first().map_or(0, |row| row.len()) as u32;
+        let mut out = RgbImage::new(grid_width * cell_width, grid.len() as u32 * cell_height);
+
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                // The atlas shows what the terminal prints, so substitutions apply
+                let character = self.texel_char(texel);
+                let (left, top) = *cells
+                    .get(&character)
+                    .ok_or_else(|| format!("atlas has no cell for `{}`", character))?;
+
+                let foreground_color = self.palette.colors[texel.foreground_color as usize];
+                let background_color = self.palette.colors[texel.background_color as usize];
+                for j in 0..cell_height {
+                    for i in 0..cell_width {
+                        let coverage = atlas.get_pixel(left + i, top + j)[0] as f32 / 255.0;
+                        let color = [0, 1, 2].map(|c| {
+                            let background = background_color[c] as f32;
+                            let foreground = foreground_color[c] as f32;
+                            (background + (foreground - background) * coverage).round() as u8
+                        });
+                        out.put_pixel(
+                            x as u32 * cell_width + i,
+                            y as u32 * cell_height + j,
+                            Rgb(color),
+                        );
+                    }
+                }
+            }
+        }
+
+        return Ok(out);
+    }
+
+    pub fn texel_char(&self, texel: &Texel) -> char {
+        let block = match self.empty_char {
+            Some(empty_char) if self.is_empty_block(texel.block) => empty_char,
+            _ => texel.block,
+        };
+        return *self.substitutions.get(&block).unwrap_or(&block);
+    }
+
+    fn row_text(&self, row: &[Texel]) -> String {
+        let mut text = String::new();
+
+        for texel in row {
+            text.push_str(
+                &Fixed(texel.foreground_color)
+                    .on(Fixed(texel.background_color))
+                    .paint(self.texel_char(texel).to_string())
+                    .to_string(),
+            );
+        }
+        text.push('\n');
+
+        return text;
+    }
 
-        let file = File::open(path)?;
-        return Ok(serde_yaml::from_reader(&file)?);
+    pub fn grid_text(&self, grid: &[Vec<Texel>]) -> String {
+        let text: String = grid.iter().map(|row| self.row_text(row)).collect();
+        return self.finish_color_text(text);
     }
-}
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
-pub struct Blocks {
-    width: u32,
-    height: u32,
-    blocks: BTreeMap<char, Vec<Vec<bool>>>,
-}
+    // Only emits the colors that changed since the previous cell, and resets at
+    // the end of each row so the background doesn't run on past the art
+    pub fn grid_text_minimal(&self, grid: &[Vec<Texel>]) -> String {
+        let mut text = String::new();
 
-impl Blocks {
-    pub fn from(path: PathBuf) -> Result<Blocks, Box<dyn std::error::Error>> {
-        info!("Opening and parsing blocks");
+        for row in grid {
+            let mut current: Option<(u8, u8)> = None;
+            for texel in row {
+                let foreground = texel.foreground_color;
+                let background = texel.background_color;
+                match current {
+                    Some((f, b)) if f == foreground && b == background => {}
+                    Some((f, _)) if f == foreground => {
+                        text.push_str(&format!("\x1b[48;5;{}m", background))
+                    }
+                    Some((_, b)) if b == background => {
+                        text.push_str(&format!("\x1b[38;5;{}m", foreground))
+                    }
+                    _ => text.push_str(&format!("\x1b[38;5;{};48;5;{}m", foreground, background)),
+                }
+                current = Some((foreground, background));
+                text.push(self.texel_char(texel));
+            }
+            if current.is_some() {
+                text.push_str("\x1b[0m");
+            }
+            text.push('\n');
+        }
 
-        let file2 = File::open(path)?;
-        let blocks: Blocks = serde_yaml::from_reader(&file2)?;
+        return self.finish_text(text);
+    }
 
-        info!("Verifying block dimensions");
+    // Each row's text is built on its own and the rows are joined in order
+    // afterwards, so the output is identical to grid_text without any shared
+    // buffer between threads
+    #[cfg(feature = "rayon")]
+    pub fn par_grid_text(&self, grid: &[Vec<Texel>]) -> String {
+        let rows: Vec<String> = grid.par_iter().map(|row| self.row_text(row)).collect();
+        return self.finish_color_text(rows.concat());
+    }
 
-        for (_character, bitmap) in blocks.blocks.iter() {
-            assert!(bitmap.len() == blocks.height as usize);
-            for row in bitmap {
-                assert!(row.len() == blocks.width as usize);
+    fn finish_color_text(&self, mut text: String) -> String {
+        if self.text_options.final_reset {
+            text.push_str("\x1b[0m");
+        }
+
+        return self.finish_text(text);
+    }
+
+    fn finish_text(&self, mut text: String) -> String {
+        if !self.text_options.trailing_newline {
+            if let Some(last_newline) = text.rfind('\n') {
+                text.remove(last_newline);
             }
         }
 
-        return Ok(blocks);
+        return text;
     }
 
-    pub fn width(&self) -> u32 {
-        self.width
+    pub fn grid_plain(&self, grid: &[Vec<Texel>]) -> String {
+        let mut text = String::new();
+
+        for row in grid {
+            for texel in row {
+                text.push(self.texel_char(texel));
+            }
+            text.push('\n');
+        }
+
+        return self.finish_text(text);
     }
 
-    pub fn height(&self) -> u32 {
-        self.height
+    pub fn grid_csv(&self, grid: &[Vec<Texel>]) -> String {
+        let mut csv = String::new();
+
+        for row in grid {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|texel| match self.texel_char(texel) {
+                    c @ (',' | '"' | '\n' | '\r') => {
+                        format!("\"{}\"", c.to_string().replace('"', "\"\""))
+                    }
+                    c => c.to_string(),
+                })
+                .collect();
+            csv.push_str(&cells.join(","));
+            csv.push('\n');
+        }
+
+        return csv;
     }
-}
 
-struct Shade {
-    ratio: f32,
-    block: char,
-}
+    pub fn grid_rust(&self, grid: &[Vec<Texel>], name: &str) -> String {
+        let grid_width = grid.first().map_or(0, |row| row.len());
 
-struct Texel {
-    foreground_color: u8,
-    background_color: u8,
-    block: char,
-}
+        let mut source = format!(
+            "pub const {}_TEXT: &str = {:?};\n\n",
+            name,
+            self.grid_text(grid)
+        );
 
-fn count_foreground_pixels(bitmap: &Vec<Vec<bool>>) -> u32 {
-    return bitmap
-        .into_iter()
-        .flat_map(IntoIterator::into_iter)
-        .map(|x| *x as u32)
-        .sum();
-}
+        source.push_str(&format!(
+            "pub const {}_GRID: [[(u8, u8, char); {}]; {}] = [\n",
+            name,
+            grid_width,
+            grid.len()
+        ));
+        for row in grid {
+            let cells: Vec<String> = row
+                .iter()
+                .map(|texel| {
+                    format!(
+                        "({}, {}, {:?})",
+                        texel.foreground_color, texel.background_color, texel.block
+                    )
+                })
+                .collect();
+            source.push_str(&format!("    [{}],\n", cells.join(", ")));
+        }
+        source.push_str("];\n");
 
-fn blend_two_colors(color_a: &[f32; 3], color_b: &[f32; 3], ratio: f32) -> [f32; 3] {
-    return [
-        color_a[0] * ratio + color_b[0] * (1.0 - ratio),
-        color_a[1] * ratio + color_b[1] * (1.0 - ratio),
-        color_a[2] * ratio + color_b[2] * (1.0 - ratio),
-    ];
-}
+        return source;
+    }
 
-fn normalize_color(color: &[u8; 3]) -> [f32; 3] {
-    return [
-        color[0] as f32 / 255.0,
-        color[1] as f32 / 255.0,
-        color[2] as f32 / 255.0,
-    ];
-}
+    // The closest mIRC color code for each palette color, IRC has no way to send
+    // arbitrary colors
+    pub fn mirc_indices(&self, colors: MircColors) -> Vec<u8> {
+        let mirc_colors = match colors {
+            MircColors::Standard => &MIRC_COLORS[..16],
+            MircColors::Extended => &MIRC_COLORS[..],
+        };
 
-pub struct ANSIfier {
-    palette: Palette,
-    pub blocks: Blocks,
-    kdtree: KdMap<[f32; 3], Texel>,
-}
+        return self
+            .palette
+            .colors
+            .iter()
+            .map(|color| {
+                let color = normalize_color(color);
+                (0..mirc_colors.len())
+                    .min_by(|a, b| {
+                        let distance_a = color_distance(&color, &normalize_color(&mirc_colors[*a]));
+                        let distance_b = color_distance(&color, &normalize_color(&mirc_colors[*b]));
+                        distance_a.total_cmp(&distance_b)
+                    })
+                    .unwrap() as u8
+            })
+            .collect();
+    }
 
-impl ANSIfier {
-    pub fn new(palette: Palette, blocks: Blocks) -> ANSIfier {
-        info!("Generating shades");
-
-        let mut shades = Vec::new();
-        for (character, bitmap) in blocks.blocks.iter() {
-            shades.push(Shade {
-                ratio: count_foreground_pixels(bitmap) as f32
-                    / (blocks.width * blocks.height) as f32,
-                block: *character,
-            });
+    // Colors are always sent as two digits with both foreground and background,
+    // so a following digit or comma in the text can't be read as part of the code
+    pub fn grid_mirc(&self, grid: &[Vec<Texel>], colors: MircColors) -> String {
+        let indices = self.mirc_indices(colors);
+        let mut text = String::new();
+
+        for row in grid {
+            let mut current = None;
+            for texel in row {
+                let code = (
+                    indices[texel.foreground_color as usize],
+                    indices[texel.background_color as usize],
+                );
+                if current != Some(code) {
+                    text.push_str(&format!("\x03{:02},{:02}", code.0, code.1));
+                    current = Some(code);
+                }
+                text.push(self.texel_char(texel));
+            }
+            text.push('\n');
         }
 
-        info!("Generating texels");
+        return self.finish_text(text);
+    }
 
-        let mut texels = Vec::new();
+    // A <pre> block with one span per cell colored with the palette's RGB values
+    pub fn grid_html(&self, grid: &[Vec<Texel>]) -> String {
+        let mut html = String::from("<pre>");
 
-        for shade in shades.iter() {
-            if shade.ratio == 0.0 {
-                for (i, color) in palette.colors.iter().enumerate() {
-                    texels.push((
-                        normalize_color(color),
-                        Texel {
-                            foreground_color: 0 as u8,
-                            background_color: i as u8,
-                            block: shade.block,
-                        },
-                    ));
-                }
-            } else if shade.ratio == 1.0 {
-                for (i, color) in palette.colors.iter().enumerate() {
-                    texels.push((
-                        normalize_color(color),
-                        Texel {
-                            foreground_color: i as u8,
-                            background_color: 0 as u8,
-                            block: shade.block,
-                        },
-                    ));
-                }
-            } else {
-                for (i, foreground_color) in palette.colors.iter().enumerate() {
-                    for (j, background_color) in palette.colors.iter().enumerate() {
-                        if foreground_color == background_color {
-                            continue;
-                        }
-                        let color = blend_two_colors(
-                            &normalize_color(foreground_color),
-                            &normalize_color(background_color),
-                            shade.ratio,
-                        );
-                        texels.push((
-                            color,
-                            Texel {
-                                foreground_color: i as u8,
-                                background_color: j as u8,
-                                block: shade.block,
-                            },
-                        ));
-                    }
-                }
+        for row in grid {
+            for texel in row {
+                let [fr, fg, fb] = self.palette.colors[texel.foreground_color as usize];
+                let [br, bg, bb] = self.palette.colors[texel.background_color as usize];
+                let block = match self.texel_char(texel) {
+                    '<' => String::from("&lt;"),
+                    '>' => String::from("&gt;"),
+                    '&' => String::from("&amp;"),
+                    block => block.to_string(),
+                };
+                html.push_str(&format!(
+                    "<span style=\"color:#{:02x}{:02x}{:02x};background-color:#{:02x}{:02x}{:02x}\">{}</span>",
+                    fr, fg, fb, br, bg, bb, block
+                ));
             }
+            html.push('\n');
         }
+        html.push_str("</pre>\n");
 
-        info!("Generate kdtree");
+        return html;
+    }
 
-        return ANSIfier {
-            palette,
-            blocks,
-            #[cfg(feature = "rayon")]
-            kdtree: KdMap::par_build_by_ordered_float(texels),
-            #[cfg(not(feature = "rayon"))]
-            kdtree: KdMap::build_by_ordered_float(texels),
-        };
+    pub fn grid_html_page(&self, grid: &[Vec<Texel>]) -> String {
+        return format!(
+            concat!(
+                "<!DOCTYPE html>\n",
+                "<html>\n",
+                "<head>\n",
+                "<meta charset=\"utf-8\">\n",
+                "<title>ansify</title>\n",
+                "<style>\n",
+                "body {{ margin: 0; min-height: 100vh; display: flex; align-items: center; justify-content: center; background-color: #111; }}\n",
+                "pre {{ margin: 0; line-height: 1; font-family: \"DejaVu Sans Mono\", Menlo, Consolas, \"Courier New\", monospace; }}\n",
+                "</style>\n",
+                "</head>\n",
+                "<body>\n",
+                "{}",
+                "</body>\n",
+                "</html>\n"
+            ),
+            self.grid_html(grid)
+        );
     }
 
     pub fn process(&self, img: &RgbImage) -> (RgbImage, String) {
-        info!("Creating output image");
+        let grid = self.match_grid(img);
+        return (self.render_grid(&grid), self.grid_text(&grid));
+    }
 
-        let mut out = RgbImage::new(
-            img.width() * self.blocks.width,
-            img.height() * self.blocks.height,
-        );
-        let mut text = String::new();
+    pub fn process_two_scale(
+        &self,
+        full_res_img: &RgbImage,
+        detail_strength: f32,
+    ) -> (RgbImage, String) {
+        let grid = self.match_grid_two_scale(full_res_img, detail_strength);
+        return (self.render_grid(&grid), self.grid_text(&grid));
+    }
 
-        info!("Generating output");
+    pub fn process_dynamic(&self, img: &DynamicImage) -> (RgbImage, String) {
+        return self.process(&flatten_to_rgb(img));
+    }
 
-        for (x, y, pixel) in img.enumerate_pixels() {
-            let nearest = self
-                .kdtree
-                .nearest(&[
-                    pixel.0[0] as f32 / 255.0,
-                    pixel.0[1] as f32 / 255.0,
-                    pixel.0[2] as f32 / 255.0,
-                ])
-                .unwrap()
-                .item;
-            let texel = &nearest.1;
-            text.push_str(
-                &Fixed(texel.foreground_color)
-                    .on(Fixed(texel.background_color))
-                    .paint(texel.block.to_string())
-                    .to_string(),
-            );
+    // The colors are laid out row by row, `width` per row. A short last row is
+    // padded with black.
+    pub fn process_colors(&self, colors: &[[u8; 3]], width: u32) -> (RgbImage, String) {
+        let width = width.max(1);
+        let height = (colors.len() as u32).div_ceil(width).max(1);
 
-            if x + 1 == img.width() {
-                text.push('\n');
-            }
-            let foreground_color = self.palette.colors[texel.foreground_color as usize];
-            let background_color = self.palette.colors[texel.background_color as usize];
-            for i in 0..self.blocks.width {
-                for j in 0..self.blocks.height {
-                    out.put_pixel(
-                        x * self.blocks.width + i,
-                        y * self.blocks.height + j,
-                        Rgb {
-                            0: if self.blocks.blocks[&texel.block][j as usize][i as usize] {
-                                foreground_color
-                            } else {
-                                background_color
-                            },
-                        },
-                    );
-                }
+        let img = RgbImage::from_fn(width, height, |x, y| {
+            Rgb(*colors.get((x + y * width) as usize).unwrap_or(&[0, 0, 0]))
+        });
+
+        return self.process(&img);
+    }
+
+    #[cfg(feature = "rayon")]
+    pub fn par_match_grid(&self, img: &RgbImage) -> Vec<Vec<Texel>> {
+        if let Dither::FloydSteinberg { .. } = self.dither {
+            return self.match_grid(img);
+        }
+
+        info!("Matching texels in parallel");
+
+        return (0..img.height())
+            .into_par_iter()
+            .map(|y| self.match_row(img, y))
+            .collect();
+    }
+
+    #[cfg(feature = "rayon")]
+    pub fn par_process(&self, img: &RgbImage) -> (RgbImage, String) {
+        let grid = self.par_match_grid(img);
+        return (self.render_grid(&grid), self.par_grid_text(&grid));
+    }
+
+    // The blended color the matcher compares against for a texel
+    fn texel_color(&self, texel: &Texel) -> [u8; 3] {
+        let ratio = self
+            .shades
+            .iter()
+            .find(|shade| shade.block == texel.block)
+            .map_or(1.0, |shade| shade.ratio);
+
+        return denormalize_color(&blend_with_gamma(
+            &normalize_color(&self.palette.colors[texel.foreground_color as usize]),
+            &normalize_color(&self.palette.colors[texel.background_color as usize]),
+            ratio,
+            self.options.gamma,
+        ));
+    }
+
+    pub fn process_with_constraints(
+        &self,
+        img: &RgbImage,
+        constraints: &RgbaImage,
+    ) -> (RgbImage, String) {
+        let grid = self.match_grid_with_constraints(img, constraints);
+        return (self.render_grid(&grid), self.grid_text(&grid));
+    }
+
+    pub fn process_with_mask(&self, img: &RgbImage, mask: &GrayImage) -> (RgbImage, String) {
+        let grid = self.match_grid_with_mask(img, mask);
+        return (self.render_grid(&grid), self.grid_text(&grid));
+    }
+
+    pub fn process_thumbnail(&self, img: &RgbImage) -> RgbImage {
+        let grid = self.match_grid(img);
+
+        return RgbImage::from_fn(img.width(), img.height(), |x, y| {
+            Rgb(self.texel_color(&grid[y as usize][x as usize]))
+        });
+    }
+
+    // Sums up how well grid, matched from img in match_duration, fits it. Only the
+    // cells img and grid have in common are compared.
+    pub fn match_stats(
+        &self,
+        img: &RgbImage,
+        grid: &[Vec<Texel>],
+        match_duration: Duration,
+    ) -> MatchStats {
+        let columns = grid.first().map_or(0, |row| row.len()) as u32;
+        let rows = grid.len() as u32;
+        let (output_width, output_height) = self.output_dimensions((columns, rows));
+
+        let mut total_error = 0.0f64;
+        let mut max_error = 0.0f32;
+        let mut compared = 0u64;
+        for (y, row) in grid.iter().enumerate().take(img.height() as usize) {
+            for (x, texel) in row.iter().enumerate().take(img.width() as usize) {
+                let error = self.distance(
+                    &normalize_color(&img.get_pixel(x as u32, y as u32).0),
+                    &normalize_color(&self.texel_color(texel)),
+                );
+                total_error += error as f64;
+                max_error = max_error.max(error);
+                compared += 1;
+            }
+        }
+        let mean_error = if compared > 0 {
+            (total_error / compared as f64) as f32
+        } else {
+            0.0
+        };
+
+        // The hidden color of a solid or empty glyph isn't counted
+        let mut color_usage = vec![0u64; self.palette.colors.len()];
+        for texel in grid.iter().flatten() {
+            let ratio = self
+                .shades
+                .iter()
+                .find(|shade| shade.block == texel.block)
+                .map_or(1.0, |shade| shade.ratio);
+            if ratio > 0.0 {
+                color_usage[texel.foreground_color as usize] += 1;
+            }
+            if ratio < 1.0 && texel.background_color != texel.foreground_color {
+                color_usage[texel.background_color as usize] += 1;
             }
         }
 
-        return (out, text);
+        return MatchStats {
+            palette_size: self.palette.colors.len(),
+            blocks: self.blocks.blocks.len(),
+            texels: self.kdtree.len(),
+            columns,
+            rows,
+            output_width,
+            output_height,
+            mean_error,
+            max_error,
+            color_usage,
+            match_duration,
+        };
+    }
+
+    // The nearest solid palette color per cell, ignoring glyphs and blends, for
+    // comparing against the full output to see what the blends are adding
+    pub fn palette_coverage_image(&self, img: &RgbImage) -> RgbImage {
+        return RgbImage::from_fn(img.width(), img.height(), |x, y| {
+            Rgb(self.palette.colors[self.palette.nearest_index(&img.get_pixel(x, y).0)])
+        });
+    }
+
+    pub fn process_rgba(
+        &self,
+        img: &RgbaImage,
+        alpha_threshold: Option<f32>,
+    ) -> (RgbaImage, String) {
+        let (rgb, alpha) = split_alpha(img, alpha_threshold);
+
+        let grid = self.match_grid(&rgb);
+        return (self.render_grid_rgba(&grid, &alpha), self.grid_text(&grid));
+    }
+
+    pub fn process_plain(&self, img: &RgbImage) -> String {
+        return self.grid_plain(&self.match_grid(img));
+    }
+
+    pub fn process_csv(&self, img: &RgbImage) -> String {
+        return self.grid_csv(&self.match_grid(img));
+    }
+
+    pub fn process_mirc(&self, img: &RgbImage, colors: MircColors) -> String {
+        return self.grid_mirc(&self.match_grid(img), colors);
+    }
+
+    pub fn process_html(&self, img: &RgbImage) -> String {
+        return self.grid_html(&self.match_grid(img));
+    }
+
+    pub fn process_html_page(&self, img: &RgbImage) -> String {
+        return self.grid_html_page(&self.match_grid(img));
     }
 
     pub fn calculate_new_dimensions(
@@ -240,15 +4411,53 @@ impl ANSIfier {
     ) -> (u32, u32) {
         info!("Calculating dimension and resizing");
 
+        // Degenerate sources (1xN, Nx1, or empty) can make the aspect ratio
+        // truncate to a zero-sized grid, so every axis is kept at least one cell.
+        let original_dimensions = (original_dimensions.0.max(1), original_dimensions.1.max(1));
+
         let ratio = (original_dimensions.0 as f32 / self.block_width() as f32)
             / (original_dimensions.1 as f32 / self.block_height() as f32);
 
-        return match desired_dimensions {
+        let dimensions = match desired_dimensions {
             (None, None) => original_dimensions,
             (Some(width), None) => (width, (width as f32 / ratio) as u32),
             (None, Some(height)) => ((height as f32 * ratio) as u32, height),
             (Some(width), Some(height)) => (width, height),
         };
+
+        return (dimensions.0.max(1), dimensions.1.max(1));
+    }
+
+    pub fn check_output_pixels(
+        &self,
+        dimensions: (u32, u32),
+        max_output_pixels: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let width = dimensions.0 as u64 * self.block_width() as u64;
+        let height = dimensions.1 as u64 * self.block_height() as u64
+            + dimensions.1.saturating_sub(1) as u64 * self.render_options.row_gap as u64;
+
+        if width * height > max_output_pixels {
+            return Err(format!(
+                "Output would be {}x{} pixels ({} total), over the limit of {} pixels",
+                width,
+                height,
+                width * height,
+                max_output_pixels
+            )
+            .into());
+        }
+
+        return Ok(());
+    }
+
+    // The size in pixels of the image rendered from a grid of the given size in cells
+    pub fn output_dimensions(&self, dimensions: (u32, u32)) -> (u32, u32) {
+        return (
+            dimensions.0 * self.blocks.width,
+            dimensions.1 * self.blocks.height
+                + dimensions.1.saturating_sub(1) * self.render_options.row_gap,
+        );
     }
 
     pub fn block_width(&self) -> u32 {
@@ -259,10 +4468,52 @@ impl ANSIfier {
         self.blocks.height()
     }
 
-    pub fn generate_lut_and_map(&self) -> (RgbaImage, RgbaImage) {
-        assert!(self.palette.colors.len() <= 256);
+    // Checks the limits generate_lut_and_map asserts on, without generating the LUT
+    pub fn lut_compatibility_report(&self) -> LutCompatibilityReport {
+        let colors = self.palette.colors.len();
+        let blocks = self.blocks.blocks.len();
+        let block_area = self.block_width() * self.block_height();
+
+        let mut problems = Vec::new();
+        if blocks > 256 {
+            problems.push(format!("{} blocks, but the LUT holds at most 256", blocks));
+        }
+        if block_area > MAX_LUT_BLOCK_AREA {
+            problems.push(format!(
+                "{}x{} blocks cover {} pixels, but the map holds at most {}",
+                self.block_width(),
+                self.block_height(),
+                block_area,
+                MAX_LUT_BLOCK_AREA
+            ));
+        }
+
+        // The LUT and map images, 4 bytes per pixel
+        let estimated_memory_bytes = (4096 * 4096 + 256 * 3) * 4;
+
+        // Time a sample of the lookups spread over the LUT and scale it up
+        const SAMPLES: u32 = 4096;
+        let start = Instant::now();
+        for i in 0..SAMPLES {
+            let color = (i * ((1 << 24) / SAMPLES)).to_be_bytes();
+            self.nearest_texel(&[color[1], color[2], color[3]]);
+        }
+        let estimated_duration = start.elapsed() * (4096 * 4096 / SAMPLES);
+
+        return LutCompatibilityReport {
+            colors,
+            blocks,
+            block_area,
+            problems,
+            estimated_memory_bytes,
+            estimated_duration,
+        };
+    }
+
+    fn lut_block_indices(&self) -> (BTreeMap<char, u8>, Vec<char>) {
+        assert!(self.palette.colors.len() <= MAX_PALETTE_COLORS);
         assert!(self.blocks.blocks.len() <= 256);
-        assert!(self.block_width() * self.block_height() <= 32);
+        assert!(self.block_width() * self.block_height() <= MAX_LUT_BLOCK_AREA);
 
         let mut char_to_idx = BTreeMap::<char, u8>::new();
         let mut idx_to_char = Vec::<char>::new();
@@ -273,63 +4524,109 @@ impl ANSIfier {
             i += 1;
         }
 
-        let lut = RgbaImage::from_fn(4096, 4096, |x, y| {
-            let r = x & 0xFF;
-            let g = y & 0xFF;
-            let b = ((x >> 8) & 0xF) | (((y >> 8) & 0xF) << 4);
-            
-            let nearest = self
-                .kdtree
-                .nearest(&[
-                    r as f32 / 255.0,
-                    g as f32 / 255.0,
-                    b as f32 / 255.0,
-                ])
-                .unwrap()
-                .item;
-            let texel = &nearest.1;
-            let block_idx = char_to_idx[&texel.block];
-            Rgba([texel.foreground_color as u8, texel.background_color as u8,  block_idx as u8, 255])
+        (char_to_idx, idx_to_char)
+    }
+
+    fn lut_pixel(&self, char_to_idx: &BTreeMap<char, u8>, x: u32, y: u32) -> Rgba<u8> {
+        let r = x & 0xFF;
+        let g = y & 0xFF;
+        let b = ((x >> 8) & 0xF) | (((y >> 8) & 0xF) << 4);
+
+        let texel = self.nearest_texel(&[r as u8, g as u8, b as u8]);
+        texel_index_pixel(char_to_idx, texel)
+    }
+
+    // One pixel per cell in the same (foreground, background, block index) layout
+    // as the LUT, with block indices into the map
+    pub fn grid_indices(&self, grid: &[Vec<Texel>]) -> RgbaImage {
+        let (char_to_idx, _) = self.lut_block_indices();
+
+        let height = grid.len() as u32;
+        let width = grid.first().map_or(0, |row| row.len()) as u32;
+
+        return RgbaImage::from_fn(width, height, |x, y| {
+            texel_index_pixel(&char_to_idx, &grid[y as usize][x as usize])
         });
+    }
 
-        let mut map = RgbaImage::new(256, 2);
-        for x in 0..self.palette.colors.len() {
-            let color = self.palette.colors[x as usize];
+    pub fn process_indices(&self, img: &RgbImage) -> (RgbImage, RgbaImage) {
+        let grid = self.match_grid(img);
+        return (self.render_grid(&grid), self.grid_indices(&grid));
+    }
+
+    // Row 0 holds the palette, rows 1 and 2 the low and high 32 bits of each block
+    fn generate_map(&self, idx_to_char: &[char]) -> RgbaImage {
+        let mut map = RgbaImage::new(256, 3);
+        for (x, color) in self.palette.colors.iter().enumerate() {
             map.put_pixel(x as u32, 0u32, Rgba([color[0], color[1], color[2], 255]));
         }
 
-        for j in 0..idx_to_char.len() {
-            let block = &self.blocks.blocks[&idx_to_char[j]];
-            let mut bits = 0u32;
+        for (j, character) in idx_to_char.iter().enumerate() {
+            let block = &self.blocks.blocks[character];
+            let mut bits = 0u64;
             for x in 0..self.block_width() {
                 for y in 0..self.block_height() {
-                    bits |= (block[y as usize][x as usize] as u32) << (x + y * self.block_width());
+                    bits |= (block[y as usize][x as usize] as u64) << (x + y * self.block_width());
                 }
             }
-            let r = (bits & 0xFF) as u8;
-            let g = ((bits >> 8) & 0xFF) as u8;
-            let b = ((bits >> 16) & 0xFF) as u8;
-            let a = (bits >> 24) as u8;
+            let [r, g, b, a, hr, hg, hb, ha] = bits.to_le_bytes();
             map.put_pixel(j as u32, 1u32, Rgba([r, g, b, a]));
+            map.put_pixel(j as u32, 2u32, Rgba([hr, hg, hb, ha]));
         }
 
-        (lut, map)
+        map
     }
 
-    #[cfg(feature = "rayon")]
-    pub fn par_generate_lut_and_map(&self) -> (RgbaImage, RgbaImage) {
-        assert!(self.palette.colors.len() <= 256);
-        assert!(self.blocks.blocks.len() <= 256);
-        assert!(self.block_width() * self.block_height() <= 32);
+    pub fn generate_lut_and_map(&self) -> (RgbaImage, RgbaImage) {
+        let (char_to_idx, idx_to_char) = self.lut_block_indices();
 
-        let mut char_to_idx = BTreeMap::<char, u8>::new();
-        let mut idx_to_char = Vec::<char>::new();
-        let mut i = 0u8;
-        for (key, _val) in &self.blocks.blocks {
-            char_to_idx.insert(*key, i);
-            idx_to_char.push(*key);
-            i += 1;
+        let lut = RgbaImage::from_fn(4096, 4096, |x, y| self.lut_pixel(&char_to_idx, x, y));
+
+        (lut, self.generate_map(&idx_to_char))
+    }
+
+    // Same output as generate_lut_and_map, but only one row of the LUT is held in
+    // memory at a time while it is encoded
+    pub fn write_lut_and_map<L: Write, M: Write>(
+        &self,
+        lut_writer: L,
+        map_writer: M,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Writing LUT and map");
+
+        let (char_to_idx, idx_to_char) = self.lut_block_indices();
+
+        let mut encoder = png::Encoder::new(lut_writer, 4096, 4096);
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut stream = writer.stream_writer();
+
+        let mut row = Vec::with_capacity(4096 * 4);
+        for y in 0..4096 {
+            row.clear();
+            for x in 0..4096 {
+                row.extend_from_slice(&self.lut_pixel(&char_to_idx, x, y).0);
+            }
+            stream.write_all(&row)?;
         }
+        stream.finish()?;
+        drop(writer);
+
+        let map = self.generate_map(&idx_to_char);
+        PngEncoder::new(map_writer).encode(
+            map.as_raw(),
+            map.width(),
+            map.height(),
+            ColorType::Rgba8,
+        )?;
+
+        return Ok(());
+    }
+
+    #[cfg(feature = "rayon")]
+    pub fn par_generate_lut_and_map(&self) -> (RgbaImage, RgbaImage) {
+        let (char_to_idx, idx_to_char) = self.lut_block_indices();
 
         let lut = RgbaImage::new(4096, 4096);
         let lut_dimensions = lut.dimensions();
@@ -337,48 +4634,11 @@ impl ANSIfier {
 
         (0..lut_dimensions.0).into_par_iter().for_each(|x| {
             (0..lut_dimensions.1).into_par_iter().for_each(|y| {
-                let r = x & 0xFF;
-                let g = y & 0xFF;
-                let b = ((x >> 8) & 0xF) | (((y >> 8) & 0xF) << 4);
-                
-                let nearest = self
-                    .kdtree
-                    .nearest(&[
-                        r as f32 / 255.0,
-                        g as f32 / 255.0,
-                        b as f32 / 255.0,
-                    ])
-                    .unwrap()
-                    .item;
-                let texel = &nearest.1;
-                let block_idx = char_to_idx[&texel.block];
-                lut_mutex.lock().unwrap().put_pixel(x as u32, y as u32, Rgba([texel.foreground_color as u8, texel.background_color as u8,  block_idx as u8, 255]));
+                let pixel = self.lut_pixel(&char_to_idx, x, y);
+                lut_mutex.lock().unwrap().put_pixel(x as u32, y as u32, pixel);
             })
         });
 
-        let map = RgbaImage::new(256, 2);
-        let map_mutex = Mutex::new(map);
-
-        (0..self.palette.colors.len()).into_par_iter().for_each(|x| {
-            let color = self.palette.colors[x as usize];
-            map_mutex.lock().unwrap().put_pixel(x as u32, 0u32, Rgba([color[0], color[1], color[2], 255]));
-        });
-
-        (0..idx_to_char.len()).into_par_iter().for_each(|j| {
-            let block = &self.blocks.blocks[&idx_to_char[j]];
-            let mut bits = 0u32;
-            for x in 0..self.block_width() {
-                for y in 0..self.block_height() {
-                    bits |= (block[y as usize][x as usize] as u32) << (x + y * self.block_width());
-                }
-            }
-            let r = (bits & 0xFF) as u8;
-            let g = ((bits >> 8) & 0xFF) as u8;
-            let b = ((bits >> 16) & 0xFF) as u8;
-            let a = (bits >> 24) as u8;
-            map_mutex.lock().unwrap().put_pixel(j as u32, 1u32, Rgba([r, g, b, a]));
-        });
-
-        (lut_mutex.into_inner().unwrap(), map_mutex.into_inner().unwrap())
+        (lut_mutex.into_inner().unwrap(), self.generate_map(&idx_to_char))
     }
 }
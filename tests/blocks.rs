@@ -0,0 +1,618 @@
+// Loading and generating block sets
+
+mod common;
+
+mod block_elements {
+    use ansify::{Blocks, BlocksError};
+
+    #[test]
+    fn quadrants_fill_their_corner() {
+        let blocks = Blocks::unicode_block_elements(4, 4).unwrap();
+
+        let upper_left = vec![
+            vec![true, true, false, false],
+            vec![true, true, false, false],
+            vec![false, false, false, false],
+            vec![false, false, false, false],
+        ];
+        let flip_horizontal = |bitmap: &Vec<Vec<bool>>| -> Vec<Vec<bool>> {
+            bitmap
+                .iter()
+                .map(|row| row.iter().rev().copied().collect())
+                .collect()
+        };
+        let flip_vertical =
+            |bitmap: &Vec<Vec<bool>>| -> Vec<Vec<bool>> { bitmap.iter().rev().cloned().collect() };
+
+        assert_eq!(blocks.bitmap('▘'), Some(&upper_left));
+        assert_eq!(blocks.bitmap('▝'), Some(&flip_horizontal(&upper_left)));
+        assert_eq!(blocks.bitmap('▖'), Some(&flip_vertical(&upper_left)));
+        assert_eq!(
+            blocks.bitmap('▗'),
+            Some(&flip_vertical(&flip_horizontal(&upper_left)))
+        );
+
+        let upper_half = vec![
+            vec![true, true, true, true],
+            vec![true, true, true, true],
+            vec![false, false, false, false],
+            vec![false, false, false, false],
+        ];
+        assert_eq!(blocks.bitmap('▀'), Some(&upper_half));
+    }
+
+    #[test]
+    fn empty_cell_is_an_error() {
+        assert_eq!(
+            Blocks::unicode_block_elements(4, 0),
+            Err(BlocksError::EmptyCell {
+                width: 4,
+                height: 0
+            })
+        );
+    }
+}
+
+mod blocks_errors {
+    use ansify::{Blocks, BlocksError};
+
+    use crate::common;
+
+    fn load(name: &str, yaml: &str) -> Result<Blocks, Box<dyn std::error::Error>> {
+        let dir = common::temp_dir();
+        return Blocks::from(dir.write(name, yaml));
+    }
+
+    #[test]
+    fn wrong_row_width_is_an_error() {
+        let error = load(
+            "blocks_row_width.yaml",
+            "width: 2\nheight: 2\nblocks:\n  a: [\"10\", \"01\"]\n  b: [\"10\", \"011\"]\n",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.downcast_ref::<BlocksError>(),
+            Some(&BlocksError::RowWidthMismatch {
+                character: 'b',
+                expected: 2,
+                found: 3,
+            })
+        );
+        assert!(error.to_string().contains("`b`"), "{}", error);
+    }
+
+    #[test]
+    fn wrong_row_count_is_an_error() {
+        let error = load(
+            "blocks_row_count.yaml",
+            "width: 2\nheight: 2\nblocks:\n  a: [\"10\"]\n",
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.downcast_ref::<BlocksError>(),
+            Some(&BlocksError::RowCountMismatch {
+                character: 'a',
+                expected: 2,
+                found: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn valid_blocks_still_load() {
+        let blocks = common::tiny_blocks();
+        assert_eq!(
+            blocks,
+            Blocks::from_trusted(common::res("tiny.yaml")).unwrap()
+        );
+    }
+}
+
+mod blocks_new {
+    use ansify::{ANSIfier, Blocks, BlocksError, Palette};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    fn bitmaps() -> BTreeMap<char, Vec<Vec<bool>>> {
+        return BTreeMap::from([
+            (' ', vec![vec![false, false], vec![false, false]]),
+            ('▀', vec![vec![true, true], vec![false, false]]),
+        ]);
+    }
+
+    #[test]
+    fn builds_blocks_in_memory() {
+        let blocks = Blocks::new(2, 2, bitmaps()).unwrap();
+        assert_eq!(blocks.blocks(), &bitmaps());
+        assert_eq!((blocks.width(), blocks.height()), (2, 2));
+
+        // Mid gray is only reachable as a half white, half black glyph
+        let ansifier =
+            ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+        let img = RgbImage::from_pixel(1, 1, Rgb([128, 128, 128]));
+        let texel = ansifier.match_grid(&img)[0][0];
+        assert_eq!(texel.block, '▀');
+        assert_ne!(texel.foreground_color, texel.background_color);
+    }
+
+    #[test]
+    fn rejects_mismatched_bitmaps() {
+        let mut bitmaps = bitmaps();
+        bitmaps.insert('▌', vec![vec![true], vec![true]]);
+
+        assert_eq!(
+            Blocks::new(2, 2, bitmaps).unwrap_err(),
+            BlocksError::RowWidthMismatch {
+                character: '▌',
+                expected: 2,
+                found: 1,
+            }
+        );
+    }
+}
+
+mod blocks_to_file {
+    use ansify::Blocks;
+
+    use crate::common;
+
+    #[test]
+    fn round_trips_through_yaml() {
+        let dir = common::temp_dir();
+
+        for file in ["tiny.yaml", "classic.yaml"] {
+            let blocks = Blocks::from(common::res(file)).unwrap();
+            let path = dir.join(file);
+            blocks.to_file(path.clone()).unwrap();
+
+            assert_eq!(Blocks::from(path).unwrap(), blocks);
+        }
+    }
+
+    #[test]
+    fn round_trips_generated_blocks() {
+        let blocks = Blocks::sextants();
+        let dir = common::temp_dir();
+        let path = dir.join("sextants.yaml");
+        blocks.to_file(path.clone()).unwrap();
+
+        assert_eq!(Blocks::from(path).unwrap(), blocks);
+    }
+}
+
+mod braille {
+    use ansify::{ANSIfier, Blocks, Palette, PalettePreset};
+
+    #[test]
+    fn braille_dots_follow_the_standard_numbering() {
+        let blocks = Blocks::braille();
+        assert_eq!((blocks.width(), blocks.height()), (2, 4));
+
+        let mut bitmaps: Vec<&Vec<Vec<bool>>> = (0x2800..=0x28FF)
+            .map(|code| blocks.bitmap(char::from_u32(code).unwrap()).unwrap())
+            .collect();
+        bitmaps.sort();
+        bitmaps.dedup();
+        assert_eq!(bitmaps.len(), 256);
+
+        let bitmap = |rows: [[bool; 2]; 4]| rows.iter().map(|row| row.to_vec()).collect();
+        assert_eq!(blocks.bitmap('⠀'), Some(&bitmap([[false; 2]; 4])));
+        // ⠁ is dot 1, ⠈ dot 4, ⡀ dot 7, and ⢀ dot 8
+        assert_eq!(
+            blocks.bitmap('⠁'),
+            Some(&bitmap([[true, false], [false; 2], [false; 2], [false; 2]]))
+        );
+        assert_eq!(
+            blocks.bitmap('⠈'),
+            Some(&bitmap([[false, true], [false; 2], [false; 2], [false; 2]]))
+        );
+        assert_eq!(
+            blocks.bitmap('⡀'),
+            Some(&bitmap([[false; 2], [false; 2], [false; 2], [true, false]]))
+        );
+        assert_eq!(
+            blocks.bitmap('⢀'),
+            Some(&bitmap([[false; 2], [false; 2], [false; 2], [false, true]]))
+        );
+        // ⠇ is dots 1, 2, and 3, the upper three of the left column
+        assert_eq!(
+            blocks.bitmap('⠇'),
+            Some(&bitmap([
+                [true, false],
+                [true, false],
+                [true, false],
+                [false; 2]
+            ]))
+        );
+        assert_eq!(blocks.bitmap('⣿'), Some(&bitmap([[true; 2]; 4])));
+    }
+
+    #[test]
+    fn braille_fits_the_lut() {
+        let ansifier =
+            ANSIfier::new(Palette::preset(PalettePreset::Pico8), Blocks::braille()).unwrap();
+        assert!(ansifier.lut_compatibility_report().is_compatible());
+    }
+}
+
+#[cfg(feature = "font")]
+mod font_blocks {
+    use ansify::{Blocks, BlocksError};
+    use std::path::PathBuf;
+
+    // DejaVu Sans Mono ships with most Linux distributions, and is too large to
+    // vendor as a fixture
+    fn font() -> Option<PathBuf> {
+        let path = PathBuf::from("/usr/share/fonts/truetype/dejavu/DejaVuSansMono.ttf");
+        return if path.exists() { Some(path) } else { None };
+    }
+
+    fn coverage(bitmap: &[Vec<bool>]) -> usize {
+        return bitmap.iter().flatten().filter(|bit| **bit).count();
+    }
+
+    #[test]
+    fn rasterizes_requested_glyphs() {
+        let Some(path) = font() else { return };
+
+        let blocks = Blocks::from_font(path, " .█", 8, 16).unwrap();
+        assert_eq!((blocks.width(), blocks.height()), (8, 16));
+
+        assert_eq!(coverage(blocks.bitmap(' ').unwrap()), 0);
+        assert!(coverage(blocks.bitmap('█').unwrap()) > 8 * 16 * 9 / 10);
+
+        // The period sits on the baseline in the bottom half of the cell
+        let period = blocks.bitmap('.').unwrap();
+        assert!(coverage(period) > 0);
+        assert!(period[..8].iter().flatten().all(|bit| !bit));
+    }
+
+    #[test]
+    fn missing_glyph_is_an_error() {
+        let Some(path) = font() else { return };
+
+        let error = Blocks::from_font(path, "a\u{10FFFD}", 8, 16)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("no glyph"), "{}", error);
+    }
+
+    #[test]
+    fn empty_cell_is_an_error() {
+        // Checked before the font is read
+        let error = Blocks::from_font(PathBuf::from("missing.ttf"), " ", 0, 16).unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<BlocksError>(),
+            Some(&BlocksError::EmptyCell {
+                width: 0,
+                height: 16
+            })
+        );
+    }
+}
+
+mod half_blocks {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn half_blocks_cover_their_half() {
+        let blocks = Blocks::half_blocks();
+        assert_eq!((blocks.width(), blocks.height()), (1, 2));
+
+        assert_eq!(blocks.bitmap(' '), Some(&vec![vec![false], vec![false]]));
+        assert_eq!(blocks.bitmap('▀'), Some(&vec![vec![true], vec![false]]));
+        assert_eq!(blocks.bitmap('▄'), Some(&vec![vec![false], vec![true]]));
+        assert_eq!(blocks.bitmap('█'), Some(&vec![vec![true], vec![true]]));
+    }
+
+    #[test]
+    fn each_cell_keeps_two_colors() {
+        let ansifier = ANSIfier::new(
+            Palette::new(vec![[0, 0, 0], [255, 0, 0], [0, 0, 255]]),
+            Blocks::half_blocks(),
+        )
+        .unwrap();
+        let img = RgbImage::from_fn(3, 2, |_, y| {
+            if y == 0 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+
+        let grid = ansifier.match_grid_structural(&img);
+        assert_eq!(ansifier.render_grid(&grid), img);
+    }
+}
+
+mod quadrants_sextants {
+    use ansify::{ANSIfier, Blocks, Palette, PalettePreset};
+
+    fn assert_every_pattern(blocks: &Blocks, characters: &[char]) {
+        let area = blocks.width() * blocks.height();
+        assert!(area <= 32);
+
+        let mut bitmaps: Vec<&Vec<Vec<bool>>> = characters
+            .iter()
+            .map(|character| blocks.bitmap(*character).unwrap())
+            .collect();
+        bitmaps.sort();
+        bitmaps.dedup();
+        assert_eq!(bitmaps.len(), 1 << area);
+    }
+
+    #[test]
+    fn quadrants_cover_every_combination() {
+        let blocks = Blocks::quadrants();
+        assert_eq!((blocks.width(), blocks.height()), (2, 2));
+
+        let characters: Vec<char> = " ▘▝▀▖▌▞▛▗▚▐▜▄▙▟█".chars().collect();
+        assert_every_pattern(&blocks, &characters);
+
+        assert_eq!(
+            blocks.bitmap('▚'),
+            Some(&vec![vec![true, false], vec![false, true]])
+        );
+        assert_eq!(
+            blocks.bitmap('▟'),
+            Some(&vec![vec![false, true], vec![true, true]])
+        );
+    }
+
+    #[test]
+    fn sextants_cover_every_combination() {
+        let blocks = Blocks::sextants();
+        assert_eq!((blocks.width(), blocks.height()), (2, 3));
+
+        let mut characters: Vec<char> = (0x1FB00..=0x1FB3B)
+            .map(|code| char::from_u32(code).unwrap())
+            .collect();
+        characters.extend([' ', '▌', '▐', '█']);
+        assert_every_pattern(&blocks, &characters);
+
+        // 🬀 is the upper left sextant, 🬋 the middle row, and 🬻 all but the upper left
+        assert_eq!(
+            blocks.bitmap('\u{1FB00}'),
+            Some(&vec![
+                vec![true, false],
+                vec![false, false],
+                vec![false, false]
+            ])
+        );
+        assert_eq!(
+            blocks.bitmap('\u{1FB0B}'),
+            Some(&vec![
+                vec![false, false],
+                vec![true, true],
+                vec![false, false]
+            ])
+        );
+        assert_eq!(
+            blocks.bitmap('\u{1FB3B}'),
+            Some(&vec![vec![false, true], vec![true, true], vec![true, true]])
+        );
+    }
+
+    #[test]
+    fn generated_sets_fit_the_lut() {
+        for blocks in [Blocks::quadrants(), Blocks::sextants()] {
+            let ansifier = ANSIfier::new(Palette::preset(PalettePreset::Pico8), blocks).unwrap();
+            assert!(ansifier.lut_compatibility_report().is_compatible());
+        }
+    }
+}
+
+mod redundant_solids {
+    use ansify::Blocks;
+    use log::{Level, LevelFilter, Log, Metadata, Record};
+    use std::sync::Mutex;
+
+    use crate::common;
+
+    struct CaptureLogger {
+        warnings: Mutex<Vec<String>>,
+    }
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            return metadata.level() <= Level::Warn;
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                self.warnings
+                    .lock()
+                    .unwrap()
+                    .push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CaptureLogger = CaptureLogger {
+        warnings: Mutex::new(Vec::new()),
+    };
+
+    #[test]
+    fn repeated_empty_glyphs_are_reported() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Warn);
+
+        let dir = common::temp_dir();
+        let path = dir.write(
+        "blocks_two_empty.yaml",
+        "width: 2\nheight: 1\nblocks:\n  ' ': [\"00\"]\n  '.': [\"00\"]\n  '▌': [\"10\"]\n  '█': [\"11\"]\n",
+    );
+
+        let blocks = Blocks::from(path).unwrap();
+        assert_eq!(blocks.redundant_solid_glyphs(), vec!['.']);
+
+        let warnings = LOGGER.warnings.lock().unwrap().clone();
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("`.`"), "{}", warnings[0]);
+
+        for file in ["tiny.yaml", "small.yaml", "classic.yaml"] {
+            let blocks = Blocks::from(common::res(file)).unwrap();
+            assert!(blocks.redundant_solid_glyphs().is_empty(), "{}", file);
+        }
+    }
+}
+
+mod sprite_sheet {
+    use ansify::Blocks;
+    use image::{GrayImage, Luma};
+    use std::path::PathBuf;
+
+    use crate::common;
+
+    // A 3x1 sheet of 2x2 cells: empty, a left half, and a full cell
+    fn sheet(dir: &common::TempDir, name: &str, width: u32, height: u32) -> PathBuf {
+        let path = dir.join(name);
+        let sheet = GrayImage::from_fn(width, height, |x, y| {
+            let lit = match x / 2 {
+                0 => false,
+                1 => x % 2 == 0,
+                _ => y < 2,
+            };
+            return Luma([if lit { 255 } else { 0 }]);
+        });
+        sheet.save(&path).unwrap();
+        return path;
+    }
+
+    #[test]
+    fn slices_cells_in_order() {
+        let dir = common::temp_dir();
+        let path = sheet(&dir, "sheet.png", 6, 2);
+        let blocks = Blocks::from_sprite_sheet(path, 2, 2, " ▌█").unwrap();
+
+        assert_eq!((blocks.width(), blocks.height()), (2, 2));
+        assert_eq!(
+            blocks.bitmap(' ').unwrap(),
+            &vec![vec![false, false], vec![false, false]]
+        );
+        assert_eq!(
+            blocks.bitmap('▌').unwrap(),
+            &vec![vec![true, false], vec![true, false]]
+        );
+        assert_eq!(
+            blocks.bitmap('█').unwrap(),
+            &vec![vec![true, true], vec![true, true]]
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_sheets() {
+        let dir = common::temp_dir();
+        let path = sheet(&dir, "uneven.png", 7, 2);
+        let error = Blocks::from_sprite_sheet(path, 2, 2, " ")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not a multiple"), "{}", error);
+
+        let path = sheet(&dir, "short.png", 6, 2);
+        let error = Blocks::from_sprite_sheet(path, 2, 2, " ▌█▐")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("3 cells but 4 characters"), "{}", error);
+    }
+}
+
+mod test_pattern {
+    use ansify::{ANSIfier, Blocks, Palette, TestPattern};
+
+    use crate::common;
+
+    #[test]
+    fn grayscale_ramp_glyph_ratios_are_monotonic() {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "black_white.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 255, 255]\n",
+        );
+
+        let ansifier = ANSIfier::new(
+            Palette::from(palette_path).unwrap(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+
+        let pattern = TestPattern::GrayscaleRamp.generate(32, 1).unwrap();
+        let (out, _) = ansifier.process(&pattern);
+
+        // Fraction of white pixels in each cell, left to right
+        let cell_pixels = ansifier.block_width() * ansifier.block_height();
+        let ratios: Vec<f32> = (0..32)
+            .map(|cell| {
+                let white = (0..ansifier.block_width())
+                    .flat_map(|x| (0..ansifier.block_height()).map(move |y| (x, y)))
+                    .filter(|(x, y)| {
+                        out.get_pixel(cell * ansifier.block_width() + x, *y).0[0] == 255
+                    })
+                    .count();
+                white as f32 / cell_pixels as f32
+            })
+            .collect();
+
+        assert!(
+            ratios.windows(2).all(|pair| pair[0] <= pair[1]),
+            "glyph ratios are not monotonic: {:?}",
+            ratios
+        );
+        assert_eq!(ratios[0], 0.0);
+        assert_eq!(ratios[31], 1.0);
+    }
+
+    #[test]
+    fn empty_pattern_is_an_error() {
+        let error = TestPattern::Checkerboard.generate(0, 8).unwrap_err();
+        assert!(error.to_string().contains("0x8"), "{}", error);
+    }
+}
+
+mod unicode_shading {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, Gamma, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "palette.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 255, 255]\n",
+        );
+        // The bitmap for ▒ only covers a quarter of the cell
+        let blocks_path = dir.write(
+            "blocks.yaml",
+            "width: 2\nheight: 2\nblocks:\n  █: [\"11\", \"11\"]\n  ▒: [\"10\", \"00\"]\n",
+        );
+
+        // Blend the raw values so half coverage of black and white is exactly 128
+        return ANSIfier::with_options(
+            Palette::from(palette_path).unwrap(),
+            Blocks::from(blocks_path).unwrap(),
+            ANSIfierOptions {
+                gamma: Gamma::Power(1.0),
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn medium_shade_is_half_coverage() {
+        let gray = RgbImage::from_pixel(1, 1, Rgb([128, 128, 128]));
+
+        let ansifier = ansifier();
+        assert!(ansifier.match_confidence(&gray).get_pixel(0, 0).0[0] < 250);
+
+        let ansifier = ansifier.rebuild_with_unicode_shading(true);
+        assert_eq!(ansifier.match_grid(&gray)[0][0].block, '▒');
+        assert!(ansifier.match_confidence(&gray).get_pixel(0, 0).0[0] >= 254);
+    }
+}
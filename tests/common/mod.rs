@@ -0,0 +1,68 @@
+// Setup shared by the integration tests. Every topic file is compiled as its own
+// crate and only uses some of this.
+#![allow(dead_code)]
+
+use ansify::{ANSIfier, Blocks, Palette};
+use image::RgbImage;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+pub fn root() -> PathBuf {
+    return PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+}
+
+pub fn res(name: &str) -> PathBuf {
+    return root().join("res").join(name);
+}
+
+pub fn tiny_blocks() -> Blocks {
+    return Blocks::from(res("tiny.yaml")).unwrap();
+}
+
+// A palette from res/ with the 3x7 tiny blocks
+pub fn ansifier(palette: &str) -> ANSIfier {
+    return ANSIfier::new(Palette::from(res(palette)).unwrap(), tiny_blocks()).unwrap();
+}
+
+pub fn cat() -> RgbImage {
+    return image::open(root().join("tests").join("golden").join("cat.png"))
+        .unwrap()
+        .into_rgb8();
+}
+
+// A directory under the system temp directory that only one test uses, removed
+// with everything in it when dropped
+pub struct TempDir {
+    path: PathBuf,
+}
+
+pub fn temp_dir() -> TempDir {
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+
+    let path = std::env::temp_dir().join(format!(
+        "ansify-test-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&path).unwrap();
+    return TempDir { path };
+}
+
+impl TempDir {
+    pub fn join(&self, name: &str) -> PathBuf {
+        return self.path.join(name);
+    }
+
+    pub fn write<C: AsRef<[u8]>>(&self, name: &str, contents: C) -> PathBuf {
+        let path = self.join(name);
+        fs::write(&path, contents).unwrap();
+        return path;
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,1319 @@
+// Matching colors to texels
+
+mod common;
+
+mod blue_noise {
+    use ansify::{Dither, Texel};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeSet;
+
+    use crate::common;
+
+    fn distinct(grid: &[Vec<Texel>]) -> usize {
+        return grid
+            .iter()
+            .flatten()
+            .map(|texel| (texel.foreground_color, texel.background_color, texel.block))
+            .collect::<BTreeSet<_>>()
+            .len();
+    }
+
+    #[test]
+    fn blue_noise_is_deterministic_and_varied() {
+        let mut ansifier = common::ansifier("8.yaml");
+        let gradient = RgbImage::from_fn(64, 16, |x, _| {
+            let value = (x * 4) as u8;
+            Rgb([value, value / 2, 255 - value])
+        });
+
+        let plain = ansifier.match_grid(&gradient);
+
+        ansifier.set_dither(Dither::BlueNoise);
+        let dithered = ansifier.match_grid(&gradient);
+        assert_eq!(dithered, ansifier.match_grid(&gradient));
+        assert!(distinct(&dithered) > distinct(&plain));
+
+        // Rows of a horizontal gradient are identical without dithering but not with it
+        assert!(plain.iter().all(|row| *row == plain[0]));
+        assert!(dithered.iter().any(|row| *row != dithered[0]));
+    }
+}
+
+mod ciede2000 {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, DistanceMetric, Palette};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    const PALETTE: [[u8; 3]; 5] = [
+        [0, 0, 0],
+        [255, 255, 255],
+        [255, 0, 0],
+        [0, 0, 255],
+        [128, 128, 128],
+    ];
+
+    // Solid cells only, so every texel is exactly one palette color
+    fn ansifier(metric: DistanceMetric) -> ANSIfier {
+        let blocks = Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+        return ANSIfier::with_options(
+            Palette::new(PALETTE.to_vec()),
+            blocks,
+            ANSIfierOptions {
+                metric,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    fn matched_color(ansifier: &ANSIfier, color: [u8; 3]) -> [u8; 3] {
+        let texel = ansifier.match_grid(&RgbImage::from_pixel(1, 1, Rgb(color)))[0][0];
+        let index = if texel.block == '█' {
+            texel.foreground_color
+        } else {
+            texel.background_color
+        };
+        return PALETTE[index as usize];
+    }
+
+    #[test]
+    fn saturated_colors_keep_their_hue() {
+        // Closer to gray in RGB, but perceptually a blue
+        let violet = [90, 60, 200];
+        assert_eq!(
+            matched_color(&ansifier(DistanceMetric::Euclidean), violet),
+            [128, 128, 128]
+        );
+        assert_eq!(
+            matched_color(&ansifier(DistanceMetric::CIEDE2000), violet),
+            [0, 0, 255]
+        );
+    }
+
+    #[test]
+    fn palette_colors_match_themselves() {
+        let ansifier = ansifier(DistanceMetric::CIEDE2000);
+        for color in PALETTE {
+            assert_eq!(matched_color(&ansifier, color), color);
+        }
+    }
+}
+
+mod color_metric {
+    use ansify::{
+        ANSIfier, ANSIfierOptions, Blocks, Ciede2000Metric, ColorMetric, DistanceMetric, LabMetric,
+        Palette, Precision, SrgbMetric, Texel, WeightedRgbMetric,
+    };
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    // Compares colors by brightness alone
+    struct Luminance;
+
+    impl ColorMetric for Luminance {
+        fn encode(&self, color: [f64; 3]) -> [f64; 3] {
+            let [r, g, b] = color;
+            return [0.2126 * r + 0.7152 * g + 0.0722 * b, 0.0, 0.0];
+        }
+    }
+
+    // Solid cells only, so every texel is exactly one palette color
+    fn blocks() -> Blocks {
+        return Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+    }
+
+    fn options(metric: DistanceMetric, precision: Precision) -> ANSIfierOptions {
+        return ANSIfierOptions {
+            metric,
+            precision,
+            ..ANSIfierOptions::default()
+        };
+    }
+
+    fn color(texel: &Texel) -> u8 {
+        return if texel.block == '█' {
+            texel.foreground_color
+        } else {
+            texel.background_color
+        };
+    }
+
+    fn colors(grid: &[Vec<Texel>]) -> Vec<u8> {
+        return grid.iter().flatten().map(color).collect();
+    }
+
+    #[test]
+    fn built_in_metrics_match_their_distance_metrics() {
+        let img = RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([(x * 8) as u8, (y * 8) as u8, ((x * 3 + y * 5) % 256) as u8])
+        });
+
+        for (metric, distance_metric) in [
+            (
+                Arc::new(SrgbMetric) as Arc<dyn ColorMetric>,
+                DistanceMetric::Euclidean,
+            ),
+            (Arc::new(LabMetric), DistanceMetric::Lab),
+            (Arc::new(Ciede2000Metric), DistanceMetric::CIEDE2000),
+            (Arc::new(WeightedRgbMetric), DistanceMetric::WeightedRgb),
+        ] {
+            for precision in [Precision::F32, Precision::F64] {
+                let built_in = ANSIfier::with_options(
+                    Palette::vga_16(),
+                    blocks(),
+                    options(distance_metric, precision),
+                )
+                .unwrap();
+                let custom = ANSIfier::with_metric(
+                    Palette::vga_16(),
+                    blocks(),
+                    options(DistanceMetric::Euclidean, precision),
+                    metric.clone(),
+                )
+                .unwrap();
+                assert_eq!(
+                    colors(&custom.match_grid(&img)),
+                    colors(&built_in.match_grid(&img)),
+                    "{:?} {:?}",
+                    distance_metric,
+                    precision
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn custom_metric_decides_the_match() {
+        // Closer to gray in RGB, but as dark as black
+        let palette = Palette::new(vec![[0, 0, 0], [128, 128, 128]]);
+        let red = RgbImage::from_pixel(1, 1, Rgb([255, 0, 0]));
+
+        let euclidean = ANSIfier::new(palette.clone(), blocks()).unwrap();
+        assert_eq!(color(&euclidean.match_grid(&red)[0][0]), 1);
+
+        let ansifier = ANSIfier::with_metric(
+            palette.clone(),
+            blocks(),
+            ANSIfierOptions::default(),
+            Arc::new(Luminance),
+        )
+        .unwrap();
+        assert_eq!(color(&ansifier.match_grid(&red)[0][0]), 0);
+
+        // Rebuilding keeps the metric
+        let ansifier = ansifier
+            .rebuild_with_palette(Palette::new(vec![[128, 128, 128], [0, 0, 0]]))
+            .unwrap();
+        assert_eq!(color(&ansifier.match_grid(&red)[0][0]), 1);
+    }
+}
+
+mod color_priority {
+    use ansify::{ANSIfier, Blocks, Palette, Texel};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    // Solid cells only, so every texel is exactly one palette color
+    fn blocks() -> Blocks {
+        return Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+    }
+
+    fn color(texel: &Texel) -> u8 {
+        return if texel.block == '█' {
+            texel.foreground_color
+        } else {
+            texel.background_color
+        };
+    }
+
+    #[test]
+    fn preferred_color_wins_a_tie() {
+        // The brand color is also in the palette under a generic name
+        let palette = Palette::new(vec![[200, 30, 30], [10, 10, 10], [200, 30, 30]]);
+        let img = RgbImage::from_pixel(1, 1, Rgb([190, 40, 40]));
+
+        let ansifier = ANSIfier::new(palette, blocks()).unwrap();
+        assert_eq!(color(&ansifier.match_grid(&img)[0][0]), 0);
+
+        let ansifier = ansifier.rebuild_with_color_priority(vec![2]);
+        assert_eq!(color(&ansifier.match_grid(&img)[0][0]), 2);
+
+        // Only ties are affected, a closer color still wins
+        let dark = RgbImage::from_pixel(1, 1, Rgb([20, 20, 20]));
+        assert_eq!(color(&ansifier.match_grid(&dark)[0][0]), 1);
+
+        // The priority is kept across rebuilds
+        let ansifier = ansifier
+            .rebuild_with_palette(Palette::new(vec![[0, 0, 0], [200, 30, 30], [200, 30, 30]]))
+            .unwrap();
+        assert_eq!(color(&ansifier.match_grid(&img)[0][0]), 2);
+    }
+}
+
+mod constraint_map {
+    use ansify::ANSIfier;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return common::ansifier("16.yaml");
+    }
+
+    #[test]
+    fn constrained_region_uses_required_foreground() {
+        let ansifier = ansifier();
+        let img = RgbImage::from_fn(16, 8, |x, y| Rgb([(x * 16) as u8, (y * 32) as u8, 128]));
+
+        // The left half requires palette color 3, the right half is unconstrained
+        let constraints = RgbaImage::from_fn(16, 8, |x, _| {
+            if x < 8 {
+                Rgba([3, 0, 0, 255])
+            } else {
+                Rgba([0, 0, 0, 0])
+            }
+        });
+
+        let grid = ansifier.match_grid_with_constraints(&img, &constraints);
+        let unconstrained = ansifier.match_grid(&img);
+
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                if x < 8 {
+                    assert_eq!(texel.foreground_color, 3);
+                    let bitmap = ansifier.blocks.bitmap(texel.block).unwrap();
+                    assert!(bitmap.iter().flatten().any(|bit| *bit));
+                } else {
+                    assert_eq!(*texel, unconstrained[y][x]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn smaller_map_is_scaled() {
+        let ansifier = ansifier();
+        let img = RgbImage::from_pixel(8, 8, Rgb([200, 40, 40]));
+        let constraints = RgbaImage::from_pixel(2, 2, Rgba([5, 0, 0, 255]));
+
+        let grid = ansifier.match_grid_with_constraints(&img, &constraints);
+        assert!(grid
+            .iter()
+            .flatten()
+            .all(|texel| texel.foreground_color == 5));
+    }
+}
+
+mod floyd_steinberg {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, Dither, Gamma, Palette, Texel};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    // Black and white solid cells only, so every cell is one of the two. The error
+    // is carried in linear light.
+    fn ansifier() -> ANSIfier {
+        let blocks = Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+        return ANSIfier::with_options(
+            Palette::new(vec![[0, 0, 0], [255, 255, 255]]),
+            blocks,
+            ANSIfierOptions {
+                gamma: Gamma::Srgb,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    fn white_fraction(grid: &[Vec<Texel>]) -> f32 {
+        let cells: Vec<&Texel> = grid.iter().flatten().collect();
+        let white = cells
+            .iter()
+            .filter(|texel| {
+                let color = if texel.block == '█' {
+                    texel.foreground_color
+                } else {
+                    texel.background_color
+                };
+                color == 1
+            })
+            .count();
+        return white as f32 / cells.len() as f32;
+    }
+
+    #[test]
+    fn diffusion_keeps_the_average_of_flat_regions() {
+        assert_eq!(Dither::default(), Dither::None);
+        let mut ansifier = ansifier();
+
+        // A quarter of the way from black to white in linear light
+        let flat = RgbImage::from_pixel(32, 32, Rgb([137, 137, 137]));
+
+        let plain = ansifier.match_grid(&flat);
+        assert!(plain.iter().flatten().all(|texel| *texel == plain[0][0]));
+
+        ansifier.set_dither(Dither::FloydSteinberg { strength: 1.0 });
+        let dithered = ansifier.match_grid(&flat);
+        let fraction = white_fraction(&dithered);
+        assert!((fraction - 0.25).abs() < 0.02, "{}", fraction);
+
+        // Carrying less of the error stays closer to the undithered result
+        ansifier.set_dither(Dither::FloydSteinberg { strength: 0.5 });
+        let weaker = white_fraction(&ansifier.match_grid(&flat));
+        assert!(
+            weaker > fraction && weaker < white_fraction(&plain),
+            "{}",
+            weaker
+        );
+
+        ansifier.set_dither(Dither::FloydSteinberg { strength: 0.0 });
+        assert_eq!(ansifier.match_grid(&flat), plain);
+    }
+}
+
+mod importance_mask {
+    use ansify::{ANSIfier, OutOfGamut, Palette};
+    use image::{GrayImage, Luma};
+
+    use crate::common;
+
+    fn ansifier(quantize: [u8; 3]) -> ANSIfier {
+        let mut ansifier = ANSIfier::new(
+            Palette::from(common::res("16.yaml")).unwrap(),
+            common::tiny_blocks(),
+        )
+        .unwrap();
+        ansifier.set_quantize(quantize);
+        ansifier.set_out_of_gamut(OutOfGamut::Desaturate, 0.05);
+        return ansifier;
+    }
+
+    #[test]
+    fn important_cells_match_their_exact_color() {
+        let input = common::cat();
+        let low = GrayImage::from_pixel(input.width(), input.height(), Luma([0]));
+        let high = GrayImage::from_pixel(input.width(), input.height(), Luma([255]));
+
+        let exact = ansifier([8, 8, 8]).match_grid(&input);
+        let coarse = ansifier([3, 3, 2]);
+        assert_ne!(coarse.match_grid(&input), exact);
+
+        assert_eq!(coarse.match_grid_with_mask(&input, &high), exact);
+        assert_eq!(
+            coarse.match_grid_with_mask(&input, &low),
+            coarse.match_grid(&input)
+        );
+
+        // The LUT cache is only a stand-in for the kd-tree, so it is skipped too
+        let cached = ansifier([8, 8, 8]).with_lut_cache(8);
+        assert_ne!(cached.match_grid(&input), exact);
+        assert_eq!(cached.match_grid_with_mask(&input, &high), exact);
+    }
+
+    #[test]
+    fn importance_is_taken_per_cell() {
+        let input = common::cat();
+        let (width, height) = input.dimensions();
+        let mask = GrayImage::from_fn(width, height, |x, _| {
+            Luma([if x < width / 2 { 255 } else { 0 }])
+        });
+
+        let exact = ansifier([8, 8, 8]).match_grid(&input);
+        let coarse = ansifier([3, 3, 2]);
+        let coarse_grid = coarse.match_grid(&input);
+
+        let grid = coarse.match_grid_with_mask(&input, &mask);
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                let expected = if (x as u32) < width / 2 {
+                    exact[y][x]
+                } else {
+                    coarse_grid[y][x]
+                };
+                assert_eq!(*texel, expected, "({}, {})", x, y);
+            }
+        }
+    }
+}
+
+mod lab_metric {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, ColorSpace, DistanceMetric, Palette};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    // Solid cells only, so every texel is exactly one palette color
+    fn ansifier(palette: Palette, metric: DistanceMetric) -> ANSIfier {
+        let blocks = Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+        return ANSIfier::with_options(
+            palette,
+            blocks,
+            ANSIfierOptions {
+                metric,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    fn lab_distance(color_a: &[u8; 3], color_b: &[u8; 3]) -> f32 {
+        let (a, b) = (
+            ColorSpace::Lab.convert(color_a),
+            ColorSpace::Lab.convert(color_b),
+        );
+        return (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f32>().sqrt();
+    }
+
+    #[test]
+    fn matches_the_nearest_color_in_lab() {
+        let palette = Palette::vga_16();
+        let ansifier = ansifier(palette.clone(), DistanceMetric::Lab);
+
+        let img = RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([(x * 8) as u8, (y * 8) as u8, ((x * 3 + y * 5) % 256) as u8])
+        });
+        let grid = ansifier.match_grid(&img);
+
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let texel = grid[y as usize][x as usize];
+            let index = if texel.block == '█' {
+                texel.foreground_color
+            } else {
+                texel.background_color
+            };
+            let matched = lab_distance(&pixel.0, &palette.colors()[index as usize]);
+            let nearest = palette
+                .colors()
+                .iter()
+                .map(|color| lab_distance(&pixel.0, color))
+                .fold(f32::INFINITY, f32::min);
+            assert!(matched <= nearest + 1e-3, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn rgb_stays_the_default() {
+        assert_eq!(ANSIfierOptions::default().metric, DistanceMetric::Euclidean);
+
+        // Closer to gray in RGB, but closer to blue in Lab
+        let palette = Palette::new(vec![[0, 0, 255], [128, 128, 128]]);
+        let violet = RgbImage::from_pixel(1, 1, Rgb([90, 60, 200]));
+        let matched = |metric| {
+            let texel = ansifier(palette.clone(), metric).match_grid(&violet)[0][0];
+            if texel.block == '█' {
+                texel.foreground_color
+            } else {
+                texel.background_color
+            }
+        };
+        assert_eq!(matched(DistanceMetric::Euclidean), 1);
+        assert_eq!(matched(DistanceMetric::Lab), 0);
+    }
+
+    #[test]
+    fn rebuild_with_metric_matches_a_fresh_build() {
+        let palette = Palette::vga_16();
+        let img = RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 90]));
+
+        let rebuilt = ansifier(palette.clone(), DistanceMetric::Euclidean)
+            .rebuild_with_metric(DistanceMetric::Lab);
+        let fresh = ansifier(palette, DistanceMetric::Lab);
+
+        assert_eq!(rebuilt.match_grid(&img), fresh.match_grid(&img));
+    }
+
+    #[test]
+    fn confidence_is_measured_in_rgb() {
+        let palette = Palette::new(vec![[0, 0, 0], [255, 255, 255]]);
+        let img = RgbImage::from_fn(2, 1, |x, _| {
+            Rgb([[255, 255, 255], [128, 128, 128]][x as usize])
+        });
+
+        for metric in [DistanceMetric::Lab, DistanceMetric::CIEDE2000] {
+            let confidence = ansifier(palette.clone(), metric).match_confidence(&img);
+            assert_eq!(confidence.get_pixel(0, 0).0[0], 255);
+            // Mid gray is half the cube's diagonal from either black or white
+            assert_eq!(confidence.get_pixel(1, 0).0[0], 128);
+        }
+    }
+}
+
+mod linear_blending {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, Gamma, Palette};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    fn ansifier(gamma: Gamma) -> ANSIfier {
+        // Only a glyph covering half the cell, so every texel is a 50% blend
+        let blocks = Blocks::new(2, 1, BTreeMap::from([('▌', vec![vec![true, false]])])).unwrap();
+        return ANSIfier::with_options(
+            Palette::new(vec![[0, 0, 0], [255, 255, 255]]),
+            blocks,
+            ANSIfierOptions {
+                gamma,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    fn confidence(ansifier: &ANSIfier, gray: u8) -> u8 {
+        let img = RgbImage::from_pixel(1, 1, Rgb([gray, gray, gray]));
+        return ansifier.match_confidence(&img).get_pixel(0, 0).0[0];
+    }
+
+    #[test]
+    fn srgb_blends_in_linear_light() {
+        // Half of white in linear light is 188 once encoded back to sRGB
+        let ansifier = ansifier(Gamma::Srgb);
+        assert!(confidence(&ansifier, 188) >= 254);
+        assert!(confidence(&ansifier, 128) < 250);
+    }
+
+    #[test]
+    fn raw_values_blend_without_decoding_by_default() {
+        assert_eq!(ANSIfierOptions::default().gamma, Gamma::Power(1.0));
+
+        let ansifier = ansifier(Gamma::default());
+        assert!(confidence(&ansifier, 128) >= 254);
+        assert!(confidence(&ansifier, 188) < 250);
+    }
+}
+
+mod lut_cache {
+    use ansify::ANSIfier;
+    use image::Rgb;
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return common::ansifier("8.yaml");
+    }
+
+    #[test]
+    fn lut_cached_output_matches_tree_within_quantization() {
+        let input = common::cat();
+
+        let resolution = 32;
+        let cached = ansifier().with_lut_cache(resolution);
+
+        // Every pixel snapped to the center of its LUT cell
+        let mut quantized = input.clone();
+        for pixel in quantized.pixels_mut() {
+            *pixel = Rgb(pixel.0.map(|channel| {
+                let cell = channel as u32 * resolution / 256;
+                ((cell * 256 + 128) / resolution) as u8
+            }));
+        }
+
+        assert_eq!(cached.match_grid(&input), ansifier().match_grid(&quantized));
+    }
+}
+
+mod match_log {
+    use ansify::{ANSIfier, Dither, Palette};
+
+    use crate::common;
+
+    #[test]
+    fn match_log_has_one_entry_per_cell() {
+        let ansifier = common::ansifier("8.yaml");
+        let input = common::cat();
+
+        let mut entries = Vec::new();
+        let grid = ansifier.match_grid_with_log(&input, |entry| entries.push(entry));
+
+        assert_eq!(entries.len(), (input.width() * input.height()) as usize);
+        assert_eq!(grid, ansifier.match_grid(&input));
+        for entry in &entries {
+            assert_eq!(entry.source, input.get_pixel(entry.x, entry.y).0);
+            assert_eq!(entry.texel, grid[entry.y as usize][entry.x as usize]);
+            assert!(entry.distance >= 0.0);
+        }
+    }
+
+    #[test]
+    fn match_log_follows_dither_and_quantize() {
+        let mut ansifier = common::ansifier("8.yaml");
+        ansifier.set_dither(Dither::BlueNoise);
+        ansifier.set_quantize([3, 3, 2]);
+        let input = common::cat();
+
+        let mut entries = Vec::new();
+        let grid = ansifier.match_grid_with_log(&input, |entry| entries.push(entry));
+
+        assert_eq!(grid, ansifier.match_grid(&input));
+        for entry in &entries {
+            assert_eq!(entry.texel, grid[entry.y as usize][entry.x as usize]);
+        }
+
+        // A texel that was picked for a color far from it is logged that far away
+        let mut ansifier = ANSIfier::new(
+            Palette::new(vec![[0, 0, 0], [255, 255, 255]]),
+            common::tiny_blocks(),
+        )
+        .unwrap();
+        ansifier.set_quantize([1, 1, 1]);
+        let gray = image::RgbImage::from_pixel(1, 1, image::Rgb([100, 100, 100]));
+        let mut entries = Vec::new();
+        ansifier.match_grid_with_log(&gray, |entry| entries.push(entry));
+        assert!(entries[0].distance > 0.3, "{}", entries[0].distance);
+    }
+}
+
+mod out_of_gamut {
+    use ansify::{ANSIfier, OutOfGamut, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    fn gray_ansifier() -> ANSIfier {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "grays.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [128, 128, 128]\n  - [255, 255, 255]\n",
+        );
+
+        return ANSIfier::new(Palette::from(palette_path).unwrap(), common::tiny_blocks()).unwrap();
+    }
+
+    #[test]
+    fn saturated_color_desaturates_to_matchable() {
+        let magenta = RgbImage::from_pixel(1, 1, Rgb([255, 0, 255]));
+        // Magenta's luminance, which desaturating keeps
+        let gray = RgbImage::from_pixel(1, 1, Rgb([72, 72, 72]));
+
+        let mut ansifier = gray_ansifier();
+        assert_ne!(ansifier.match_grid(&magenta), ansifier.match_grid(&gray));
+
+        ansifier.set_out_of_gamut(OutOfGamut::Desaturate, 0.1);
+        assert_eq!(ansifier.match_grid(&magenta), ansifier.match_grid(&gray));
+    }
+}
+
+#[cfg(feature = "rayon")]
+mod par_process {
+    use ansify::{ANSIfier, Blocks, Palette, TextOptions};
+
+    use crate::common;
+
+    #[test]
+    fn parallel_text_equals_serial() {
+        let mut ansifier = ANSIfier::new(
+            Palette::from(common::res("16.yaml")).unwrap(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+        let input = common::cat();
+
+        let (serial_image, serial_text) = ansifier.process(&input);
+        let (parallel_image, parallel_text) = ansifier.par_process(&input);
+        assert!(parallel_image == serial_image);
+        assert_eq!(parallel_text, serial_text);
+
+        ansifier.set_text_options(TextOptions {
+            final_reset: true,
+            trailing_newline: false,
+        });
+        assert_eq!(ansifier.par_process(&input).1, ansifier.process(&input).1);
+    }
+}
+
+mod posterize {
+    use ansify::Texel;
+    use std::collections::BTreeSet;
+
+    use crate::common;
+
+    #[test]
+    fn output_uses_at_most_the_requested_colors() {
+        let ansifier = common::ansifier("16.yaml");
+        let input = common::cat();
+
+        let distinct = |grid: &[Vec<Texel>]| {
+            let render = ansifier.render_grid(grid);
+            return render
+                .pixels()
+                .map(|pixel| pixel.0)
+                .collect::<BTreeSet<_>>()
+                .len();
+        };
+
+        let mut grid = ansifier.match_grid(&input);
+        assert!(distinct(&grid) > 4);
+
+        for colors in [4, 2, 1] {
+            ansifier.posterize(&mut grid, colors);
+            assert!(distinct(&grid) <= colors);
+        }
+    }
+}
+
+mod precision {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, Gamma, Palette, Precision, Texel};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    use crate::common;
+
+    const FOREGROUND: [u8; 3] = [181, 248, 31];
+    const BACKGROUND: [u8; 3] = [17, 215, 74];
+    const SIZE: u32 = 8;
+
+    // One glyph per coverage count, so consecutive glyphs blend to nearly identical colors
+    fn ramp_glyph(coverage: u32) -> char {
+        return char::from_u32(0x2800 + coverage).unwrap();
+    }
+
+    fn ansifier(precision: Precision) -> ANSIfier {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "palette.yaml",
+            format!("colors:\n  - {:?}\n  - {:?}\n", BACKGROUND, FOREGROUND),
+        );
+
+        let mut blocks = format!("width: {}\nheight: {}\nblocks:\n", SIZE, SIZE);
+        for coverage in 0..=SIZE * SIZE {
+            let rows: Vec<String> = (0..SIZE)
+                .map(|y| {
+                    let row: String = (0..SIZE)
+                        .map(|x| if y * SIZE + x < coverage { '1' } else { '0' })
+                        .collect();
+                    format!("\"{}\"", row)
+                })
+                .collect();
+            blocks += &format!("  {}: [{}]\n", ramp_glyph(coverage), rows.join(", "));
+        }
+        let blocks_path = dir.write("blocks.yaml", blocks);
+
+        return ANSIfier::with_options(
+            Palette::from(palette_path).unwrap(),
+            Blocks::from(blocks_path).unwrap(),
+            ANSIfierOptions {
+                gamma: Gamma::Power(1.0),
+                precision,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    // The glyph whose blend is nearest to the color, computed directly in f64
+    fn exact_nearest(color: [u8; 3]) -> char {
+        let distance = |coverage: u32| -> f64 {
+            let ratio = coverage as f64 / (SIZE * SIZE) as f64;
+            return (0..3)
+                .map(|i| {
+                    let blended =
+                        FOREGROUND[i] as f64 * ratio + BACKGROUND[i] as f64 * (1.0 - ratio);
+                    (blended - color[i] as f64).powi(2)
+                })
+                .sum();
+        };
+
+        let coverage = (0..=SIZE * SIZE)
+            .min_by(|a, b| distance(*a).partial_cmp(&distance(*b)).unwrap())
+            .unwrap();
+        return ramp_glyph(coverage);
+    }
+
+    fn match_color(ansifier: &ANSIfier, color: [u8; 3]) -> Texel {
+        return ansifier.match_grid(&RgbImage::from_pixel(1, 1, Rgb(color)))[0][0];
+    }
+
+    #[test]
+    fn f64_resolves_near_tie() {
+        let f32_ansifier = ansifier(Precision::F32);
+        let f64_ansifier = ansifier(Precision::F64);
+
+        // The two nearest blends are about 1e-6 apart relative to their distance,
+        // which is below what f32 can tell apart
+        let color = [78, 246, 54];
+        let expected = exact_nearest(color);
+
+        let texel = match_color(&f64_ansifier, color);
+        assert_eq!(texel.block, expected);
+        assert_eq!(texel.foreground_color, 1);
+        assert_eq!(texel.background_color, 0);
+
+        assert_ne!(match_color(&f32_ansifier, color).block, expected);
+    }
+
+    #[test]
+    fn f64_keeps_blends_closer_than_f32_rounding() {
+        // 260 pixel cells step each channel of a blend between adjacent grays by less
+        // than 1/65536, which used to merge some neighbouring coverages
+        let (width, height) = (20, 13);
+        let area = width * height;
+        let blocks: BTreeMap<char, Vec<Vec<bool>>> = (0..=area)
+            .map(|coverage| {
+                let bitmap = (0..height)
+                    .map(|y| (0..width).map(|x| y * width + x < coverage).collect())
+                    .collect();
+                (ramp_glyph(coverage), bitmap)
+            })
+            .collect();
+        let ansifier = ANSIfier::with_options(
+            Palette::new(vec![[128, 128, 128], [129, 129, 129]]),
+            Blocks::new(width, height, blocks).unwrap(),
+            ANSIfierOptions {
+                gamma: Gamma::Power(1.0),
+                precision: Precision::F64,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+
+        // Nearest to a blend with 87 of the 260 pixels in the lighter gray
+        let texel = match_color(&ansifier, [129, 128, 128]);
+        let coverage = texel.block as u32 - 0x2800;
+        let lighter = if texel.foreground_color == 1 {
+            coverage
+        } else {
+            area - coverage
+        };
+        assert_eq!(lighter, 87, "{:?}", texel);
+    }
+}
+
+mod process_dirty {
+    use ansify::{ANSIfier, Blocks, Dither, Palette, Rect};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return ANSIfier::new(
+            Palette::from(common::res("8.yaml")).unwrap(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn first_call_reports_every_cell() {
+        let mut ansifier = ansifier();
+        let img = RgbImage::from_pixel(6, 4, Rgb([20, 20, 20]));
+
+        let changes = ansifier.process_dirty(
+            &img,
+            Rect {
+                x: 0,
+                y: 0,
+                width: 0,
+                height: 0,
+            },
+        );
+        assert_eq!(changes.len(), 24);
+        assert_eq!(ansifier.previous_grid(), Some(&ansifier.match_grid(&img)));
+    }
+
+    #[test]
+    fn editing_one_cell_changes_only_that_cell() {
+        let mut ansifier = ansifier();
+        let mut img = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 20]));
+        let everything = Rect {
+            x: 0,
+            y: 0,
+            width: 6,
+            height: 4,
+        };
+        ansifier.process_dirty(&img, everything);
+
+        let before = ansifier.match_grid(&img)[2][3];
+        img.put_pixel(3, 2, Rgb([255, 255, 255]));
+        let after = ansifier.match_grid(&img)[2][3];
+        assert_ne!(before, after);
+
+        // The dirty region is wider than the edit, but unchanged cells are not reported
+        let changes = ansifier.process_dirty(
+            &img,
+            Rect {
+                x: 2,
+                y: 1,
+                width: 3,
+                height: 3,
+            },
+        );
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            (changes[0].x, changes[0].y, changes[0].texel),
+            (3, 2, after)
+        );
+
+        assert!(ansifier.process_dirty(&img, everything).is_empty());
+        assert_eq!(ansifier.previous_grid(), Some(&ansifier.match_grid(&img)));
+    }
+
+    #[test]
+    fn error_diffusion_matches_a_full_render() {
+        let mut ansifier = ansifier();
+        ansifier.set_dither(Dither::FloydSteinberg { strength: 1.0 });
+        let mut img = RgbImage::from_fn(6, 4, |x, y| Rgb([(x * 40) as u8, (y * 60) as u8, 90]));
+        let edit = Rect {
+            x: 1,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+        ansifier.process_dirty(&img, edit);
+
+        img.put_pixel(1, 1, Rgb([255, 255, 255]));
+        let changes = ansifier.process_dirty(&img, edit);
+
+        // The edited cell's error reaches cells outside the dirty rect
+        assert!(changes.iter().any(|change| (change.x, change.y) != (1, 1)));
+        assert_eq!(ansifier.previous_grid(), Some(&ansifier.match_grid(&img)));
+    }
+}
+
+mod process_dynamic {
+    use ansify::ANSIfier;
+    use image::{DynamicImage, ImageBuffer, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return common::ansifier("16.yaml");
+    }
+
+    fn gray_ramp() -> RgbImage {
+        return RgbImage::from_fn(16, 4, |x, _| {
+            let level = (x * 17) as u8;
+            Rgb([level, level, level])
+        });
+    }
+
+    #[test]
+    fn grayscale_is_expanded() {
+        let ansifier = ansifier();
+        let expected = ansifier.process(&gray_ramp());
+
+        let luma8 =
+            DynamicImage::ImageLuma8(ImageBuffer::from_fn(16, 4, |x, _| Luma([(x * 17) as u8])));
+        assert_eq!(ansifier.process_dynamic(&luma8), expected);
+
+        // Every 8-bit level is stored as level * 257 at 16 bits
+        let luma16 = DynamicImage::ImageLuma16(ImageBuffer::from_fn(16, 4, |x, _| {
+            Luma([(x * 17) as u16 * 257])
+        }));
+        assert_eq!(ansifier.process_dynamic(&luma16), expected);
+    }
+
+    #[test]
+    fn transparency_is_composited_over_black() {
+        let ansifier = ansifier();
+
+        let transparent =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 0])));
+        let black = RgbImage::from_pixel(4, 4, Rgb([0, 0, 0]));
+
+        assert_eq!(
+            ansifier.process_dynamic(&transparent),
+            ansifier.process(&black)
+        );
+    }
+}
+
+mod quantize {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    fn ansifier() -> ANSIfier {
+        return ANSIfier::new(Palette::vga_16(), Blocks::half_blocks()).unwrap();
+    }
+
+    #[test]
+    fn queries_are_rounded_to_the_precision() {
+        let mut quantized = ansifier();
+        quantized.set_quantize([5, 6, 5]);
+        let exact = ansifier();
+
+        let img = RgbImage::from_fn(64, 16, |x, y| {
+            Rgb([(x * 4 + 1) as u8, (y * 16 + 3) as u8, (x * 2 + y * 7) as u8])
+        });
+
+        // Each channel snapped to the nearest of its 2^bits levels
+        let round = |value: u8, bits: u32| {
+            let levels = (1u32 << bits) - 1;
+            let level = (value as f32 * levels as f32 / 255.0).round();
+            (level * 255.0 / levels as f32).round() as u8
+        };
+        let rounded = RgbImage::from_fn(64, 16, |x, y| {
+            let [r, g, b] = img.get_pixel(x, y).0;
+            Rgb([round(r, 5), round(g, 6), round(b, 5)])
+        });
+
+        assert_eq!(quantized.match_grid(&img), exact.match_grid(&rounded));
+    }
+
+    #[test]
+    fn pixels_equal_after_rounding_share_one_match() {
+        // Every channel below 128 rounds to 0 with one bit
+        let img = RgbImage::from_fn(100, 1, |x, _| Rgb([x as u8, 100 - x as u8, 20]));
+
+        let exact = ansifier();
+        exact.match_grid(&img);
+        assert_eq!(exact.tree_queries(), 100);
+
+        let mut quantized = ansifier();
+        quantized.set_quantize([1, 1, 1]);
+        let grid = quantized.match_grid(&img);
+        assert_eq!(quantized.tree_queries(), 1);
+        assert!(grid[0].iter().all(|texel| *texel == grid[0][0]));
+    }
+}
+
+mod reachable_glyphs {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use std::collections::BTreeSet;
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "palette.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 255, 255]\n",
+        );
+        // ▄ covers as much as ▀ and █ shows the same solid colors as the empty glyph, so
+        // neither ever blends to a color another glyph does not already reach first
+        let blocks_path = dir.write(
+        "blocks.yaml",
+        "width: 2\nheight: 2\nblocks:\n  \" \": [\"00\", \"00\"]\n  ▀: [\"11\", \"00\"]\n  ▄: [\"00\", \"11\"]\n  █: [\"11\", \"11\"]\n",
+    );
+
+        return ANSIfier::new(
+            Palette::from(palette_path).unwrap(),
+            Blocks::from(blocks_path).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn dominated_glyphs_are_unreachable() {
+        let reachable = ansifier().reachable_glyphs();
+
+        assert_eq!(reachable, BTreeSet::from([' ', '▀']));
+    }
+
+    #[test]
+    fn lut_cache_agrees() {
+        let ansifier = ansifier();
+        let reachable = ansifier.reachable_glyphs();
+
+        assert_eq!(ansifier.with_lut_cache(16).reachable_glyphs(), reachable);
+    }
+}
+
+mod two_scale {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "palette.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 255, 255]\n",
+        );
+        let blocks_path = dir.write(
+        "blocks.yaml",
+        "width: 2\nheight: 2\nblocks:\n  \" \": [\"00\", \"00\"]\n  █: [\"11\", \"11\"]\n  ▌: [\"10\", \"10\"]\n  ▀: [\"11\", \"00\"]\n",
+    );
+
+        return ANSIfier::new(
+            Palette::from(palette_path).unwrap(),
+            Blocks::from(blocks_path).unwrap(),
+        )
+        .unwrap();
+    }
+
+    // Two cells that both average to the same gray, one split left and right and the
+    // other top and bottom
+    fn full_res() -> RgbImage {
+        return RgbImage::from_fn(4, 2, |x, y| {
+            let white = if x < 2 { x == 0 } else { y == 0 };
+            if white {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        });
+    }
+
+    #[test]
+    fn detail_glyphs_follow_full_res_luminance() {
+        let ansifier = ansifier();
+        let coarse = ansifier.match_grid(&RgbImage::from_pixel(2, 1, Rgb([127, 127, 127])))[0][0];
+
+        let grid = ansifier.match_grid_two_scale(&full_res(), 1.0);
+
+        assert_eq!(grid[0][0].block, '▌');
+        assert_eq!(grid[0][1].block, '▀');
+        for texel in grid[0].iter() {
+            // White is drawn on the bright side of the glyph
+            assert_eq!(texel.foreground_color, 1);
+
+            let mut colors = [texel.foreground_color, texel.background_color];
+            let mut coarse_colors = [coarse.foreground_color, coarse.background_color];
+            colors.sort();
+            coarse_colors.sort();
+            assert_eq!(colors, coarse_colors);
+        }
+    }
+
+    #[test]
+    fn zero_strength_keeps_coarse_pass() {
+        let ansifier = ansifier();
+        let coarse = ansifier.match_grid(&RgbImage::from_pixel(2, 1, Rgb([127, 127, 127])));
+
+        assert_eq!(ansifier.match_grid_two_scale(&full_res(), 0.0), coarse);
+
+        let (out, _) = ansifier.process_two_scale(&full_res(), 1.0);
+        assert_eq!(out.dimensions(), (4, 2));
+    }
+}
+
+mod uniform_runs {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn uniform_half_skips_tree_queries() {
+        let ansifier = ANSIfier::new(
+            Palette::from(common::res("8.yaml")).unwrap(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+
+        // The left half is a flat border, the right half never repeats a neighbor
+        let img = RgbImage::from_fn(64, 32, |x, y| {
+            if x < 32 {
+                Rgb([10, 10, 10])
+            } else {
+                Rgb([(x * 7) as u8, (y * 8) as u8, ((x + y) * 3) as u8])
+            }
+        });
+
+        let grid = ansifier.match_grid(&img);
+        let queries = ansifier.tree_queries();
+        assert!(queries <= 32 * 33, "{} queries", queries);
+
+        // Identical inputs still produce identical output
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                let single = RgbImage::from_pixel(1, 1, *img.get_pixel(x as u32, y as u32));
+                assert_eq!(ansifier.match_grid(&single)[0][0], *texel);
+            }
+        }
+    }
+}
+
+mod weighted_rgb {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, DistanceMetric, Palette};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    const WEIGHTS: [f32; 3] = [0.3, 0.59, 0.11];
+
+    // Solid cells only, so every texel is exactly one palette color
+    fn ansifier(palette: Palette, metric: DistanceMetric) -> ANSIfier {
+        let blocks = Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+        return ANSIfier::with_options(
+            palette,
+            blocks,
+            ANSIfierOptions {
+                metric,
+                ..ANSIfierOptions::default()
+            },
+        )
+        .unwrap();
+    }
+
+    fn matched(ansifier: &ANSIfier, color: [u8; 3]) -> u8 {
+        let texel = ansifier.match_grid(&RgbImage::from_pixel(1, 1, Rgb(color)))[0][0];
+        return if texel.block == '█' {
+            texel.foreground_color
+        } else {
+            texel.background_color
+        };
+    }
+
+    fn weighted_distance(color_a: &[u8; 3], color_b: &[u8; 3]) -> f32 {
+        return (0..3)
+            .map(|i| WEIGHTS[i] * (color_a[i] as f32 - color_b[i] as f32).powi(2))
+            .sum::<f32>();
+    }
+
+    #[test]
+    fn matches_the_nearest_weighted_color() {
+        let palette = Palette::vga_16();
+        let ansifier = ansifier(palette.clone(), DistanceMetric::WeightedRgb);
+
+        let img = RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([(x * 8) as u8, (y * 8) as u8, ((x * 3 + y * 5) % 256) as u8])
+        });
+        let grid = ansifier.match_grid(&img);
+
+        for (x, y, pixel) in img.enumerate_pixels() {
+            let texel = grid[y as usize][x as usize];
+            let index = if texel.block == '█' {
+                texel.foreground_color
+            } else {
+                texel.background_color
+            };
+            let matched = weighted_distance(&pixel.0, &palette.colors()[index as usize]);
+            let nearest = palette
+                .colors()
+                .iter()
+                .map(|color| weighted_distance(&pixel.0, color))
+                .fold(f32::INFINITY, f32::min);
+            assert!(matched <= nearest + 1.0, "{:?}", pixel);
+        }
+    }
+
+    #[test]
+    fn unweighted_stays_the_default() {
+        assert_eq!(ANSIfierOptions::default().metric, DistanceMetric::Euclidean);
+
+        // Blue adds little to brightness, so a far off blue is nearer than a dim olive
+        let palette = Palette::new(vec![[0, 0, 200], [90, 90, 0]]);
+        let euclidean = ansifier(palette.clone(), DistanceMetric::Euclidean);
+        let weighted = ansifier(palette, DistanceMetric::WeightedRgb);
+        assert_eq!(matched(&euclidean, [0, 0, 0]), 1);
+        assert_eq!(matched(&weighted, [0, 0, 0]), 0);
+    }
+}
@@ -0,0 +1,1224 @@
+// Rendering, text and the other output formats
+
+mod common;
+
+mod aa_edges {
+    use ansify::{ANSIfier, Palette, RenderOptions, Texel};
+    use image::{GrayImage, Luma};
+
+    use crate::common;
+
+    #[test]
+    fn glyph_edges_against_transparent_cells_get_partial_alpha() {
+        let mut ansifier = ANSIfier::new(
+            Palette::new(vec![[0, 0, 0], [255, 255, 255]]),
+            common::tiny_blocks(),
+        )
+        .unwrap();
+
+        // A solid glyph next to a transparent cell
+        let texel = |block| Texel {
+            foreground_color: 1,
+            background_color: 0,
+            block,
+        };
+        let grid = vec![vec![texel('█'), texel('░')]];
+        let alpha = GrayImage::from_fn(2, 1, |x, _| Luma([if x == 0 { 255 } else { 0 }]));
+
+        // tiny.yaml cells are 3x7, so x = 2 is the glyph's right edge
+        let hard = ansifier.render_grid_rgba(&grid, &alpha);
+        assert_eq!(hard.get_pixel(2, 3).0[3], 255);
+
+        ansifier.set_render_options(RenderOptions {
+            aa_edges: true,
+            ..RenderOptions::default()
+        });
+        let soft = ansifier.render_grid_rgba(&grid, &alpha);
+
+        let edge = soft.get_pixel(2, 3).0;
+        assert_eq!(&edge[..3], &[255, 255, 255]);
+        assert!(edge[3] > 0 && edge[3] < 255, "{}", edge[3]);
+
+        // The glyph's inside and the transparent cell's background are unchanged
+        assert_eq!(soft.get_pixel(1, 3).0[3], 255);
+        assert_eq!(soft.get_pixel(4, 3).0[3], 0);
+    }
+}
+
+mod alpha {
+    use ansify::ANSIfier;
+    use image::{Rgba, RgbaImage};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return common::ansifier("8.yaml");
+    }
+
+    #[test]
+    fn alpha_threshold_snaps_to_transparent_or_opaque() {
+        let ansifier = ansifier();
+
+        let faint = RgbaImage::from_pixel(1, 1, Rgba([200, 40, 40, 26]));
+        let (out, _) = ansifier.process_rgba(&faint, Some(0.5));
+        assert!(out.pixels().all(|pixel| pixel.0[3] == 0));
+
+        let solid = RgbaImage::from_pixel(1, 1, Rgba([200, 40, 40, 230]));
+        let (out, _) = ansifier.process_rgba(&solid, Some(0.5));
+        assert!(out.pixels().all(|pixel| pixel.0[3] == 255));
+    }
+
+    #[test]
+    fn alpha_without_threshold_is_kept() {
+        let ansifier = ansifier();
+
+        let faint = RgbaImage::from_pixel(1, 1, Rgba([200, 40, 40, 26]));
+        let (out, _) = ansifier.process_rgba(&faint, None);
+        assert!(out.pixels().all(|pixel| pixel.0[3] == 26));
+    }
+}
+
+mod atlas {
+    use ansify::{ANSIfier, Palette, Texel};
+    use image::{GrayImage, Luma, Rgb};
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return ANSIfier::new(
+            Palette::new(vec![[0, 0, 0], [200, 100, 0], [0, 0, 100]]),
+            common::tiny_blocks(),
+        )
+        .unwrap();
+    }
+
+    // Two 2x3 cells: `a` has a lit top row and a half covered middle row, `b` is
+    // lit everywhere
+    fn atlas() -> GrayImage {
+        return GrayImage::from_fn(4, 3, |x, y| {
+            let coverage = match (x / 2, y) {
+                (0, 0) => 255,
+                (0, 1) => 128,
+                (0, _) => 0,
+                _ => 255,
+            };
+            return Luma([coverage]);
+        });
+    }
+
+    #[test]
+    fn cells_render_from_their_atlas_region() {
+        let texel = |block| Texel {
+            foreground_color: 1,
+            background_color: 2,
+            block,
+        };
+        let grid = vec![vec![texel('b'), texel('a')]];
+
+        let out = ansifier()
+            .render_with_atlas(&grid, &atlas(), 2, 3, "ab")
+            .unwrap();
+        assert_eq!(out.dimensions(), (4, 3));
+
+        for y in 0..3 {
+            assert_eq!(out.get_pixel(0, y), &Rgb([200, 100, 0]));
+        }
+        assert_eq!(out.get_pixel(2, 0), &Rgb([200, 100, 0]));
+        assert_eq!(out.get_pixel(3, 1), &Rgb([100, 50, 50]));
+        assert_eq!(out.get_pixel(3, 2), &Rgb([0, 0, 100]));
+    }
+
+    #[test]
+    fn characters_missing_from_the_atlas_are_an_error() {
+        let grid = vec![vec![Texel {
+            foreground_color: 1,
+            background_color: 2,
+            block: 'c',
+        }]];
+
+        let error = ansifier()
+            .render_with_atlas(&grid, &atlas(), 2, 3, "ab")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("no cell for `c`"), "{}", error);
+    }
+}
+
+mod composite {
+    use ansify::{composite_over, ANSIfier, Blocks, Palette, Texel};
+    use image::{GrayImage, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn transparent_cells_show_the_background() {
+        let blocks = Blocks::new(2, 2, BTreeMap::from([('█', vec![vec![true; 2]; 2])])).unwrap();
+        let ansifier =
+            ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+        let texel = Texel {
+            foreground_color: 1,
+            background_color: 0,
+            block: '█',
+        };
+        let grid = vec![vec![texel; 2]];
+        let alpha = GrayImage::from_fn(2, 1, |x, _| Luma([if x == 0 { 255 } else { 0 }]));
+
+        // The background is scaled to the render, which is 4x2 pixels
+        let background = RgbImage::from_pixel(8, 4, Rgb([0, 0, 200]));
+        let out = composite_over(&ansifier.render_grid_rgba(&grid, &alpha), &background);
+
+        assert_eq!(out.dimensions(), (4, 2));
+        for y in 0..2 {
+            assert_eq!(out.get_pixel(1, y).0, [255, 255, 255]);
+            assert_eq!(out.get_pixel(2, y).0, [0, 0, 200]);
+        }
+    }
+
+    #[test]
+    fn partial_alpha_blends() {
+        let foreground = RgbaImage::from_pixel(1, 1, Rgba([200, 100, 0, 128]));
+        let background = RgbImage::from_pixel(1, 1, Rgb([0, 100, 200]));
+        assert_eq!(
+            composite_over(&foreground, &background).get_pixel(0, 0).0,
+            [100, 100, 100]
+        );
+    }
+}
+
+mod empty_char {
+    use ansify::{ANSIfier, Blocks, Palette, Texel};
+    use std::collections::BTreeMap;
+
+    fn texel(block: char) -> Texel {
+        return Texel {
+            foreground_color: 1,
+            background_color: 0,
+            block,
+        };
+    }
+
+    #[test]
+    fn empty_cells_print_the_empty_char() {
+        // `_` has no foreground pixels either, so it counts as empty too
+        let blocks = Blocks::new(
+            2,
+            1,
+            BTreeMap::from([
+                (' ', vec![vec![false, false]]),
+                ('_', vec![vec![false, false]]),
+                ('▌', vec![vec![true, false]]),
+                ('█', vec![vec![true, true]]),
+            ]),
+        )
+        .unwrap();
+        let mut ansifier =
+            ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+        let grid = vec![vec![texel(' '), texel('▌'), texel('_'), texel('█')]];
+
+        assert_eq!(ansifier.grid_plain(&grid), " ▌_█\n");
+
+        ansifier.set_empty_char(Some('.'));
+        assert_eq!(ansifier.grid_plain(&grid), ".▌.█\n");
+        assert_eq!(
+            ansifier.grid_text_minimal(&grid),
+            "\x1b[38;5;1;48;5;0m.▌.█\x1b[0m\n"
+        );
+        assert!(!ansifier.grid_text(&grid).contains(' '));
+    }
+}
+
+mod frame_step {
+    use ansify::frame_step;
+    use image::{Delay, Frame, Rgba, RgbaImage};
+    use std::time::Duration;
+
+    #[test]
+    fn every_second_frame_with_doubled_delays() {
+        let frames = (0..4u8).map(|i| {
+            Ok(Frame::from_parts(
+                RgbaImage::from_pixel(2, 2, Rgba([i, i, i, 255])),
+                0,
+                0,
+                Delay::from_numer_denom_ms(100, 1),
+            ))
+        });
+
+        let stepped: Vec<Frame> = frame_step(frames, 2).map(Result::unwrap).collect();
+        assert_eq!(stepped.len(), 2);
+        assert_eq!(stepped[0].buffer().get_pixel(0, 0).0[0], 0);
+        assert_eq!(stepped[1].buffer().get_pixel(0, 0).0[0], 2);
+        for frame in stepped {
+            assert_eq!(Duration::from(frame.delay()), Duration::from_millis(200));
+        }
+    }
+}
+
+mod golden {
+    use ansify::{ANSIfier, ANSIfierOptions, Blocks, DistanceMetric, Gamma, Palette, Precision};
+    use std::fs;
+
+    use crate::common;
+
+    // Set ANSIFY_BLESS=1 to regenerate the expected outputs after an intentional change
+    fn golden(case: &str, input: &str, palette: &str, blocks: &str, options: ANSIfierOptions) {
+        let root = common::root();
+        let golden = root.join("tests").join("golden");
+
+        let ansifier = ANSIfier::with_options(
+            Palette::from(common::res(palette)).unwrap(),
+            Blocks::from(common::res(blocks)).unwrap(),
+            options,
+        )
+        .unwrap();
+        let input = image::open(golden.join(input)).unwrap().into_rgb8();
+
+        let (out, text) = ansifier.process(&input);
+
+        let expected_image_path = golden.join(format!("{}.expected.png", case));
+        let expected_text_path = golden.join(format!("{}.expected.txt", case));
+
+        if std::env::var_os("ANSIFY_BLESS").is_some() {
+            out.save(&expected_image_path).unwrap();
+            fs::write(&expected_text_path, &text).unwrap();
+            return;
+        }
+
+        let expected_image = image::open(&expected_image_path).unwrap().into_rgb8();
+        assert_eq!(out.dimensions(), expected_image.dimensions());
+        assert!(
+            out.as_raw() == expected_image.as_raw(),
+            "{} output image differs from {}",
+            case,
+            expected_image_path.display()
+        );
+
+        let expected_text = fs::read_to_string(&expected_text_path).unwrap();
+        assert!(
+            text == expected_text,
+            "{} output text differs from {}",
+            case,
+            expected_text_path.display()
+        );
+    }
+
+    // Blending in linear light instead of the raw channel values changes the matches
+    const SRGB: ANSIfierOptions = ANSIfierOptions {
+        gamma: Gamma::Srgb,
+        precision: Precision::F32,
+        metric: DistanceMetric::Euclidean,
+    };
+
+    #[test]
+    fn golden_cat_8_tiny() {
+        golden(
+            "cat_8_tiny",
+            "cat.png",
+            "8.yaml",
+            "tiny.yaml",
+            ANSIfierOptions::default(),
+        );
+    }
+
+    #[test]
+    fn golden_cat_8_classic() {
+        golden(
+            "cat_8_classic",
+            "cat.png",
+            "8.yaml",
+            "classic.yaml",
+            ANSIfierOptions::default(),
+        );
+    }
+
+    #[test]
+    fn golden_cat_8_tiny_srgb() {
+        golden("cat_8_tiny_srgb", "cat.png", "8.yaml", "tiny.yaml", SRGB);
+    }
+
+    #[test]
+    fn golden_cat_8_classic_srgb() {
+        golden(
+            "cat_8_classic_srgb",
+            "cat.png",
+            "8.yaml",
+            "classic.yaml",
+            SRGB,
+        );
+    }
+}
+
+mod grid_csv {
+    use ansify::{ANSIfier, Blocks, Palette, Texel};
+    use std::collections::BTreeMap;
+
+    fn texel(block: char) -> Texel {
+        return Texel {
+            foreground_color: 1,
+            background_color: 0,
+            block,
+        };
+    }
+
+    #[test]
+    fn csv_uses_the_text_characters() {
+        let blocks = Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+        let mut ansifier =
+            ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+        let grid = vec![vec![texel(' '), texel('█')], vec![texel('█'), texel(' ')]];
+
+        assert_eq!(ansifier.grid_csv(&grid), " ,█\n█, \n");
+
+        // Characters that need quoting are quoted after substitution
+        ansifier.set_empty_char(Some('.'));
+        ansifier.set_substitutions(BTreeMap::from([('█', ',')]));
+        assert_eq!(ansifier.grid_csv(&grid), ".,\",\"\n\",\",.\n");
+    }
+}
+
+mod grid_rust {
+    use ansify::{ANSIfier, Blocks, Palette, Texel};
+    use std::collections::BTreeMap;
+
+    fn texel(foreground_color: u8, background_color: u8, block: char) -> Texel {
+        return Texel {
+            foreground_color,
+            background_color,
+            block,
+        };
+    }
+
+    #[test]
+    fn emits_text_and_grid_constants() {
+        let blocks = Blocks::new(
+            1,
+            1,
+            BTreeMap::from([(' ', vec![vec![false]]), ('█', vec![vec![true]])]),
+        )
+        .unwrap();
+        let ansifier =
+            ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+        let grid = vec![
+            vec![texel(1, 0, '█'), texel(0, 1, ' '), texel(1, 1, '█')],
+            vec![texel(0, 0, ' '), texel(1, 0, '█'), texel(0, 1, '█')],
+        ];
+
+        let source = ansifier.grid_rust(&grid, "ART");
+
+        let text = format!(
+            "pub const ART_TEXT: &str = {:?};\n\n",
+            ansifier.grid_text(&grid)
+        );
+        assert!(source.starts_with(&text));
+
+        let array = &source[text.len()..];
+        assert_eq!(
+            array,
+            "pub const ART_GRID: [[(u8, u8, char); 3]; 2] = [\n    \
+         [(1, 0, '█'), (0, 1, ' '), (1, 1, '█')],\n    \
+         [(0, 0, ' '), (1, 0, '█'), (0, 1, '█')],\n\
+         ];\n"
+        );
+    }
+}
+
+mod html {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn html_page_wraps_pre_block() {
+        let ansifier = ANSIfier::new(
+            Palette::from(common::res("8.yaml")).unwrap(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+        let img = RgbImage::from_fn(4, 3, |x, y| Rgb([(x * 60) as u8, (y * 100) as u8, 0]));
+
+        let html = ansifier.process_html(&img);
+        assert!(html.starts_with("<pre>"));
+        assert!(html.ends_with("</pre>\n"));
+        assert_eq!(html.matches("<span ").count(), 12);
+
+        let page = ansifier.process_html_page(&img);
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains(&html));
+        assert!(page.contains("monospace"));
+    }
+}
+
+mod indices {
+    use crate::common;
+
+    #[test]
+    fn cell_index_image_matches_grid() {
+        let ansifier = common::ansifier("8.yaml");
+        let input = common::cat();
+
+        let grid = ansifier.match_grid(&input);
+        let (_, indices) = ansifier.process_indices(&input);
+        assert_eq!(indices.dimensions(), input.dimensions());
+
+        // Block indices follow the map's ordering, which is sorted by code point
+        let glyphs = ['█', '░', '▒', '▓'];
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                let [foreground, background, block, alpha] =
+                    indices.get_pixel(x as u32, y as u32).0;
+                assert_eq!(foreground, texel.foreground_color);
+                assert_eq!(background, texel.background_color);
+                assert_eq!(glyphs[block as usize], texel.block);
+                assert_eq!(alpha, 255);
+            }
+        }
+    }
+}
+
+mod lut {
+    use ansify::{ANSIfier, Palette};
+
+    use crate::common;
+
+    #[test]
+    fn streamed_lut_matches_in_memory() {
+        let dir = common::temp_dir();
+        let palette_path = dir.write(
+            "palette.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 0, 0]\n  - [255, 255, 255]\n",
+        );
+
+        let ansifier =
+            ANSIfier::new(Palette::from(palette_path).unwrap(), common::tiny_blocks()).unwrap();
+
+        let (lut, map) = ansifier.generate_lut_and_map();
+
+        let mut lut_png = Vec::new();
+        let mut map_png = Vec::new();
+        ansifier
+            .write_lut_and_map(&mut lut_png, &mut map_png)
+            .unwrap();
+
+        let streamed_lut = image::load_from_memory(&lut_png).unwrap().into_rgba8();
+        let streamed_map = image::load_from_memory(&map_png).unwrap().into_rgba8();
+
+        assert_eq!(streamed_lut.dimensions(), lut.dimensions());
+        assert!(streamed_lut.as_raw() == lut.as_raw());
+        assert!(streamed_map == map);
+    }
+}
+
+mod lut_compatibility {
+    use ansify::{ANSIfier, Blocks, Palette};
+
+    use crate::common;
+
+    fn ansifier(palette: &str, blocks: &str) -> ANSIfier {
+        return ANSIfier::new(
+            Palette::from(common::res(palette)).unwrap(),
+            Blocks::from(common::res(blocks)).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn compatible_palette_and_blocks() {
+        let report = ansifier("8.yaml", "tiny.yaml").lut_compatibility_report();
+
+        assert!(report.is_compatible());
+        assert_eq!(report.colors, 8);
+        assert_eq!(report.blocks, 4);
+        assert_eq!(report.block_area, 21);
+        assert_eq!(report.estimated_memory_bytes, (4096 * 4096 + 256 * 3) * 4);
+    }
+
+    #[test]
+    fn oversized_blocks_are_incompatible() {
+        let report = ansifier("8.yaml", "classic.yaml").lut_compatibility_report();
+
+        assert!(!report.is_compatible());
+        assert_eq!(report.block_area, 7 * 17);
+        assert_eq!(report.problems.len(), 1);
+        assert!(report.problems[0].contains("at most 64"));
+    }
+}
+
+mod lut_map_u64 {
+    use ansify::{ANSIfier, Blocks, Palette};
+
+    use crate::common;
+
+    // 8x8 blocks, so the lower half lands in the high 32 bits
+    fn blocks(width: usize) -> Blocks {
+        let mut yaml = format!("width: {}\nheight: 8\nblocks:\n", width);
+        for (character, lit_rows) in [(' ', 0..0), ('▀', 0..4), ('▄', 4..8)] {
+            yaml += &format!("  \"{}\":\n", character);
+            for y in 0..8 {
+                let pixel = if lit_rows.contains(&y) { "1" } else { "0" };
+                yaml += &format!("    - \"{}\"\n", pixel.repeat(width));
+            }
+        }
+        let dir = common::temp_dir();
+        return Blocks::from(dir.write("blocks.yaml", yaml)).unwrap();
+    }
+
+    fn ansifier(blocks: Blocks) -> ANSIfier {
+        return ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+    }
+
+    #[test]
+    fn map_holds_64_pixel_blocks() {
+        let ansifier = ansifier(blocks(8));
+        assert!(ansifier.lut_compatibility_report().is_compatible());
+
+        let (_, map) = ansifier.generate_lut_and_map();
+        assert_eq!(map.dimensions(), (256, 3));
+
+        // Blocks are indexed in character order, ' ', '▀', '▄'
+        let bits = |index: u32| {
+            let low = map.get_pixel(index, 1).0;
+            let high = map.get_pixel(index, 2).0;
+            return u64::from_le_bytes([
+                low[0], low[1], low[2], low[3], high[0], high[1], high[2], high[3],
+            ]);
+        };
+        assert_eq!(bits(0), 0);
+        assert_eq!(bits(1), 0x0000_0000_FFFF_FFFF);
+        assert_eq!(bits(2), 0xFFFF_FFFF_0000_0000);
+    }
+
+    #[test]
+    fn larger_blocks_are_incompatible() {
+        let report = ansifier(blocks(9)).lut_compatibility_report();
+
+        assert!(!report.is_compatible());
+        assert_eq!(report.block_area, 72);
+        assert!(report.problems[0].contains("at most 64"));
+    }
+
+    #[test]
+    fn bitmask_holds_64_pixel_blocks() {
+        let blocks = Blocks::from_yaml_str(
+            "width: 8\nheight: 8\nblocks:\n  \" \": 0\n  ▄: 0xFFFFFFFF00000000\n",
+        )
+        .unwrap();
+
+        let lower_half = blocks.bitmap('▄').unwrap();
+        assert!(lower_half[..4].iter().flatten().all(|bit| !bit));
+        assert!(lower_half[4..].iter().flatten().all(|bit| *bit));
+
+        let error = Blocks::from_yaml_str("width: 9\nheight: 8\nblocks:\n  a: 1\n")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("cannot hold"), "{}", error);
+    }
+}
+
+mod match_stats {
+    use image::{Rgb, RgbImage};
+    use serde_yaml::Value;
+    use std::time::Duration;
+
+    use crate::common;
+
+    #[test]
+    fn stats_json_has_every_key() {
+        let ansifier = common::ansifier("8.yaml");
+        let img = RgbImage::from_fn(12, 5, |x, y| Rgb([(x * 20) as u8, (y * 50) as u8, 128]));
+        let grid = ansifier.match_grid(&img);
+        let stats = ansifier.match_stats(&img, &grid, Duration::from_millis(1500));
+
+        assert_eq!((stats.columns, stats.rows), (12, 5));
+        assert_eq!(stats.palette_size, 8);
+        assert_eq!(stats.color_usage.len(), 8);
+        assert!(stats.mean_error > 0.0 && stats.mean_error <= stats.max_error);
+
+        // JSON is valid YAML, so it can be parsed without another dependency
+        let json: Value = serde_yaml::from_str(&stats.to_json()).unwrap();
+        let integers = [
+            "palette_size",
+            "blocks",
+            "texels",
+            "columns",
+            "rows",
+            "output_width",
+            "output_height",
+        ];
+        for key in integers {
+            assert!(json[key].is_u64(), "{}", key);
+        }
+        for key in ["mean_error", "max_error", "match_seconds"] {
+            assert!(json[key].is_f64(), "{}", key);
+        }
+        assert_eq!(json["match_seconds"].as_f64(), Some(1.5));
+        assert_eq!(json["columns"].as_u64(), Some(12));
+
+        let usage = json["color_usage"].as_sequence().unwrap();
+        assert_eq!(usage.len(), 8);
+        assert!(usage.iter().all(Value::is_u64));
+        assert_eq!(json.as_mapping().unwrap().len(), integers.len() + 4);
+    }
+}
+
+mod max_bytes {
+    use ansify::{fit_width_to_bytes, ANSIfier, Texel};
+    use image::imageops::FilterType;
+    use image::GenericImageView;
+    use std::cell::Cell;
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return common::ansifier("8.yaml");
+    }
+
+    #[test]
+    fn minimal_text_only_emits_changed_colors() {
+        let texel = |foreground_color, background_color| Texel {
+            foreground_color,
+            background_color,
+            block: ' ',
+        };
+        let grid = vec![vec![texel(1, 2), texel(1, 2), texel(3, 2), texel(3, 4)]];
+
+        assert_eq!(
+            ansifier().grid_text_minimal(&grid),
+            "\x1b[38;5;1;48;5;2m  \x1b[38;5;3m \x1b[48;5;4m \x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn fits_the_widest_text_within_budget() {
+        let root = common::root();
+        let original = image::open(root.join("tests").join("golden").join("cat.png")).unwrap();
+        let ansifier = ansifier();
+
+        let renders = Cell::new(0);
+        let render = |width| {
+            renders.set(renders.get() + 1);
+            let (width, height) =
+                ansifier.calculate_new_dimensions(original.dimensions(), (Some(width), None));
+            let img = original.resize_exact(width, height, FilterType::Triangle);
+            ansifier.grid_text_minimal(&ansifier.match_grid(&img.into_rgb8()))
+        };
+
+        let budget = 2000;
+        let (width, text) = fit_width_to_bytes(64, budget, render).unwrap();
+        // A binary search over 64 widths renders at most 7 of them
+        assert!(renders.get() <= 7);
+        assert!(text.len() <= budget);
+        assert!(width < 64);
+        assert!(render(width + 1).len() > budget);
+
+        assert_eq!(fit_width_to_bytes(64, 1, render), None);
+    }
+}
+
+mod max_output_pixels {
+    use ansify::{ANSIfier, Blocks, Palette};
+
+    use crate::common;
+
+    #[test]
+    fn over_limit_output_is_rejected() {
+        let ansifier = ANSIfier::new(
+            Palette::from(common::res("8.yaml")).unwrap(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+
+        let dimensions = ansifier.calculate_new_dimensions((640, 480), (Some(100000), None));
+        let error = ansifier
+            .check_output_pixels(dimensions, 100_000_000)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("over the limit of 100000000 pixels"));
+
+        let dimensions = ansifier.calculate_new_dimensions((640, 480), (Some(80), None));
+        assert!(ansifier
+            .check_output_pixels(dimensions, 100_000_000)
+            .is_ok());
+    }
+}
+
+mod mirc {
+    use ansify::{ANSIfier, MircColors, Palette, PalettePreset, Texel};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    fn with_palette(palette: Palette) -> ANSIfier {
+        return ANSIfier::new(palette, common::tiny_blocks()).unwrap();
+    }
+
+    #[test]
+    fn palette_maps_to_valid_mirc_codes() {
+        let ansifier = with_palette(Palette::new(vec![
+            [0, 0, 0],
+            [255, 255, 255],
+            [250, 5, 5],
+            [0, 0, 120],
+            [170, 255, 10],
+        ]));
+
+        assert_eq!(
+            ansifier.mirc_indices(MircColors::Standard),
+            vec![1, 0, 4, 2, 8]
+        );
+        // The extended colors have a closer yellow green
+        assert_eq!(ansifier.mirc_indices(MircColors::Extended)[4], 55);
+
+        let nes = with_palette(Palette::preset(PalettePreset::Nes));
+        assert!(nes
+            .mirc_indices(MircColors::Standard)
+            .iter()
+            .all(|index| *index < 16));
+        assert!(nes
+            .mirc_indices(MircColors::Extended)
+            .iter()
+            .all(|index| *index < 99));
+    }
+
+    #[test]
+    fn output_contains_color_codes() {
+        let ansifier = with_palette(Palette::new(vec![[0, 0, 0], [255, 0, 0]]));
+        let texel = |foreground_color, background_color| Texel {
+            foreground_color,
+            background_color,
+            block: '1',
+        };
+        let grid = vec![
+            vec![texel(1, 0), texel(1, 0), texel(0, 1)],
+            vec![texel(0, 1)],
+        ];
+
+        assert_eq!(
+            ansifier.grid_mirc(&grid, MircColors::Standard),
+            "\x0304,0111\x0301,041\n\x0301,041\n"
+        );
+
+        let img = RgbImage::from_fn(6, 2, |x, _| Rgb([(x * 50) as u8, 0, 0]));
+        let text = ansifier.process_mirc(&img, MircColors::Extended);
+        assert!(text.contains('\x03'));
+        assert!(!text.contains('\x1b'));
+    }
+}
+
+mod palette_coverage {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn solid_input_covers_with_one_palette_color() {
+        let palette = Palette::from(common::res("8.yaml")).unwrap();
+        let ansifier = ANSIfier::new(
+            palette.clone(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+
+        let color = [200, 40, 30];
+        let coverage = ansifier.palette_coverage_image(&RgbImage::from_pixel(7, 5, Rgb(color)));
+
+        let expected = palette.colors()[palette.nearest_index(&color)];
+        assert_eq!(coverage.dimensions(), (7, 5));
+        assert!(coverage.pixels().all(|pixel| pixel.0 == expected));
+    }
+}
+
+mod plain_text {
+    use crate::common;
+
+    #[test]
+    fn plain_text_has_no_escape_sequences() {
+        let ansifier = common::ansifier("8.yaml");
+        let input = common::cat();
+
+        let text = ansifier.process_plain(&input);
+
+        assert!(!text.contains('\x1b'));
+        assert_eq!(text.lines().count(), input.height() as usize);
+        assert!(text
+            .lines()
+            .all(|line| line.chars().count() == input.width() as usize));
+    }
+}
+
+mod png_metadata {
+    use ansify::write_png_with_text;
+    use image::{Rgb, RgbImage};
+
+    // (type, data) for every chunk after the PNG signature
+    fn chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut offset = 8;
+        while offset < png.len() {
+            let length = u32::from_be_bytes(png[offset..offset + 4].try_into().unwrap()) as usize;
+            let kind = png[offset + 4..offset + 8].try_into().unwrap();
+            chunks.push((kind, png[offset + 8..offset + 8 + length].to_vec()));
+            // Length, type, data, and CRC
+            offset += 12 + length;
+        }
+        return chunks;
+    }
+
+    #[test]
+    fn text_chunks_are_written() {
+        let img = RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8 * 80, y as u8 * 80, 7]));
+        let text = vec![
+            (String::from("Source"), String::from("cat.jpg")),
+            (String::from("Source Dimensions"), String::from("640x480")),
+            (String::from("Software"), String::from("ansify ☃")),
+        ];
+
+        let mut png = Vec::new();
+        write_png_with_text(&img, &mut png, &text).unwrap();
+
+        let text_chunks: Vec<Vec<u8>> = chunks(&png)
+            .into_iter()
+            .filter(|(kind, _)| kind == b"tEXt")
+            .map(|(_, data)| data)
+            .collect();
+        assert_eq!(
+            text_chunks,
+            vec![
+                b"Source\x00cat.jpg".to_vec(),
+                b"Source Dimensions\x00640x480".to_vec(),
+                b"Software\x00ansify ?".to_vec(),
+            ]
+        );
+
+        let decoded = image::load_from_memory(&png).unwrap().into_rgb8();
+        assert_eq!(decoded, img);
+    }
+}
+
+mod process_colors {
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn flat_colors_wrap_into_grid() {
+        let ansifier = common::ansifier("8.yaml");
+
+        let colors = [
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+            [255, 255, 0],
+            [0, 255, 255],
+            [255, 0, 255],
+        ];
+
+        let (out, text) = ansifier.process_colors(&colors, 3);
+        assert_eq!(out.width(), 3 * ansifier.block_width());
+        assert_eq!(out.height(), 2 * ansifier.block_height());
+        assert_eq!(text.lines().count(), 2);
+
+        let img = RgbImage::from_fn(3, 2, |x, y| Rgb(colors[(x + y * 3) as usize]));
+        let (expected_out, expected_text) = ansifier.process(&img);
+        assert!(out == expected_out);
+        assert_eq!(text, expected_text);
+    }
+}
+
+mod repeat_to {
+    use ansify::repeat_to;
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn repeated_source_gives_a_periodic_grid() {
+        let ansifier = common::ansifier("8.yaml");
+
+        let source = RgbImage::from_fn(2, 2, |x, y| match (x, y) {
+            (0, 0) => Rgb([250, 10, 10]),
+            (1, 0) => Rgb([10, 250, 10]),
+            (0, 1) => Rgb([10, 10, 250]),
+            _ => Rgb([120, 120, 120]),
+        });
+        let tiled = repeat_to(&source, 4, 4);
+        assert_eq!(tiled.dimensions(), (4, 4));
+
+        let grid = ansifier.match_grid(&tiled);
+        let source_grid = ansifier.match_grid(&source);
+        assert_eq!(grid.len(), 4);
+        for (y, row) in grid.iter().enumerate() {
+            assert_eq!(row.len(), 4);
+            for (x, texel) in row.iter().enumerate() {
+                assert_eq!(*texel, source_grid[y % 2][x % 2]);
+            }
+        }
+    }
+}
+
+mod row_gap {
+    use ansify::{ANSIfier, Blocks, Palette, RenderOptions};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn row_gap_adds_rows_between_cell_rows() {
+        let palette = Palette::from(common::res("8.yaml")).unwrap();
+        let mut ansifier = ANSIfier::new(
+            palette.clone(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+        let img = RgbImage::from_fn(5, 4, |x, y| Rgb([(x * 50) as u8, (y * 80) as u8, 90]));
+        let grid = ansifier.match_grid(&img);
+
+        let (width, height) = ansifier.render_grid(&grid).dimensions();
+
+        ansifier.set_render_options(RenderOptions {
+            row_gap: 3,
+            ..RenderOptions::default()
+        });
+        let out = ansifier.render_grid(&grid);
+        assert_eq!(out.dimensions(), (width, height + 3 * 3));
+        assert_eq!(ansifier.output_dimensions((5, 4)), out.dimensions());
+
+        // The gap below a row is filled with the background colors of that row
+        for (x, texel) in grid[0].iter().enumerate() {
+            let gap_pixel =
+                out.get_pixel(x as u32 * ansifier.block_width(), ansifier.block_height());
+            assert_eq!(
+                gap_pixel.0,
+                palette.colors()[texel.background_color as usize]
+            );
+        }
+    }
+}
+
+mod shader_lut {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    use crate::common;
+
+    fn ansifier() -> ANSIfier {
+        return ANSIfier::new(
+            Palette::new(vec![[0, 0, 0], [255, 0, 0], [255, 255, 255]]),
+            common::tiny_blocks(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn shader_lut_matches_a_manual_lookup() {
+        let reference = ansifier();
+        let (lut, map) = reference.generate_lut_and_map();
+        let ansifier = ansifier().with_shader_lut().unwrap();
+
+        let img = RgbImage::from_fn(16, 8, |x, y| {
+            Rgb([(x * 17) as u8, (y * 37) as u8, ((x * y * 29) % 256) as u8])
+        });
+        let grid = ansifier.match_grid(&img);
+
+        for (y, row) in grid.iter().enumerate() {
+            for (x, texel) in row.iter().enumerate() {
+                // Looked up the way the OBS shader does
+                let [r, g, b] = img.get_pixel(x as u32, y as u32).0.map(|c| c as u32);
+                let lut_x = r | ((b & 0xF) << 8);
+                let lut_y = g | ((b >> 4) << 8);
+                let [foreground, background, block, _] = lut.get_pixel(lut_x, lut_y).0;
+
+                assert_eq!(texel.foreground_color, foreground);
+                assert_eq!(texel.background_color, background);
+
+                // The map's second and third rows pack each block's bitmap
+                let [b0, b1, b2, b3] = map.get_pixel(block as u32, 1).0;
+                let [b4, b5, b6, b7] = map.get_pixel(block as u32, 2).0;
+                let bits = u64::from_le_bytes([b0, b1, b2, b3, b4, b5, b6, b7]);
+                let bitmap = ansifier.blocks.bitmap(texel.block).unwrap();
+                for (j, bitmap_row) in bitmap.iter().enumerate() {
+                    for (i, pixel) in bitmap_row.iter().enumerate() {
+                        let bit = i as u32 + j as u32 * ansifier.block_width();
+                        assert_eq!((bits >> bit) & 1 == 1, *pixel);
+                    }
+                }
+            }
+        }
+
+        // The LUT holds exact matches, so it agrees with the kd-tree
+        assert_eq!(grid, reference.match_grid(&img));
+    }
+
+    #[test]
+    fn oversized_blocks_are_an_error() {
+        // 9x8 is over the 64 pixels the map has room for per block
+        let blocks = Blocks::new(
+            9,
+            8,
+            BTreeMap::from([
+                (' ', vec![vec![false; 9]; 8]),
+                ('█', vec![vec![true; 9]; 8]),
+            ]),
+        )
+        .unwrap();
+        let ansifier =
+            ANSIfier::new(Palette::new(vec![[0, 0, 0], [255, 255, 255]]), blocks).unwrap();
+
+        assert!(ansifier.with_shader_lut().is_err());
+    }
+}
+
+mod single_frame_gif {
+    use image::gif::GifDecoder;
+    use image::{AnimationDecoder, DynamicImage, Rgb, RgbImage};
+    use std::fs::File;
+
+    use crate::common;
+
+    // The Image command saves its output with `RgbImage::save`, which picks the format
+    // from the extension
+    #[test]
+    fn image_output_saves_as_a_single_frame_gif() {
+        let ansifier = common::ansifier("16.yaml");
+        let img = RgbImage::from_fn(12, 8, |x, y| {
+            Rgb([(x * 20) as u8, (y * 30) as u8, ((x + y) * 10) as u8])
+        });
+        let out = ansifier.render_grid(&ansifier.match_grid(&img));
+
+        let dir = common::temp_dir();
+        let path = dir.join("single_frame.gif");
+        out.save(&path).unwrap();
+
+        let frames = GifDecoder::new(File::open(&path).unwrap())
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        assert_eq!(frames.len(), 1);
+
+        // Output only uses palette colors, so it fits in the GIF's color table exactly
+        let decoded = DynamicImage::ImageRgba8(frames[0].buffer().clone()).into_rgb8();
+        assert_eq!(decoded, out);
+    }
+}
+
+mod substitutions {
+    use std::collections::BTreeMap;
+
+    use crate::common;
+
+    #[test]
+    fn substitution_rewrites_text_but_not_image() {
+        let mut ansifier = common::ansifier("8.yaml");
+        let input = common::cat();
+
+        let (image, _) = ansifier.process(&input);
+        let text = ansifier.process_plain(&input);
+        assert!(text.contains('░') && text.contains('▒'));
+
+        ansifier.set_substitutions(BTreeMap::from([('░', '#'), ('▒', '*')]));
+        let (substituted_image, _) = ansifier.process(&input);
+        let substituted_text = ansifier.process_plain(&input);
+
+        assert!(substituted_image == image);
+        assert!(!substituted_text.contains('░') && !substituted_text.contains('▒'));
+        assert_eq!(substituted_text, text.replace('░', "#").replace('▒', "*"));
+    }
+}
+
+mod text_options {
+    use ansify::TextOptions;
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    #[test]
+    fn final_reset_and_trailing_newline() {
+        let mut ansifier = common::ansifier("8.yaml");
+        let input = RgbImage::from_pixel(3, 2, Rgb([200, 40, 40]));
+
+        let (_, text) = ansifier.process(&input);
+        assert!(text.ends_with("\x1b[0m\n"));
+        assert_eq!(text.matches('\n').count(), 2);
+
+        ansifier.set_text_options(TextOptions {
+            final_reset: true,
+            trailing_newline: true,
+        });
+        let (_, text) = ansifier.process(&input);
+        assert!(text.ends_with("\n\x1b[0m"));
+        assert_eq!(text.matches('\n').count(), 2);
+
+        ansifier.set_text_options(TextOptions {
+            final_reset: true,
+            trailing_newline: false,
+        });
+        let (_, text) = ansifier.process(&input);
+        assert!(text.ends_with("\x1b[0m\x1b[0m"));
+        assert_eq!(text.matches('\n').count(), 1);
+
+        ansifier.set_text_options(TextOptions {
+            final_reset: false,
+            trailing_newline: false,
+        });
+        let text = ansifier.process_plain(&input);
+        assert!(!text.ends_with('\n'));
+        assert_eq!(text.lines().count(), 2);
+    }
+}
+
+mod thumbnail {
+    use crate::common;
+
+    #[test]
+    fn thumbnail_is_cell_grid_sized() {
+        let ansifier = common::ansifier("8.yaml");
+        let input = common::cat();
+
+        let grid = ansifier.match_grid(&input);
+        let thumbnail = ansifier.process_thumbnail(&input);
+
+        assert_eq!(thumbnail.height() as usize, grid.len());
+        assert_eq!(thumbnail.width() as usize, grid[0].len());
+    }
+}
+
+mod tiles {
+    use ansify::split_tiles;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn large_output_splits_into_tiles() {
+        let img = RgbImage::from_fn(250, 130, |x, y| Rgb([x as u8, y as u8, 0]));
+
+        let tiles = split_tiles(&img, 100, 64);
+
+        assert_eq!(tiles.len(), 3);
+        for (row, height) in tiles.iter().zip([64, 64, 2]) {
+            assert_eq!(row.len(), 3);
+            for (tile, width) in row.iter().zip([100, 100, 50]) {
+                assert_eq!(tile.dimensions(), (width, height));
+            }
+        }
+
+        assert_eq!(tiles[1][2].get_pixel(0, 0).0, [200, 64, 0]);
+        assert_eq!(tiles[2][1].get_pixel(49, 1).0, [149, 129, 0]);
+    }
+
+    #[test]
+    fn small_output_is_one_tile() {
+        let img = RgbImage::new(40, 30);
+
+        let tiles = split_tiles(&img, 100, 100);
+
+        assert_eq!(tiles.len(), 1);
+        assert_eq!(tiles[0].len(), 1);
+        assert_eq!(tiles[0][0].dimensions(), (40, 30));
+    }
+}
@@ -0,0 +1,778 @@
+// Loading, building and saving palettes
+
+mod common;
+
+mod ase {
+    use ansify::Palette;
+
+    use crate::common;
+
+    fn block(block_type: u16, data: &[u8]) -> Vec<u8> {
+        let mut block = block_type.to_be_bytes().to_vec();
+        block.extend((data.len() as u32).to_be_bytes());
+        block.extend(data);
+        return block;
+    }
+
+    // Length in code units, then the null-terminated UTF-16 name
+    fn name(name: &str) -> Vec<u8> {
+        let units: Vec<u16> = name.encode_utf16().chain([0]).collect();
+        let mut data = (units.len() as u16).to_be_bytes().to_vec();
+        for unit in units {
+            data.extend(unit.to_be_bytes());
+        }
+        return data;
+    }
+
+    fn swatch(swatch_name: &str, model: &[u8; 4], values: &[f32]) -> Vec<u8> {
+        let mut data = name(swatch_name);
+        data.extend(model);
+        for value in values {
+            data.extend(value.to_be_bytes());
+        }
+        // Normal color type
+        data.extend(2u16.to_be_bytes());
+        return block(0x0001, &data);
+    }
+
+    fn ase(blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut ase = b"ASEF".to_vec();
+        ase.extend(1u16.to_be_bytes());
+        ase.extend(0u16.to_be_bytes());
+        ase.extend((blocks.len() as u32).to_be_bytes());
+        for block in blocks {
+            ase.extend(block);
+        }
+        return ase;
+    }
+
+    #[test]
+    fn grouped_swatches_are_flattened() {
+        let dir = common::temp_dir();
+        let path = dir.write(
+            "swatches.ase",
+            ase(&[
+                swatch("Red", b"RGB ", &[1.0, 0.0, 0.0]),
+                block(0xC001, &name("Brand")),
+                swatch("Gray", b"Gray", &[0.5]),
+                swatch("Cyan", b"CMYK", &[1.0, 0.0, 0.0, 0.0]),
+                swatch("White", b"LAB ", &[1.0, 0.0, 0.0]),
+                block(0xC002, &[]),
+            ]),
+        );
+
+        let palette = Palette::from(path).unwrap();
+        assert_eq!(
+            palette.colors(),
+            &[[255, 0, 0], [128, 128, 128], [0, 255, 255], [255, 255, 255]]
+        );
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let dir = common::temp_dir();
+        let mut bytes = ase(&[swatch("Red", b"RGB ", &[1.0, 0.0, 0.0])]);
+        bytes.truncate(bytes.len() - 4);
+        let path = dir.write("truncated.ase", &bytes);
+
+        let error = Palette::from(path).unwrap_err().to_string();
+        assert!(error.contains("ends early"), "{}", error);
+    }
+
+    #[test]
+    fn from_ase_reads_fixture() {
+        let root = common::root();
+        let fixture = root.join("tests").join("fixtures").join("swatches.ase");
+
+        let palette = Palette::from_ase(fixture.clone()).unwrap();
+        assert_eq!(
+            palette.colors(),
+            &[[255, 99, 71], [0, 128, 128], [255, 255, 255]]
+        );
+        assert_eq!(Palette::from(fixture).unwrap(), palette);
+    }
+
+    #[test]
+    fn from_ase_rejects_other_files() {
+        let dir = common::temp_dir();
+        let path = dir.write("not_ase.ase", b"colors:\n  - [0, 0, 0]\n");
+
+        let error = Palette::from_ase(path).unwrap_err().to_string();
+        assert!(error.contains("ASEF"), "{}", error);
+    }
+}
+
+mod color_roles {
+    use ansify::{ANSIfier, Blocks, ColorRole, Palette, Texel};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    fn palette() -> Palette {
+        let dir = common::temp_dir();
+        let path = dir.write(
+        "palette.yaml",
+        "colors:\n  - [0, 0, 0]\n  - [128, 0, 0]\n  - rgb: [255, 255, 0]\n    role: fg_only\n  - rgb: [0, 0, 255]\n    role: bg_only\n  - [255, 255, 255]\n",
+    );
+        return Palette::from(path).unwrap();
+    }
+
+    #[test]
+    fn roles_are_deserialized() {
+        assert_eq!(
+            palette().roles(),
+            &[
+                ColorRole::Both,
+                ColorRole::Both,
+                ColorRole::FgOnly,
+                ColorRole::BgOnly,
+                ColorRole::Both,
+            ]
+        );
+    }
+
+    #[test]
+    fn fg_only_color_is_never_a_background() {
+        let ansifier = ANSIfier::new(
+            palette(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+
+        // Sample the whole color cube so every kind of texel gets a chance to match
+        let mut img = RgbImage::new(256, 128);
+        for (x, y, pixel) in img.enumerate_pixels_mut() {
+            let i = y * 256 + x;
+            *pixel = Rgb([
+                (i % 32 * 8) as u8,
+                (i / 32 % 32 * 8) as u8,
+                (i / 1024 * 8) as u8,
+            ]);
+        }
+
+        let grid: Vec<Vec<Texel>> = ansifier.match_grid(&img);
+        let texels: Vec<&Texel> = grid.iter().flatten().collect();
+        assert!(texels.iter().any(|texel| texel.foreground_color == 2));
+        for texel in texels {
+            assert_ne!(texel.background_color, 2);
+            assert_ne!(texel.foreground_color, 3);
+        }
+    }
+}
+
+mod gpl {
+    use ansify::Palette;
+
+    use crate::common;
+
+    #[test]
+    fn gpl_matches_yaml() {
+        let dir = common::temp_dir();
+        let gpl = dir.write(
+        "palette.gpl",
+        "GIMP Palette\nName: Test\nColumns: 4\n#\n  0   0   0\tBlack\n255 128  64 Light Orange\n# trailing comment\n 12 34 56\n",
+    );
+        let yaml = dir.write(
+            "palette_gpl.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 128, 64]\n  - [12, 34, 56]\n",
+        );
+
+        assert_eq!(Palette::from(gpl).unwrap(), Palette::from(yaml).unwrap());
+    }
+
+    #[test]
+    fn malformed_gpl_rows_are_errors() {
+        let dir = common::temp_dir();
+        let missing_header = dir.write("palette_no_header.gpl", "0 0 0 Black\n");
+        assert!(Palette::from(missing_header)
+            .unwrap_err()
+            .to_string()
+            .contains("GIMP Palette"));
+
+        let out_of_range = dir.write("palette_range.gpl", "GIMP Palette\n0 0 0\n0 300 0\n");
+        let error = Palette::from(out_of_range).unwrap_err().to_string();
+        assert!(error.contains("line 3"), "{}", error);
+        assert!(error.contains("300"), "{}", error);
+
+        let short = dir.write("palette_short.gpl", "GIMP Palette\n10 20\n");
+        let error = Palette::from(short).unwrap_err().to_string();
+        assert!(error.contains("missing blue"), "{}", error);
+    }
+}
+
+mod hex_list {
+    use ansify::Palette;
+    use std::fs;
+
+    use crate::common;
+
+    #[test]
+    fn hex_list_matches_yaml() {
+        let dir = common::temp_dir();
+        let hex = dir.write(
+            "palette_list.txt",
+            "# My palette\n\n#000000\nff8040 orange\n  #0C2238 // dark blue\n#\n",
+        );
+        let yaml = dir.write(
+            "palette_list.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 128, 64]\n  - [12, 34, 56]\n",
+        );
+
+        let palette = Palette::from_hex_list(hex.clone()).unwrap();
+        assert_eq!(palette, Palette::from(yaml).unwrap());
+
+        let extension = dir.write("palette_list.hex", fs::read_to_string(hex).unwrap());
+        assert_eq!(Palette::from(extension).unwrap(), palette);
+    }
+
+    #[test]
+    fn hex_list_typos_report_the_line() {
+        let dir = common::temp_dir();
+        let hex = dir.write("palette_typo.txt", "#000000\n\nff80g0\n");
+        let error = Palette::from_hex_list(hex).unwrap_err().to_string();
+        assert!(error.contains("line 3"), "{}", error);
+        assert!(error.contains("ff80g0"), "{}", error);
+    }
+}
+
+mod jasc_pal {
+    use ansify::Palette;
+
+    use crate::common;
+
+    #[test]
+    fn jasc_pal_matches_yaml() {
+        let dir = common::temp_dir();
+        let pal = dir.write(
+            "palette_jasc.pal",
+            "JASC-PAL\r\n0100\r\n3\r\n0 0 0\r\n255 128 64\r\n12 34 56\r\n",
+        );
+        let yaml = dir.write(
+            "palette_jasc.yaml",
+            "colors:\n  - [0, 0, 0]\n  - [255, 128, 64]\n  - [12, 34, 56]\n",
+        );
+
+        assert_eq!(Palette::from(pal).unwrap(), Palette::from(yaml).unwrap());
+    }
+
+    #[test]
+    fn jasc_pal_count_must_match() {
+        let dir = common::temp_dir();
+        let pal = dir.write(
+            "palette_jasc_count.pal",
+            "JASC-PAL\n0100\n4\n0 0 0\n255 255 255\n",
+        );
+        let error = Palette::from(pal).unwrap_err().to_string();
+        assert!(
+            error.contains("declares 4 colors but contains 2"),
+            "{}",
+            error
+        );
+    }
+}
+
+mod malformed_files {
+    use ansify::{Blocks, Palette, Rng};
+    use std::fs;
+
+    use crate::common;
+
+    fn blocks_error(yaml: &str) -> String {
+        return Blocks::from_yaml_str(yaml).unwrap_err().to_string();
+    }
+
+    #[test]
+    fn rejects_degenerate_blocks() {
+        let error = blocks_error("width: 0\nheight: 0\nblocks: {}\n");
+        assert!(error.contains("no pixels"), "{}", error);
+
+        let error = blocks_error("width: 2\nheight: 2\nblocks: {}\n");
+        assert!(error.contains("no blocks"), "{}", error);
+
+        // The area of a bitmask glyph must not overflow while it is checked
+        let error = blocks_error("width: 4294967295\nheight: 4294967295\nblocks:\n  a: 1\n");
+        assert!(error.contains("cannot hold"), "{}", error);
+
+        let error = blocks_error("width: 100000\nheight: 100000\nblocks:\n  a: [\"1\"]\n");
+        assert!(error.contains("rows"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_oversized_gradients() {
+        let error = Palette::from_yaml_str(
+            "gradient:\n  - { at: 0.0, rgb: [0, 0, 0] }\nsteps: 18446744073709551615\n",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("at most 256"), "{}", error);
+    }
+
+    // The same check as the fuzz targets, on mutations of the bundled files so it
+    // runs with the rest of the tests
+    #[test]
+    fn mutated_files_never_panic() {
+        let mut rng = Rng::new(1272);
+
+        for (file, is_palette) in [("16.yaml", true), ("tiny.yaml", false)] {
+            let original = fs::read(common::res(file)).unwrap();
+            for _ in 0..500 {
+                let mut bytes = original.clone();
+                for _ in 0..1 + rng.next_below(8) {
+                    let i = rng.next_below(bytes.len());
+                    match rng.next_below(3) {
+                        0 => bytes[i] = b"0123456789-:[],{} \n\"x."[rng.next_below(22)],
+                        1 => {
+                            bytes.remove(i);
+                        }
+                        _ => bytes.insert(i, bytes[rng.next_below(bytes.len())]),
+                    }
+                }
+
+                let text = String::from_utf8_lossy(&bytes);
+                if is_palette {
+                    let _ = Palette::from_yaml_str(&text);
+                } else {
+                    let _ = Blocks::from_yaml_str(&text);
+                }
+            }
+        }
+    }
+}
+
+mod palette_builder {
+    use ansify::{ANSIfier, Blocks, Palette, PaletteBuilder};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn builder_matches_new() {
+        let palette = PaletteBuilder::new()
+            .push_color([0, 0, 0])
+            .push_hex("#FF8000")
+            .unwrap()
+            .push_hex("00ff00")
+            .unwrap()
+            .build();
+
+        assert_eq!(palette.colors(), &[[0, 0, 0], [255, 128, 0], [0, 255, 0]]);
+        assert_eq!(
+            palette,
+            Palette::new(vec![[0, 0, 0], [255, 128, 0], [0, 255, 0]])
+        );
+    }
+
+    #[test]
+    fn bad_hex_is_rejected() {
+        let error = PaletteBuilder::new()
+            .push_hex("#12345")
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("#12345"), "{}", error);
+    }
+
+    #[test]
+    fn built_palette_drives_an_ansifier() {
+        let palette = Palette::new(vec![[0, 0, 0], [255, 0, 0]]);
+        let ansifier =
+            ANSIfier::new(palette, Blocks::unicode_block_elements(2, 2).unwrap()).unwrap();
+
+        let grid = ansifier.match_grid(&RgbImage::from_pixel(1, 1, Rgb([250, 0, 0])));
+        assert_eq!(
+            ansifier.palette().colors()[grid[0][0].foreground_color as usize],
+            [255, 0, 0]
+        );
+    }
+}
+
+mod palette_from_image {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    const QUADRANTS: [[u8; 3]; 4] = [[200, 30, 30], [30, 200, 30], [30, 30, 200], [220, 220, 40]];
+
+    fn quadrants() -> RgbImage {
+        return RgbImage::from_fn(32, 32, |x, y| {
+            Rgb(QUADRANTS[(x / 16 + (y / 16) * 2) as usize])
+        });
+    }
+
+    #[test]
+    fn same_seed_is_deterministic() {
+        let img = RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+
+        let first = Palette::from_image(&img, 8, 7);
+        let second = Palette::from_image(&img, 8, 7);
+
+        assert_eq!(first.colors(), second.colors());
+        assert!(first.colors().len() <= 8);
+    }
+
+    #[test]
+    fn recovers_the_colors_present() {
+        let palette = Palette::from_image(&quadrants(), 4, 0);
+
+        let mut colors = palette.colors().to_vec();
+        colors.sort();
+        let mut expected = QUADRANTS.to_vec();
+        expected.sort();
+        assert_eq!(colors, expected);
+    }
+
+    #[test]
+    fn never_exceeds_max_colors() {
+        assert!(Palette::from_image(&quadrants(), 2, 0).colors().len() <= 2);
+        assert!(Palette::from_image(&quadrants(), 0, 0).colors().is_empty());
+    }
+
+    #[test]
+    fn empty_palette_is_rejected() {
+        let palette = Palette::from_image(&quadrants(), 0, 0);
+
+        let result = ANSIfier::new(palette, Blocks::unicode_block_elements(1, 1).unwrap());
+
+        assert!(result.is_err());
+    }
+}
+
+mod palette_limit {
+    use ansify::{ANSIfier, Blocks, Palette};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn palette_is_clamped_to_256_colors() {
+        let colors: Vec<[u8; 3]> = (0..300u32)
+            .map(|i| [(i % 256) as u8, (i / 256 * 200) as u8, 50])
+            .collect();
+        let ansifier = ANSIfier::new(
+            Palette::new(colors),
+            Blocks::unicode_block_elements(2, 2).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(ansifier.palette().colors().len(), 256);
+        assert_eq!(ansifier.palette().roles().len(), 256);
+
+        // Only the dropped colors had a green of 200, so this has to fall back to
+        // colors that are still in the palette
+        let texel = ansifier.match_grid(&RgbImage::from_pixel(1, 1, Rgb([10, 200, 50])))[0][0];
+        for index in [texel.foreground_color, texel.background_color] {
+            assert_eq!(ansifier.palette().colors()[index as usize][1], 0);
+        }
+    }
+}
+
+mod palette_stability {
+    use ansify::Palette;
+    use image::{Rgb, RgbImage};
+
+    fn distance(a: &[u8; 3], b: &[u8; 3]) -> f32 {
+        return (0..3)
+            .map(|c| ((a[c] as f32 - b[c] as f32) / 255.0).powi(2))
+            .sum::<f32>()
+            .sqrt();
+    }
+
+    #[test]
+    fn static_scene_has_stable_palette() {
+        let frame = RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+        let max_drift = 0.05;
+
+        let mut palettes = vec![Palette::from_image(&frame, 8, 0)];
+        for _ in 0..8 {
+            let previous = palettes.last().unwrap();
+            let next = Palette::from_image_stabilized(&frame, previous, max_drift);
+
+            assert_eq!(next.colors().len(), previous.colors().len());
+            for (a, b) in next.colors().iter().zip(previous.colors()) {
+                // Allow for rounding to whole color values
+                assert!(distance(a, b) <= max_drift + 1.0 / 255.0);
+            }
+            palettes.push(next);
+        }
+
+        // Once settled the palette stops changing between frames
+        let settled = &palettes[palettes.len() - 2..];
+        assert_eq!(settled[0], settled[1]);
+    }
+}
+
+mod palette_to_file {
+    use ansify::{ColorRole, Palette, PalettePreset};
+
+    use crate::common;
+
+    #[test]
+    fn round_trips_through_yaml() {
+        let dir = common::temp_dir();
+
+        let palette = Palette::preset(PalettePreset::Pico8);
+        let path = dir.join("palette.yaml");
+        palette.to_file(path.clone()).unwrap();
+
+        assert_eq!(Palette::from(path).unwrap(), palette);
+    }
+
+    #[test]
+    fn round_trips_roles() {
+        let dir = common::temp_dir();
+        let source = dir.write(
+        "source.yaml",
+        "colors:\n  - [0, 0, 0]\n  - { rgb: [255, 255, 255], role: fg_only }\n  - { rgb: [255, 0, 0], role: bg_only }\n",
+    );
+        let palette = Palette::from(source).unwrap();
+
+        let path = dir.join("palette.yaml");
+        palette.to_file(path.clone()).unwrap();
+        let written = Palette::from(path).unwrap();
+
+        assert_eq!(written, palette);
+        assert_eq!(
+            written.roles(),
+            &[ColorRole::Both, ColorRole::FgOnly, ColorRole::BgOnly]
+        );
+    }
+}
+
+mod palette_validation {
+    use ansify::{ColorRole, Palette};
+
+    use crate::common;
+
+    #[test]
+    fn empty_palette_is_rejected() {
+        let dir = common::temp_dir();
+        let path = dir.write("palette_empty.yaml", "colors: []\n");
+
+        let error = Palette::from(path).unwrap_err().to_string();
+        assert!(error.contains("no colors"), "{}", error);
+    }
+
+    #[test]
+    fn duplicates_keep_their_indices_on_load() {
+        let dir = common::temp_dir();
+        let path = dir.write(
+        "palette_duplicates.yaml",
+        "colors:\n  - [0, 0, 0]\n  - [255, 0, 0]\n  - [0, 0, 0]\n  - { rgb: [255, 255, 255], role: fg_only }\n  - [255, 0, 0]\n",
+    );
+
+        let palette = Palette::from(path).unwrap();
+        assert_eq!(palette.colors().len(), 5);
+        assert_eq!(palette.colors()[4], [255, 0, 0]);
+
+        let deduped = palette.dedup();
+        assert_eq!(deduped.colors(), &[[0, 0, 0], [255, 0, 0], [255, 255, 255]]);
+        assert_eq!(
+            deduped.roles(),
+            &[ColorRole::Both, ColorRole::Both, ColorRole::FgOnly]
+        );
+    }
+}
+
+mod per_frame_palette {
+    use ansify::Palette;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn different_frames_derive_different_palettes() {
+        let warm = RgbImage::from_fn(32, 32, |x, _| Rgb([255, (x * 4) as u8, 0]));
+        let cool = RgbImage::from_fn(32, 32, |_, y| Rgb([0, (y * 4) as u8, 255]));
+
+        let warm_palette = Palette::from_image(&warm, 4, 0);
+        let cool_palette = Palette::from_image(&cool, 4, 0);
+
+        assert!(!warm_palette.colors().is_empty());
+        assert!(!cool_palette.colors().is_empty());
+        assert!(warm_palette
+            .colors()
+            .iter()
+            .all(|color| !cool_palette.colors().contains(color)));
+    }
+}
+
+mod presets {
+    use ansify::{ANSIfier, Blocks, Palette, PalettePreset};
+    use image::{Rgb, RgbImage};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn game_boy_preset_has_canonical_colors() {
+        let palette = Palette::preset(PalettePreset::GameBoy);
+
+        assert_eq!(
+            palette.colors(),
+            &[[15, 56, 15], [48, 98, 48], [139, 172, 15], [155, 188, 15]]
+        );
+    }
+
+    #[test]
+    fn xterm_256_indices_match_the_terminal() {
+        let palette = Palette::xterm_256();
+        let colors = palette.colors();
+
+        assert_eq!(colors.len(), 256);
+        assert_eq!(colors[9], [255, 0, 0]);
+        assert_eq!(colors[16], [0, 0, 0]);
+        assert_eq!(colors[196], [255, 0, 0]);
+        assert_eq!(colors[231], [255, 255, 255]);
+        assert_eq!(colors[232], [8, 8, 8]);
+        assert_eq!(colors[255], [238, 238, 238]);
+    }
+
+    #[test]
+    fn xterm_256_text_uses_the_matched_index() {
+        let blocks = Blocks::new(1, 1, BTreeMap::from([('█', vec![vec![true]])])).unwrap();
+        let ansifier = ANSIfier::new(Palette::xterm_256(), blocks).unwrap();
+
+        // Index 67 of the color cube
+        let img = RgbImage::from_pixel(4, 4, Rgb([95, 135, 175]));
+        let grid = ansifier.match_grid(&img);
+        assert!(grid
+            .iter()
+            .flatten()
+            .all(|texel| texel.foreground_color == 67));
+
+        let background = grid[0][0].background_color;
+        let row = format!("\x1b[38;5;67;48;5;{}m████\x1b[0m\n", background);
+        assert_eq!(ansifier.grid_text_minimal(&grid), row.repeat(4));
+    }
+
+    #[test]
+    fn sixteen_color_presets() {
+        let vga = Palette::vga_16();
+        assert_eq!(vga.colors().len(), 16);
+        assert_eq!(vga.colors()[3], [170, 85, 0]);
+
+        let c64 = Palette::c64();
+        assert_eq!(c64.colors().len(), 16);
+        assert_eq!(c64, Palette::preset(PalettePreset::C64));
+    }
+}
+
+mod prune_similar {
+    use ansify::{ColorSpace, Palette};
+
+    #[test]
+    fn drops_near_duplicates_and_keeps_distinct_colors() {
+        let palette = Palette::new(vec![
+            [0, 0, 0],
+            [2, 2, 2],
+            [255, 0, 0],
+            [250, 4, 0],
+            [0, 0, 255],
+            [255, 255, 255],
+        ]);
+
+        // Earlier colors win, so each near-duplicate goes and its original stays
+        let pruned = palette.prune_similar(0.05, ColorSpace::Rgb);
+        assert_eq!(
+            pruned.colors(),
+            &[[0, 0, 0], [255, 0, 0], [0, 0, 255], [255, 255, 255]]
+        );
+        assert_eq!(pruned.roles().len(), pruned.colors().len());
+
+        // Lab distances are in units of L*, where the near-duplicates are still close
+        let pruned = palette.prune_similar(3.0, ColorSpace::Lab);
+        assert_eq!(
+            pruned.colors(),
+            &[[0, 0, 0], [255, 0, 0], [0, 0, 255], [255, 255, 255]]
+        );
+
+        // Nothing is within a zero threshold
+        assert_eq!(
+            palette.prune_similar(0.0, ColorSpace::Rgb).colors(),
+            palette.colors()
+        );
+    }
+}
+
+mod split_palette {
+    use ansify::{ANSIfier, Blocks, ColorRole, Palette};
+    use image::{Rgb, RgbImage};
+
+    use crate::common;
+
+    const FOREGROUND: [[u8; 3]; 3] = [[255, 255, 255], [255, 255, 0], [0, 255, 0]];
+    const BACKGROUND: [[u8; 3]; 3] = [[0, 0, 0], [0, 0, 128], [0, 255, 0]];
+
+    fn palette() -> Palette {
+        let dir = common::temp_dir();
+        let path = dir.write(
+        "palette.yaml",
+        "foreground:\n  - [255, 255, 255]\n  - [255, 255, 0]\n  - [0, 255, 0]\nbackground:\n  - [0, 0, 0]\n  - [0, 0, 128]\n  - [0, 255, 0]\n",
+    );
+        return Palette::from(path).unwrap();
+    }
+
+    #[test]
+    fn split_form_is_deserialized() {
+        let palette = palette();
+        assert_eq!(
+            palette,
+            Palette::split(FOREGROUND.to_vec(), BACKGROUND.to_vec())
+        );
+
+        // The shared green is kept once and allowed on both sides
+        assert_eq!(palette.colors().len(), 5);
+        assert_eq!(palette.roles()[2], ColorRole::Both);
+        assert_eq!(palette.foreground_colors(), FOREGROUND.to_vec());
+        assert_eq!(
+            palette.background_colors(),
+            [[0, 255, 0], [0, 0, 0], [0, 0, 128]]
+        );
+    }
+
+    #[test]
+    fn texels_pair_foreground_and_background_colors() {
+        let palette = palette();
+        let ansifier = ANSIfier::new(
+            palette.clone(),
+            Blocks::from(common::res("classic.yaml")).unwrap(),
+        )
+        .unwrap();
+
+        let img = RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        });
+        for texel in ansifier.match_grid(&img).iter().flatten() {
+            let foreground = palette.colors()[texel.foreground_color as usize];
+            let background = palette.colors()[texel.background_color as usize];
+            assert!(FOREGROUND.contains(&foreground), "{:?}", texel);
+            assert!(BACKGROUND.contains(&background), "{:?}", texel);
+        }
+    }
+}
+
+mod white_balance {
+    use ansify::{tint, white_balance, white_balance_scale};
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn daylight_is_near_identity() {
+        for channel in white_balance_scale(6500.0) {
+            assert!((channel - 1.0).abs() < 1e-4);
+        }
+
+        let mut img = RgbImage::from_fn(16, 1, |x, _| {
+            Rgb([(x * 16) as u8, 128, 255 - (x * 16) as u8])
+        });
+        let original = img.clone();
+        white_balance(&mut img, 6500.0);
+        assert_eq!(img, original);
+    }
+
+    #[test]
+    fn warm_light_is_cooled() {
+        // Incandescent light is orange, so correcting for it pulls blue up against red
+        let [red, _, blue] = white_balance_scale(3000.0);
+        assert!(blue > 1.0);
+        assert!(blue > red);
+    }
+
+    #[test]
+    fn tint_scales_each_channel() {
+        let mut img = RgbImage::from_pixel(2, 2, Rgb([100, 100, 100]));
+        tint(&mut img, [0.5, 1.0, 3.0]);
+        assert!(img.pixels().all(|pixel| pixel.0 == [50, 100, 255]));
+    }
+}